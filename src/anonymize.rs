@@ -0,0 +1,483 @@
+// Pseudonymization of the patient identifying header fields.
+//
+// Every identifying value is replaced by a pseudonym which is recorded in a
+// mapping file keyed by header field and original value, so the same patient
+// gets the same pseudonym in every file and the process can be reversed.
+// Creation date and time are shifted by a random, fixed offset per patient.
+// The reserved part of the header is cleared and cannot be restored.
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::modal::{hnd_header_t, HeaderValue};
+
+/// Header fields holding patient identifying values, in the order they are
+/// pseudonymized.
+pub const IDENTIFYING_FIELDS: &[&str] = &[
+    "sPatientID",
+    "nPatientSer",
+    "sSeriesID",
+    "nSeriesSer",
+    "sSliceID",
+    "nSliceSer",
+];
+
+const DATE_OFFSET: &str = "date_offset";
+const TIME_OFFSET: &str = "time_offset";
+
+#[derive(Debug, Default, Clone)]
+pub struct PseudonymMap {
+    // (field, original value) -> pseudonym
+    entries: BTreeMap<(String, String), String>,
+    // original patient ID -> date shift in days
+    date_offsets: BTreeMap<String, i64>,
+    // original patient ID -> time shift in seconds, within a day
+    time_offsets: BTreeMap<String, i64>,
+}
+
+impl PseudonymMap {
+    pub fn new() -> PseudonymMap {
+        PseudonymMap {
+            ..Default::default()
+        }
+    }
+
+    /// Read a mapping file written by `save`.
+    pub fn load(path: &Path) -> Result<PseudonymMap, io::Error> {
+        let mut map = PseudonymMap::new();
+        let invalid = |n: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: malformed line {}", path.display(), n + 1),
+            )
+        };
+        for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cols: Vec<&str> = line.split('\t').collect();
+            if cols.len() != 3 {
+                return Err(invalid(n));
+            }
+            let original = unescape(cols[1]).ok_or_else(|| invalid(n))?;
+            if cols[0] == DATE_OFFSET {
+                let days = cols[2].parse().map_err(|_| invalid(n))?;
+                map.date_offsets.insert(original, days);
+            } else if cols[0] == TIME_OFFSET {
+                let seconds = cols[2].parse().map_err(|_| invalid(n))?;
+                map.time_offsets.insert(original, seconds);
+            } else if IDENTIFYING_FIELDS.contains(&cols[0]) {
+                let pseudonym = unescape(cols[2]).ok_or_else(|| invalid(n))?;
+                map.entries.insert((cols[0].to_string(), original), pseudonym);
+            } else {
+                return Err(invalid(n));
+            }
+        }
+        Ok(map)
+    }
+
+    /// Write the mapping as tab separated `field original pseudonym` lines.
+    /// The file links pseudonyms back to patients and must be kept private.
+    /// It is replaced atomically, a failed save leaves the old file intact.
+    pub fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        self.write(&mut tmp)?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn write<W: Write>(&self, f: &mut W) -> Result<(), io::Error> {
+        writeln!(f, "# hnd pseudonym map: field\toriginal\tpseudonym")?;
+        for ((field, original), pseudonym) in &self.entries {
+            writeln!(f, "{}\t{}\t{}", field, escape(original), escape(pseudonym))?;
+        }
+        for (patient, days) in &self.date_offsets {
+            writeln!(f, "{}\t{}\t{}", DATE_OFFSET, escape(patient), days)?;
+        }
+        for (patient, seconds) in &self.time_offsets {
+            writeln!(f, "{}\t{}\t{}", TIME_OFFSET, escape(patient), seconds)?;
+        }
+        Ok(())
+    }
+
+    // Number of entries of all kinds, grows whenever the map is extended.
+    fn len(&self) -> usize {
+        self.entries.len() + self.date_offsets.len() + self.time_offsets.len()
+    }
+
+    fn pseudonym(&mut self, field: &str, original: &HeaderValue) -> HeaderValue {
+        let key = (field.to_string(), original.to_string());
+        if let Some(p) = self.entries.get(&key) {
+            return match original {
+                HeaderValue::U32(_) => HeaderValue::U32(p.parse().unwrap_or(0)),
+                _ => HeaderValue::Str(p.clone()),
+            };
+        }
+        let n = self.entries.keys().filter(|(f, _)| f == field).count() + 1;
+        let value = match (field, original) {
+            ("sPatientID", _) => HeaderValue::Str(format!("ANON{:06}", n)),
+            ("sSeriesID", _) => HeaderValue::Str(format!("SERIES{:06}", n)),
+            ("sSliceID", _) => HeaderValue::Str(format!("SLICE{:06}", n)),
+            _ => HeaderValue::U32(n as u32),
+        };
+        self.entries.insert(key, value.to_string());
+        value
+    }
+
+    /// The date and time shift of a patient in days and seconds. Patients
+    /// from a map written before times were shifted keep their time.
+    fn date_offset(&mut self, patient: &str) -> (i64, i64) {
+        if let Some(days) = self.date_offsets.get(patient) {
+            return (*days, self.time_offsets.get(patient).cloned().unwrap_or(0));
+        }
+        // RandomState is seeded randomly per process, which is all we need
+        // for an offset that cannot be guessed from the data.
+        let random = RandomState::new().hash_one(patient);
+        let days = -((random % 3650) as i64 + 1);
+        let seconds = ((random >> 32) % 86400) as i64;
+        self.date_offsets.insert(patient.to_string(), days);
+        self.time_offsets.insert(patient.to_string(), seconds);
+        (days, seconds)
+    }
+
+    /// Replace the identifying fields of `h` with their pseudonyms, adding new
+    /// ones to the map as needed, and shift the creation date. Empty strings
+    /// and zero serial numbers are left alone. The reserved part of the header
+    /// is cleared as it may hold acquisition paths, it is not kept in the map.
+    pub fn anonymize(&mut self, h: &mut hnd_header_t) -> Result<(), io::Error> {
+        let patient = h.sPatientID.clone();
+        for field in IDENTIFYING_FIELDS {
+            let original = h.field(field)?;
+            if original == HeaderValue::Str(String::new()) || original == HeaderValue::U32(0) {
+                continue;
+            }
+            let value = self.pseudonym(field, &original);
+            h.set_field(field, value)?;
+        }
+        let (days, seconds) = self.date_offset(&patient);
+        shift_creation(h, days, seconds)?;
+        h.Reserved = Vec::new();
+        Ok(())
+    }
+
+    /// Undo `anonymize`, failing if a pseudonym is not in the map. The
+    /// cleared reserved part of the header stays empty.
+    pub fn restore(&self, h: &mut hnd_header_t) -> Result<(), io::Error> {
+        for field in IDENTIFYING_FIELDS {
+            let pseudonym = h.field(field)?.to_string();
+            let original = self
+                .entries
+                .iter()
+                .find(|((f, _), p)| f == field && **p == pseudonym)
+                .map(|((_, o), _)| o.clone());
+            match original {
+                Some(o) => h.set_field_str(field, &o)?,
+                None if pseudonym.is_empty() || pseudonym == "0" => {}
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no original value for {} `{}`", field, pseudonym),
+                    ))
+                }
+            }
+        }
+        let days = self.date_offsets.get(&h.sPatientID).cloned().unwrap_or(0);
+        let seconds = self.time_offsets.get(&h.sPatientID).cloned().unwrap_or(0);
+        shift_creation(h, -days, -seconds)
+    }
+}
+
+/// Shift creation date and time of `h` by `days` and `seconds`, the time
+/// carrying over into the date. An empty date or time is left empty, one
+/// that does not parse is an error.
+fn shift_creation(h: &mut hnd_header_t, days: i64, seconds: i64) -> Result<(), io::Error> {
+    let invalid = |field: &str, value: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("cannot shift {} `{}`", field, value),
+        )
+    };
+    if h.sCreationDate.is_empty() {
+        if !h.sCreationTime.is_empty() {
+            h.sCreationTime = shift_time(&h.sCreationTime, seconds).ok_or_else(|| invalid("sCreationTime", &h.sCreationTime))?.1;
+        }
+        return Ok(());
+    }
+    if h.sCreationTime.is_empty() {
+        h.sCreationDate = shift_date(&h.sCreationDate, days).ok_or_else(|| invalid("sCreationDate", &h.sCreationDate))?;
+        return Ok(());
+    }
+    let (carry, time) = shift_time(&h.sCreationTime, seconds).ok_or_else(|| invalid("sCreationTime", &h.sCreationTime))?;
+    h.sCreationDate = shift_date(&h.sCreationDate, days + carry).ok_or_else(|| invalid("sCreationDate", &h.sCreationDate))?;
+    h.sCreationTime = time;
+    Ok(())
+}
+
+/// Anonymize (or restore, with `reverse`) every `.hnd` file below `input`
+/// and write it to the same relative path below `output`. `input` may also
+/// be a single file. When anonymizing with a `map_path`, the map is saved
+/// there before each file that added to it is written, so no written file
+/// has a pseudonym missing from the saved map even if a later file fails.
+/// Returns the number of files written.
+pub fn anonymize_tree(
+    input: &Path,
+    output: &Path,
+    map: &mut PseudonymMap,
+    map_path: Option<&Path>,
+    reverse: bool,
+) -> Result<usize, io::Error> {
+    if input.is_file() {
+        process_file(input, output, map, map_path, reverse)?;
+        return Ok(1);
+    }
+    let mut count = 0;
+    let mut entries: Vec<_> = std::fs::read_dir(input)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let target = output.join(entry.file_name());
+        if path.is_dir() {
+            count += anonymize_tree(&path, &target, map, map_path, reverse)?;
+        } else if crate::is_hnd_file(&path) {
+            std::fs::create_dir_all(output)?;
+            process_file(&path, &target, map, map_path, reverse)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn process_file(
+    input: &Path,
+    output: &Path,
+    map: &mut PseudonymMap,
+    map_path: Option<&Path>,
+    reverse: bool,
+) -> Result<(), io::Error> {
+    let mut header = crate::read_header(&mut File::open(input)?)?;
+    if reverse {
+        map.restore(&mut header)?;
+    } else {
+        let before = map.len();
+        map.anonymize(&mut header)?;
+        if let Some(path) = map_path.filter(|_| map.len() != before) {
+            map.save(path)?;
+        }
+    }
+    crate::copy_with_header(input, output, &header)
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+/// Shift a `YYYYMMDD` date by `days`. Returns `None` for anything that is not
+/// a valid date.
+pub fn shift_date(date: &str, days: i64) -> Option<String> {
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let y: i64 = date[0..4].parse().ok()?;
+    let m: i64 = date[4..6].parse().ok()?;
+    let d: i64 = date[6..8].parse().ok()?;
+    if !(1..=12).contains(&m) || d < 1 || civil_from_days(days_from_civil(y, m, d)) != (y, m, d) {
+        return None;
+    }
+    let (y, m, d) = civil_from_days(days_from_civil(y, m, d) + days);
+    if !(0..=9999).contains(&y) {
+        return None;
+    }
+    Some(format!("{:04}{:02}{:02}", y, m, d))
+}
+
+/// Shift a `HH:MM:SS` time by `seconds`. Returns the days carried over and
+/// the new time, `None` for anything that is not a valid time.
+pub fn shift_time(time: &str, seconds: i64) -> Option<(i64, String)> {
+    let parts: Vec<&str> = time.split(':').collect();
+    if parts.len() != 3 || parts.iter().any(|p| p.len() != 2 || !p.bytes().all(|b| b.is_ascii_digit())) {
+        return None;
+    }
+    let (h, m, s): (i64, i64, i64) = (parts[0].parse().ok()?, parts[1].parse().ok()?, parts[2].parse().ok()?);
+    if h > 23 || m > 59 || s > 59 {
+        return None;
+    }
+    let total = h * 3600 + m * 60 + s + seconds;
+    let t = total.rem_euclid(86400);
+    Some((total.div_euclid(86400), format!("{:02}:{:02}:{:02}", t / 3600, t / 60 % 60, t % 60)))
+}
+
+// Header strings may hold arbitrary bytes, one char per byte. Keep the
+// mapping file one entry per line by escaping everything but printable ASCII,
+// so the original bytes are restored exactly.
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        if c == '\\' || !(' '..'\u{7f}').contains(&c) {
+            out.push_str(&format!("\\x{:02x}", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            let hex = s.get(i + 2..i + 4)?;
+            if bytes.get(i + 1) != Some(&b'x') {
+                return None;
+            }
+            out.push(u8::from_str_radix(hex, 16).ok()? as char);
+            i += 4;
+        } else {
+            out.push(bytes[i] as char);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift_date() {
+        assert_eq!(shift_date("20190610", 0).unwrap(), "20190610");
+        assert_eq!(shift_date("20190301", -1).unwrap(), "20190228");
+        assert_eq!(shift_date("20200301", -1).unwrap(), "20200229");
+        assert_eq!(shift_date("20191231", 1).unwrap(), "20200101");
+        assert_eq!(shift_date("20190230", 0), None);
+        assert_eq!(shift_date("2019061", 0), None);
+        assert_eq!(shift_time("13:41:10", 11 * 3600).unwrap(), (1, "00:41:10".to_string()));
+        assert_eq!(shift_time("00:00:05", -10).unwrap(), (-1, "23:59:55".to_string()));
+        assert_eq!(shift_time("25:00:00", 0), None);
+    }
+
+    #[test]
+    fn test_anonymize_roundtrip() {
+        let mut f = File::open("test/test_data_1.hnd").unwrap();
+        let original = crate::read_header(&mut f).unwrap();
+
+        let mut map = PseudonymMap::new();
+        let mut h1 = original.clone();
+        let mut h2 = original.clone();
+        map.anonymize(&mut h1).unwrap();
+        map.anonymize(&mut h2).unwrap();
+        assert_eq!(h1.sPatientID, "ANON000001");
+        assert_eq!(h1.nPatientSer, 1);
+        assert_eq!(h1.sPatientID, h2.sPatientID);
+        assert_eq!(h1.sCreationDate, h2.sCreationDate);
+        assert_ne!(h1.sCreationDate, original.sCreationDate);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.tsv");
+        map.save(&path).unwrap();
+        let map = PseudonymMap::load(&path).unwrap();
+
+        map.restore(&mut h1).unwrap();
+        for field in IDENTIFYING_FIELDS {
+            assert_eq!(h1.field(field).unwrap(), original.field(field).unwrap());
+        }
+        assert_eq!(h1.sCreationDate, original.sCreationDate);
+        assert_eq!(h1.sCreationTime, original.sCreationTime);
+
+        let mut bad = original.clone();
+        bad.sCreationDate = "2019-6-10".to_string();
+        assert!(map.clone().anonymize(&mut bad).is_err());
+        let mut bad = h2.clone();
+        bad.sCreationTime = "1:41".to_string();
+        assert!(map.restore(&mut bad).is_err());
+    }
+
+    #[test]
+    fn test_anonymize_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Proj_00000.hnd");
+        std::fs::copy("test/test_data_1.hnd", &path).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        let mut map = PseudonymMap::new();
+        assert_eq!(anonymize_tree(dir.path(), dir.path(), &mut map, None, false).unwrap(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        let h = crate::read_header(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(h.sPatientID, "ANON000001");
+    }
+
+    #[test]
+    fn test_anonymize_non_ascii() {
+        let mut raw = crate::read_header(&mut File::open("test/test_data_1.hnd").unwrap())
+            .unwrap()
+            .to_raw();
+        // sPatientID at byte 60, with a Latin-1 letter and garbage after the NUL
+        raw[60..76].copy_from_slice(b"M\xfcller\0\x01\xff\0\0\0\0\0\0\0");
+        let original = hnd_header_t::from_raw(raw.clone());
+        let mut map = PseudonymMap::new();
+        let mut h = original.clone();
+        map.anonymize(&mut h).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.tsv");
+        map.save(&path).unwrap();
+        let map = PseudonymMap::load(&path).unwrap();
+        map.restore(&mut h).unwrap();
+        assert_eq!(h.to_raw()[60..76], raw[60..76]);
+    }
+
+    #[test]
+    fn test_map_saved_before_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::copy("test/test_data_1.hnd", input.join("Proj_00000.hnd")).unwrap();
+        let mut h = crate::read_header(&mut File::open("test/test_data_1.hnd").unwrap()).unwrap();
+        h.sPatientID = "OTHER".to_string();
+        h.sCreationDate = "2019-6-10".to_string();
+        crate::copy_with_header(Path::new("test/test_data_1.hnd"), &input.join("Proj_00001.hnd"), &h)
+            .unwrap();
+
+        let output = dir.path().join("out");
+        let map_path = dir.path().join("map.tsv");
+        let mut map = PseudonymMap::new();
+        assert!(anonymize_tree(&input, &output, &mut map, Some(&map_path), false).is_err());
+        assert!(output.join("Proj_00000.hnd").exists());
+
+        let map = PseudonymMap::load(&map_path).unwrap();
+        let mut h = crate::read_header(&mut File::open(output.join("Proj_00000.hnd")).unwrap()).unwrap();
+        map.restore(&mut h).unwrap();
+        let original = crate::read_header(&mut File::open("test/test_data_1.hnd").unwrap()).unwrap();
+        assert_eq!(h.sPatientID, original.sPatientID);
+    }
+}
@@ -0,0 +1,367 @@
+// Integrity checks for HND files and scans.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::modal::{hnd_header_t, HeaderValue, LutIter, HEADER_FIELDS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub severity: Severity,
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.path.display(), self.message)
+    }
+}
+
+/// The most severe issue in a report, `None` if the report is empty.
+pub fn worst_severity(issues: &[Issue]) -> Option<Severity> {
+    issues.iter().map(|i| i.severity).max()
+}
+
+// Largest detector we expect to see, anything above is most likely a
+// corrupted header.
+const MAX_IMAGE_SIZE: u32 = 16384;
+
+/// Validate a single HND file: header fields, file length and the layout of
+/// the compressed image data.
+pub fn check_file(path: &Path) -> Result<Vec<Issue>, io::Error> {
+    let mut issues = Vec::new();
+    let mut report = |severity, message: String| {
+        issues.push(Issue {
+            severity,
+            path: path.to_path_buf(),
+            message,
+        })
+    };
+
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < 1024 {
+        report(Severity::Error, format!("file is {} bytes, shorter than the 1024 byte header", buf.len()));
+        return Ok(issues);
+    }
+    let header = hnd_header_t::from_raw(buf[..1024].to_vec());
+
+    // Varian writes the size the file would have uncompressed (32 bits per
+    // pixel) into FileLength, other writers use the real size.
+    let uncompressed = 1024 + header.SizeX as u64 * header.SizeY as u64 * 4;
+    let file_length = header.FileLength as u64;
+    if file_length != buf.len() as u64 && file_length != uncompressed {
+        report(
+            Severity::Error,
+            format!(
+                "FileLength is {} but the file has {} bytes ({} uncompressed)",
+                header.FileLength,
+                buf.len(),
+                uncompressed
+            ),
+        );
+    }
+
+    // the checksum algorithm is not documented, so say so rather than
+    // leaving the impression that it was checked
+    if header.nCheckSum != 0 || !header.chasChecksumSpec.is_empty() {
+        report(
+            Severity::Info,
+            format!(
+                "checksum {} (spec `{}`) not verified",
+                header.nCheckSum,
+                header.chasChecksumSpec.escape_default()
+            ),
+        );
+    }
+
+    // strings are stored NUL padded, a non-ASCII byte or anything after the
+    // first NUL hints at garbage in the header
+    let mut offset = 0;
+    for field in HEADER_FIELDS {
+        let size = hnd_header_t::field_size(field).unwrap();
+        if let Ok(HeaderValue::Str(_)) = header.field(field) {
+            let raw = &buf[offset..offset + size];
+            let len = raw.iter().position(|b| *b == 0).unwrap_or(size);
+            if !raw[..len].iter().all(|b| b.is_ascii() && !b.is_ascii_control()) {
+                report(Severity::Warning, format!("{} is not printable ASCII", field));
+            } else if raw[len..].iter().any(|b| *b != 0) {
+                report(Severity::Warning, format!("{} is not NUL padded", field));
+            }
+        }
+        offset += size;
+    }
+
+    let (width, height) = (header.SizeX as usize, header.SizeY as usize);
+    if header.SizeX == 0 || header.SizeY < 2 || header.SizeX > MAX_IMAGE_SIZE || header.SizeY > MAX_IMAGE_SIZE {
+        report(Severity::Error, format!("implausible image size {}x{}", width, height));
+        return Ok(issues);
+    }
+
+    match stream_length(&buf[1024..], width, height) {
+        Err(message) => {
+            report(Severity::Error, message);
+            return Ok(issues);
+        }
+        Ok(expected) if expected < buf.len() - 1024 => report(
+            Severity::Error,
+            format!("{} trailing bytes after the image data", buf.len() - 1024 - expected),
+        ),
+        Ok(_) => {}
+    }
+
+    let bad = pixels_out_of_range(&buf[1024..], width, height);
+    if let Some((i, value)) = bad.first() {
+        report(
+            Severity::Error,
+            format!(
+                "{} pixel(s) decode outside the 32 bit range, the first at x={} y={} to {}",
+                bad.len(),
+                i % width,
+                i / width,
+                value
+            ),
+        );
+    }
+
+    Ok(issues)
+}
+
+/// The number of bytes the compressed image of the given size takes, as
+/// described by its LUT. Fails if the data is too short.
+pub fn stream_length(data: &[u8], width: usize, height: usize) -> Result<usize, String> {
+    if width == 0 || height < 2 {
+        return Err(format!("no compressed stream for a {}x{} image", width, height));
+    }
+    let lut_len = width * (height - 1) / 4;
+    let n_codes = width * (height - 1) - 1;
+    let raw_len = (width + 1) * 4;
+    if data.len() < lut_len + raw_len {
+        return Err(format!(
+            "image data has {} bytes, too short for the {} byte LUT and the first row",
+            data.len(),
+            lut_len
+        ));
+    }
+    if n_codes.div_ceil(4) > lut_len {
+        return Err(format!("LUT of {} bytes cannot hold {} entries", lut_len, n_codes));
+    }
+    let mut len = lut_len + raw_len;
+    for (i, code) in LutIter::new(&data[..lut_len], n_codes).enumerate() {
+        len += match code {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            _ => return Err(format!("invalid LUT entry {} for pixel {}", code, width + 1 + i)),
+        };
+    }
+    if len > data.len() {
+        return Err(format!(
+            "image data is {} bytes short of what the LUT describes",
+            len - data.len()
+        ));
+    }
+    Ok(len)
+}
+
+/// Decode a stream that `stream_length` accepted and return the pixels, by
+/// index, whose reconstructed value does not fit into 32 bits.
+pub fn pixels_out_of_range(data: &[u8], width: usize, height: usize) -> Vec<(usize, i64)> {
+    let lut_len = width * (height - 1) / 4;
+    let n_codes = width * (height - 1) - 1;
+    let mut pos = lut_len + (width + 1) * 4;
+    let mut pixels: Vec<i64> = data[lut_len..pos]
+        .chunks_exact(4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as i64)
+        .collect();
+    let mut bad = Vec::new();
+    for code in LutIter::new(&data[..lut_len], n_codes) {
+        let (diff, len) = match code {
+            0 => (data[pos] as i8 as i64, 1),
+            1 => (i16::from_ne_bytes([data[pos], data[pos + 1]]) as i64, 2),
+            _ => (i32::from_ne_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as i64, 4),
+        };
+        pos += len;
+        let i = pixels.len();
+        let value = pixels[i - width] + pixels[i - 1] - pixels[i - width - 1] + diff;
+        if !(0..=u32::MAX as i64).contains(&value) {
+            bad.push((i, value));
+        }
+        pixels.push(value);
+    }
+    bad
+}
+
+// Difference between two angles in degree, wrapped into (-180, 180].
+pub(crate) fn angle_step(from: f64, to: f64) -> f64 {
+    let d = (to - from).rem_euclid(360.0);
+    if d > 180.0 {
+        d - 360.0
+    } else {
+        d
+    }
+}
+
+/// Validate all files of a scan directory and their consistency: frame
+/// numbering, image sizes and the direction of rotation.
+pub fn check_scan(dir: &Path) -> Result<Vec<Issue>, io::Error> {
+    let files = crate::list_scan_files(dir)?;
+    let mut issues = Vec::new();
+    let mut report = |severity, path: &Path, message: String| {
+        issues.push(Issue {
+            severity,
+            path: path.to_path_buf(),
+            message,
+        })
+    };
+    if files.is_empty() {
+        report(Severity::Error, dir, "no HND files found".to_string());
+        return Ok(issues);
+    }
+
+    let mut headers = Vec::new();
+    let mut file_issues = Vec::new();
+    for path in &files {
+        file_issues.extend(check_file(path)?);
+        headers.push(crate::read_header(&mut File::open(path)?)?);
+    }
+
+    let indices: Vec<Option<u64>> = files.iter().map(|p| crate::frame_index(p)).collect();
+    for (i, path) in files.iter().enumerate() {
+        match (i.checked_sub(1).and_then(|j| indices[j]), indices[i]) {
+            (_, None) => report(Severity::Warning, path, "no frame number in the file name".to_string()),
+            (Some(prev), Some(cur)) if cur == prev => {
+                report(Severity::Error, path, format!("duplicate frame number {}", cur))
+            }
+            (Some(prev), Some(cur)) if cur == prev + 2 => {
+                report(Severity::Error, path, format!("frame {} is missing", prev + 1))
+            }
+            (Some(prev), Some(cur)) if cur > prev + 2 => report(
+                Severity::Error,
+                path,
+                format!("frames {} to {} are missing", prev + 1, cur - 1),
+            ),
+            _ => {}
+        }
+    }
+
+    let (width, height) = (headers[0].SizeX, headers[0].SizeY);
+    for (path, h) in files.iter().zip(&headers) {
+        if (h.SizeX, h.SizeY) != (width, height) {
+            report(
+                Severity::Error,
+                path,
+                format!("image size {}x{} differs from {}x{} of the first frame", h.SizeX, h.SizeY, width, height),
+            );
+        }
+    }
+
+    // the rotation direction is taken from the net travel of the gantry
+    let steps: Vec<f64> = headers
+        .windows(2)
+        .map(|w| angle_step(w[0].dCTProjectionAngle, w[1].dCTProjectionAngle))
+        .collect();
+    let direction = steps.iter().sum::<f64>().signum();
+    for (i, step) in steps.iter().enumerate() {
+        if *step == 0.0 {
+            report(Severity::Warning, &files[i + 1], "same projection angle as the previous frame".to_string());
+        } else if step.signum() != direction {
+            report(
+                Severity::Error,
+                &files[i + 1],
+                format!(
+                    "projection angle {} runs against the rotation direction",
+                    headers[i + 1].dCTProjectionAngle
+                ),
+            );
+        }
+    }
+
+    issues.extend(file_issues);
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file() {
+        let issues = check_file(Path::new("test/test_data_1.hnd")).unwrap();
+        assert_ne!(worst_severity(&issues), Some(Severity::Error));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("short.hnd");
+        let mut data = Vec::new();
+        File::open("test/test_data_1.hnd").unwrap().read_to_end(&mut data).unwrap();
+        std::fs::write(&path, &data[..data.len() - 10]).unwrap();
+        let issues = check_file(&path).unwrap();
+        assert_eq!(worst_severity(&issues), Some(Severity::Error));
+        assert!(issues.iter().any(|i| i.message.contains("10 bytes short")));
+    }
+
+    #[test]
+    fn test_check_pixels() {
+        let issues = check_file(Path::new("test/test_data_1.hnd")).unwrap();
+        assert!(issues.iter().any(|i| i.severity == Severity::Info && i.message.contains("not verified")));
+
+        // a 4x3 image of zeros, the first difference turned into -1
+        let mut header = crate::read_header(&mut File::open("test/test_data_1.hnd").unwrap()).unwrap();
+        header.SizeX = 4;
+        header.SizeY = 3;
+        let mut data = crate::encode_u32(&vec![0; 12], 4, 3).unwrap();
+        assert!(pixels_out_of_range(&data, 4, 3).is_empty());
+        data[2 + 5 * 4] = 0xff;
+        assert_eq!(pixels_out_of_range(&data, 4, 3), vec![(5, -1), (6, -1), (7, -1), (8, -1), (9, -2), (10, -2), (11, -2)]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("negative.hnd");
+        let mut raw = header.to_raw();
+        raw.extend_from_slice(&data);
+        std::fs::write(&path, &raw).unwrap();
+        let issues = check_file(&path).unwrap();
+        assert!(issues
+            .iter()
+            .any(|i| i.severity == Severity::Error && i.message.contains("7 pixel(s) decode outside")));
+    }
+
+    #[test]
+    fn test_check_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut header = crate::read_header(&mut File::open("test/test_data_1.hnd").unwrap()).unwrap();
+        for (i, angle) in [(0, 10.0), (1, 11.0), (3, 12.0), (4, 11.5)].iter() {
+            header.dCTProjectionAngle = *angle;
+            let path = dir.path().join(format!("Proj_{:05}.hnd", i));
+            crate::copy_with_header(Path::new("test/test_data_1.hnd"), &path, &header).unwrap();
+        }
+        let issues = check_scan(dir.path()).unwrap();
+        let errors: Vec<&str> = issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .map(|i| i.message.as_str())
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("frame 2 is missing"));
+        assert!(errors[1].contains("against the rotation direction"));
+    }
+}
@@ -0,0 +1,106 @@
+// Pixel level comparison of two decoded images.
+
+use crate::modal::ImageConvError;
+use crate::{RawImage, Size2D};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareStats {
+    pub n_pixels: usize,
+    pub n_different: usize,
+    pub max_abs_diff: u64,
+    pub rmse: f64,
+    /// Peak signal to noise ratio in dB, relative to the largest pixel value
+    /// of the first (reference) image. Infinite for identical images.
+    pub psnr: f64,
+}
+
+impl CompareStats {
+    pub fn identical(&self) -> bool {
+        self.n_different == 0
+    }
+}
+
+fn check_size(a: &RawImage<u32>, b: &RawImage<u32>) -> Result<(), ImageConvError> {
+    if a.width() != b.width() || a.height() != b.height() {
+        return Err(ImageConvError::SizeMismatch);
+    }
+    Ok(())
+}
+
+/// Compare image `b` against the reference image `a`.
+pub fn compare_images(a: &RawImage<u32>, b: &RawImage<u32>) -> Result<CompareStats, ImageConvError> {
+    check_size(a, b)?;
+    let mut n_different = 0;
+    let mut max_abs_diff = 0;
+    let mut sum_sq = 0.0;
+    for (x, y) in a.data().iter().zip(b.data()) {
+        let d = (*x as i64 - *y as i64).unsigned_abs();
+        if d != 0 {
+            n_different += 1;
+            max_abs_diff = max_abs_diff.max(d);
+            sum_sq += (d as f64) * (d as f64);
+        }
+    }
+    let n_pixels = a.data().len();
+    let mse = if n_pixels > 0 { sum_sq / n_pixels as f64 } else { 0.0 };
+    let peak = a.data().iter().cloned().max().unwrap_or(0) as f64;
+    let psnr = if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (peak * peak / mse).log10()
+    };
+    Ok(CompareStats {
+        n_pixels,
+        n_different,
+        max_abs_diff,
+        rmse: mse.sqrt(),
+        psnr,
+    })
+}
+
+/// The signed difference `b - a`, clamped to the range of i32.
+pub fn difference_image(a: &RawImage<u32>, b: &RawImage<u32>) -> Result<RawImage<i32>, ImageConvError> {
+    check_size(a, b)?;
+    let data = a
+        .data()
+        .iter()
+        .zip(b.data())
+        .map(|(x, y)| {
+            let d = *y as i64 - *x as i64;
+            d.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+        })
+        .collect();
+    RawImage::new(a.width(), a.height(), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_images() {
+        let a = RawImage::new(2, 2, vec![10, 20, 30, 40]).unwrap();
+        let b = RawImage::new(2, 2, vec![10, 22, 30, 36]).unwrap();
+        let stats = compare_images(&a, &a).unwrap();
+        assert!(stats.identical());
+        assert!(stats.psnr.is_infinite());
+
+        let stats = compare_images(&a, &b).unwrap();
+        assert_eq!(stats.n_different, 2);
+        assert_eq!(stats.max_abs_diff, 4);
+        assert_eq!(stats.rmse, 5f64.sqrt());
+        assert_eq!(difference_image(&a, &b).unwrap().data(), [0, 2, 0, -4]);
+
+        let c = RawImage::new(1, 4, vec![10, 20, 30, 40]).unwrap();
+        assert!(compare_images(&a, &c).is_err());
+    }
+
+    #[test]
+    fn test_compare_hnd_with_raw() {
+        let mut f = std::fs::File::open("test/test_data_1.hnd").unwrap();
+        let hnd = crate::read_image(&mut f).unwrap();
+        let mut f = std::fs::File::open("test/test_data_1.raw").unwrap();
+        let raw = crate::read_raw_image(&mut f, 1024, 768, 4).unwrap();
+        assert!(compare_images(&hnd, &raw).unwrap().identical());
+    }
+}
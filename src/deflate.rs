@@ -0,0 +1,168 @@
+// Minimal zlib/gzip framing for the image writers. The data is stored in
+// uncompressed deflate blocks, which every inflater reads, so no compression
+// library is needed.
+
+use std::io;
+use std::io::Write;
+
+const MAX_STORED_BLOCK: usize = 65535;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// CRC-32 as used by PNG, gzip and zip.
+#[cfg(test)]
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continue a CRC-32 over more data.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for b in data {
+        c = CRC32_TABLE[((c ^ *b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for x in chunk {
+            a += *x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// A raw deflate stream made of stored blocks.
+pub fn deflate_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK * 5 + 5);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(if chunks.peek().is_none() { 1 } else { 0 });
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+/// A zlib stream (RFC 1950) holding `data`.
+pub fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate_stored(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// A gzip member (RFC 1952) of stored blocks, written as the data streams
+/// in. `finish` must be called to write the trailer.
+pub struct GzipWriter<W: Write> {
+    w: W,
+    block: Vec<u8>,
+    crc: u32,
+    len: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub fn new(mut w: W) -> Result<GzipWriter<W>, io::Error> {
+        w.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+        Ok(GzipWriter {
+            w,
+            block: Vec::with_capacity(MAX_STORED_BLOCK),
+            crc: 0,
+            len: 0,
+        })
+    }
+
+    fn write_block(&mut self, last: bool) -> Result<(), io::Error> {
+        let len = self.block.len() as u16;
+        self.w.write_all(&[last as u8])?;
+        self.w.write_all(&len.to_le_bytes())?;
+        self.w.write_all(&(!len).to_le_bytes())?;
+        self.w.write_all(&self.block)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.write_block(true)?;
+        self.w.write_all(&self.crc.to_le_bytes())?;
+        self.w.write_all(&self.len.to_le_bytes())?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let n = buf.len().min(MAX_STORED_BLOCK - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        self.len = self.len.wrapping_add(n as u32);
+        if self.block.len() == MAX_STORED_BLOCK {
+            self.write_block(false)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_deflate_stored() {
+        assert_eq!(deflate_stored(b""), [1, 0, 0, 0xff, 0xff]);
+        let data = vec![7u8; 70000];
+        let out = deflate_stored(&data);
+        assert_eq!(out.len(), 70000 + 10);
+        assert_eq!(out[0], 0);
+        assert_eq!(out[5 + 65535], 1);
+    }
+
+    #[test]
+    fn test_gzip_writer() {
+        let data = vec![7u8; 70000];
+        let mut gz = GzipWriter::new(Vec::new()).unwrap();
+        gz.write_all(&data).unwrap();
+        let out = gz.finish().unwrap();
+        // a full block and the rest in the final block
+        assert_eq!(out.len(), 10 + 70000 + 10 + 8);
+        assert_eq!(&out[out.len() - 8..out.len() - 4], &crc32(&data).to_le_bytes());
+    }
+}
@@ -0,0 +1,714 @@
+// DICOM Part 10 RT Image export, explicit VR little endian, and import of
+// uncompressed little endian image files.
+//
+// The HND header keeps lengths in cm while DICOM uses mm. Varian marks unset
+// geometry fields with 99999999, those are left out of the DICOM file.
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::modal::{hnd_header_t, is_set, UNSET};
+use crate::{RawImage, Size2D};
+
+pub const RT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.481.1";
+pub const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+pub const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+
+// UID prefix for UUID derived UIDs (ISO/IEC 9834-8), see DICOM PS3.5 B.2.
+const UUID_ROOT: &str = "2.25.";
+const IMPLEMENTATION_CLASS_UID: &str = "2.25.163796420395317254658112476582134791713";
+const IMPLEMENTATION_VERSION: &str = "HND_RS_0_1";
+
+const VARIAN_FILE_TYPE: &str = "VARIAN_VA_INTERNAL_HND_1.0";
+
+/// A new globally unique UID.
+pub fn generate_uid() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut value: u128 = 0;
+    for i in 0..2u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u64(i);
+        value = (value << 64) | hasher.finish() as u128;
+    }
+    format!("{}{}", UUID_ROOT, value)
+}
+
+/// Identifiers shared by the images of one series.
+#[derive(Debug, Clone)]
+pub struct DicomIds {
+    pub study_uid: String,
+    pub series_uid: String,
+    pub series_number: u32,
+    pub instance_number: u32,
+}
+
+impl DicomIds {
+    pub fn new() -> DicomIds {
+        DicomIds {
+            study_uid: generate_uid(),
+            series_uid: generate_uid(),
+            series_number: 1,
+            instance_number: 1,
+        }
+    }
+}
+
+impl Default for DicomIds {
+    fn default() -> DicomIds {
+        DicomIds::new()
+    }
+}
+
+// Builds the encoded elements of a data set, which must be added in
+// ascending tag order.
+struct DataSet {
+    buf: Vec<u8>,
+}
+
+impl DataSet {
+    fn new() -> DataSet {
+        DataSet { buf: Vec::new() }
+    }
+
+    fn element(&mut self, group: u16, element: u16, vr: &str, value: &[u8]) {
+        self.buf.extend_from_slice(&group.to_le_bytes());
+        self.buf.extend_from_slice(&element.to_le_bytes());
+        self.buf.extend_from_slice(vr.as_bytes());
+        let padded = value.len() + value.len() % 2;
+        if ["OB", "OW", "OF", "SQ", "UT", "UN"].contains(&vr) {
+            self.buf.extend_from_slice(&[0, 0]);
+            self.buf.extend_from_slice(&(padded as u32).to_le_bytes());
+        } else {
+            self.buf.extend_from_slice(&(padded as u16).to_le_bytes());
+        }
+        self.buf.extend_from_slice(value);
+        if value.len() % 2 == 1 {
+            // UIDs are padded with NUL, everything else with a space
+            self.buf.push(if vr == "UI" || vr == "OB" { 0 } else { b' ' });
+        }
+    }
+
+    fn string(&mut self, group: u16, element: u16, vr: &str, value: &str) {
+        self.element(group, element, vr, value.as_bytes());
+    }
+
+    fn us(&mut self, group: u16, element: u16, value: u16) {
+        self.element(group, element, "US", &value.to_le_bytes());
+    }
+
+    fn ds(&mut self, group: u16, element: u16, values: &[f64]) {
+        let s: Vec<String> = values.iter().map(|v| format_ds(*v)).collect();
+        self.string(group, element, "DS", &s.join("\\"));
+    }
+
+    fn sequence(&mut self, group: u16, element: u16, items: &[DataSet]) {
+        let mut value = Vec::new();
+        for item in items {
+            value.extend_from_slice(&0xfffeu16.to_le_bytes());
+            value.extend_from_slice(&0xe000u16.to_le_bytes());
+            value.extend_from_slice(&(item.buf.len() as u32).to_le_bytes());
+            value.extend_from_slice(&item.buf);
+        }
+        self.element(group, element, "SQ", &value);
+    }
+}
+
+/// A decimal string of at most 16 characters.
+pub fn format_ds(v: f64) -> String {
+    if !v.is_finite() {
+        return "0".to_string();
+    }
+    for precision in (0..=10).rev() {
+        let s = format!("{:.*}", precision, v);
+        let s = if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s
+        };
+        if s.len() <= 16 {
+            return if s == "-0" { "0".to_string() } else { s };
+        }
+    }
+    format!("{:.6e}", v)
+}
+
+// HH:MM:SS as written by Varian to DICOM HHMMSS.
+fn format_tm(time: &str) -> String {
+    time.chars().filter(|c| c.is_ascii_digit()).take(6).collect()
+}
+
+fn format_da(date: &str) -> String {
+    if date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit()) {
+        date.to_string()
+    } else {
+        String::new()
+    }
+}
+
+fn cm_to_mm(v: f64) -> f64 {
+    v * 10.0
+}
+
+/// Write `img` as a DICOM RT Image with 16 or 32 bits per pixel, mapping the
+/// geometry and acquisition fields of `h` to the matching attributes.
+pub fn write_rt_image<W: Write>(
+    w: &mut W,
+    h: &hnd_header_t,
+    img: &RawImage<u32>,
+    bits: u16,
+    ids: &DicomIds,
+) -> Result<(), io::Error> {
+    let pixels = crate::pixel_bytes(img, bits)?;
+    let sop_instance_uid = generate_uid();
+    let date = format_da(&h.sCreationDate);
+    let time = format_tm(&h.sCreationTime);
+
+    let mut ds = DataSet::new();
+    ds.string(0x0008, 0x0008, "CS", "ORIGINAL\\PRIMARY\\PORTAL");
+    ds.string(0x0008, 0x0016, "UI", RT_IMAGE_STORAGE);
+    ds.string(0x0008, 0x0018, "UI", &sop_instance_uid);
+    ds.string(0x0008, 0x0020, "DA", &date);
+    ds.string(0x0008, 0x0023, "DA", &date);
+    ds.string(0x0008, 0x0030, "TM", &time);
+    ds.string(0x0008, 0x0033, "TM", &time);
+    ds.string(0x0008, 0x0050, "SH", "");
+    ds.string(0x0008, 0x0060, "CS", "RTIMAGE");
+    ds.string(0x0008, 0x0070, "LO", "");
+    ds.string(0x0008, 0x0090, "PN", "");
+    ds.string(0x0010, 0x0010, "PN", "");
+    ds.string(0x0010, 0x0020, "LO", h.sPatientID.trim_matches('\0'));
+    ds.string(0x0010, 0x0030, "DA", "");
+    ds.string(0x0010, 0x0040, "CS", "");
+    if is_set(h.dXRayKV) && h.dXRayKV > 0.0 {
+        ds.ds(0x0018, 0x0060, &[h.dXRayKV]);
+    }
+    if is_set(h.dXRayMA) && h.dXRayMA > 0.0 {
+        ds.string(0x0018, 0x1151, "IS", &format!("{}", h.dXRayMA.round() as i64));
+    }
+    ds.string(0x0020, 0x000d, "UI", &ids.study_uid);
+    ds.string(0x0020, 0x000e, "UI", &ids.series_uid);
+    ds.string(0x0020, 0x0010, "SH", "");
+    ds.string(0x0020, 0x0011, "IS", &ids.series_number.to_string());
+    ds.string(0x0020, 0x0013, "IS", &ids.instance_number.to_string());
+    ds.string(0x0020, 0x0020, "CS", "");
+    ds.us(0x0028, 0x0002, 1);
+    ds.string(0x0028, 0x0004, "CS", "MONOCHROME2");
+    ds.us(0x0028, 0x0010, img.height() as u16);
+    ds.us(0x0028, 0x0011, img.width() as u16);
+    ds.us(0x0028, 0x0100, bits);
+    ds.us(0x0028, 0x0101, bits);
+    ds.us(0x0028, 0x0102, bits - 1);
+    ds.us(0x0028, 0x0103, 0);
+    ds.string(0x3002, 0x0002, "SH", "HND");
+    ds.string(0x3002, 0x000c, "CS", "NORMAL");
+    if is_set(h.dIDUPosLat) && is_set(h.dIDUPosLng) && is_set(h.dIDUPosVrt) {
+        ds.ds(
+            0x3002,
+            0x000d,
+            &[cm_to_mm(h.dIDUPosLat), cm_to_mm(h.dIDUPosLng), cm_to_mm(h.dIDUPosVrt)],
+        );
+    }
+    if is_set(h.dIDUPosRtn) {
+        ds.ds(0x3002, 0x000e, &[h.dIDUPosRtn]);
+    }
+    // row spacing first, then column spacing
+    let (sx, sy) = (h.dImageResolutionX, h.dImageResolutionY);
+    if is_set(sx) && is_set(sy) && sx > 0.0 && sy > 0.0 {
+        ds.ds(0x3002, 0x0011, &[sy, sx]);
+        // centre of the upper left pixel, with the image centred on the
+        // beam axis
+        ds.ds(
+            0x3002,
+            0x0012,
+            &[-(img.width() as f64 - 1.0) / 2.0 * sx, (img.height() as f64 - 1.0) / 2.0 * sy],
+        );
+    }
+    ds.string(0x3002, 0x0020, "SH", "");
+    if is_set(h.dSAD) && h.dSAD > 0.0 {
+        ds.ds(0x3002, 0x0022, &[cm_to_mm(h.dSAD)]);
+        if is_set(h.dIDUPosVrt) {
+            ds.ds(0x3002, 0x0026, &[cm_to_mm(h.dSAD - h.dIDUPosVrt)]);
+        }
+    }
+    let jaws = [h.dCollX1, h.dCollX2, h.dCollY1, h.dCollY2];
+    if jaws.iter().all(|v| is_set(*v)) {
+        let mut devices = Vec::new();
+        for (name, a, b) in &[("ASYMX", h.dCollX1, h.dCollX2), ("ASYMY", h.dCollY1, h.dCollY2)] {
+            let mut item = DataSet::new();
+            item.string(0x300a, 0x00b8, "CS", name);
+            item.string(0x300a, 0x00bc, "IS", "1");
+            item.ds(0x300a, 0x011c, &[cm_to_mm(*a), cm_to_mm(*b)]);
+            devices.push(item);
+        }
+        let mut exposure = DataSet::new();
+        exposure.sequence(0x300a, 0x00b6, &devices);
+        ds.sequence(0x3002, 0x0030, &[exposure]);
+    }
+    if is_set(h.dGantryRtn) {
+        ds.ds(0x300a, 0x011e, &[h.dGantryRtn]);
+    }
+    if is_set(h.dCollRtn) {
+        ds.ds(0x300a, 0x0120, &[h.dCollRtn]);
+    }
+    if is_set(h.dPatientSupportAngle) {
+        ds.ds(0x300a, 0x0122, &[h.dPatientSupportAngle]);
+    }
+    ds.element(0x7fe0, 0x0010, "OW", &pixels);
+
+    let mut meta = DataSet::new();
+    meta.element(0x0002, 0x0001, "OB", &[0, 1]);
+    meta.string(0x0002, 0x0002, "UI", RT_IMAGE_STORAGE);
+    meta.string(0x0002, 0x0003, "UI", &sop_instance_uid);
+    meta.string(0x0002, 0x0010, "UI", EXPLICIT_VR_LITTLE_ENDIAN);
+    meta.string(0x0002, 0x0012, "UI", IMPLEMENTATION_CLASS_UID);
+    meta.string(0x0002, 0x0013, "SH", IMPLEMENTATION_VERSION);
+    let mut group_length = DataSet::new();
+    group_length.element(0x0002, 0x0000, "UL", &(meta.buf.len() as u32).to_le_bytes());
+
+    w.write_all(&[0u8; 128])?;
+    w.write_all(b"DICM")?;
+    w.write_all(&group_length.buf)?;
+    w.write_all(&meta.buf)?;
+    w.write_all(&ds.buf)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+type Tag = (u16, u16);
+
+const ITEM: Tag = (0xfffe, 0xe000);
+const ITEM_END: Tag = (0xfffe, 0xe00d);
+const SEQUENCE_END: Tag = (0xfffe, 0xe0dd);
+const PIXEL_DATA: Tag = (0x7fe0, 0x0010);
+const UNDEFINED_LENGTH: u32 = 0xffff_ffff;
+
+// Sequences read by `read_dicom`, needed to tell them apart in implicit VR
+// files.
+const SEQUENCES: &[Tag] = &[(0x3002, 0x0030), (0x300a, 0x00b6)];
+
+#[derive(Debug, Default)]
+struct Element {
+    value: Vec<u8>,
+    items: Vec<DicomObject>,
+}
+
+#[derive(Debug, Default)]
+struct DicomObject {
+    elements: BTreeMap<Tag, Element>,
+}
+
+impl DicomObject {
+    fn string(&self, tag: Tag) -> Option<String> {
+        let e = self.elements.get(&tag)?;
+        let s = String::from_utf8_lossy(&e.value);
+        let s = s.trim_matches(|c: char| c == ' ' || c == '\0');
+        if s.is_empty() {
+            None
+        } else {
+            Some(s.to_string())
+        }
+    }
+
+    fn numbers(&self, tag: Tag) -> Vec<f64> {
+        self.string(tag)
+            .map_or(Vec::new(), |s| s.split('\\').filter_map(|v| v.trim().parse().ok()).collect())
+    }
+
+    fn number(&self, tag: Tag) -> Option<f64> {
+        self.numbers(tag).first().cloned()
+    }
+
+    fn us(&self, tag: Tag) -> Option<u16> {
+        let v = &self.elements.get(&tag)?.value;
+        if v.len() >= 2 {
+            Some(u16::from_le_bytes([v[0], v[1]]))
+        } else {
+            None
+        }
+    }
+
+    fn items(&self, tag: Tag) -> &[DicomObject] {
+        self.elements.get(&tag).map_or(&[], |e| &e.items)
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    explicit: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, io::Error> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], io::Error> {
+        let b = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("unexpected end of DICOM data"))?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn peek_group(&self) -> Option<u16> {
+        let b = self.data.get(self.pos..self.pos + 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    // Tag, VR (empty for implicit VR and delimiters) and value length.
+    fn header(&mut self) -> Result<(Tag, [u8; 2], u32), io::Error> {
+        let tag = (self.u16()?, self.u16()?);
+        if tag.0 == 0xfffe || !self.explicit {
+            return Ok((tag, [0, 0], self.u32()?));
+        }
+        let vr = self.bytes(2)?;
+        let vr = [vr[0], vr[1]];
+        let len = match &vr {
+            b"OB" | b"OD" | b"OF" | b"OL" | b"OW" | b"SQ" | b"UC" | b"UR" | b"UT" | b"UN" => {
+                self.bytes(2)?;
+                self.u32()?
+            }
+            _ => self.u16()? as u32,
+        };
+        Ok((tag, vr, len))
+    }
+
+    // Parse elements until `end` or a delimiter, whichever comes first.
+    fn object(&mut self, end: usize, group: Option<u16>) -> Result<DicomObject, io::Error> {
+        let mut obj = DicomObject::default();
+        while self.pos < end {
+            if group.is_some() && self.peek_group() != group {
+                break;
+            }
+            let (tag, vr, len) = self.header()?;
+            if tag == ITEM_END || tag == SEQUENCE_END {
+                break;
+            }
+            let is_sequence = &vr == b"SQ" || (vr == [0, 0] && SEQUENCES.contains(&tag));
+            let element = if is_sequence || (len == UNDEFINED_LENGTH && tag != PIXEL_DATA) {
+                Element {
+                    value: Vec::new(),
+                    items: self.sequence(len)?,
+                }
+            } else if len == UNDEFINED_LENGTH {
+                return Err(invalid("compressed pixel data is not supported"));
+            } else {
+                Element {
+                    value: self.bytes(len as usize)?.to_vec(),
+                    items: Vec::new(),
+                }
+            };
+            obj.elements.insert(tag, element);
+        }
+        Ok(obj)
+    }
+
+    fn sequence(&mut self, len: u32) -> Result<Vec<DicomObject>, io::Error> {
+        let end = if len == UNDEFINED_LENGTH { self.data.len() } else { self.pos + len as usize };
+        let mut items = Vec::new();
+        while self.pos < end {
+            let (tag, _, item_len) = self.header()?;
+            match tag {
+                SEQUENCE_END => break,
+                ITEM if item_len == UNDEFINED_LENGTH => items.push(self.object(self.data.len(), None)?),
+                ITEM => {
+                    let item_end = self.pos + item_len as usize;
+                    items.push(self.object(item_end, None)?);
+                    self.pos = item_end;
+                }
+                _ => return Err(invalid("expected a sequence item")),
+            }
+        }
+        Ok(items)
+    }
+}
+
+fn parse(data: &[u8]) -> Result<DicomObject, io::Error> {
+    let start = if data.get(128..132) == Some(b"DICM") { 132 } else { 0 };
+    let mut p = Parser {
+        data,
+        pos: start,
+        explicit: true,
+    };
+    // the file meta group is always explicit VR little endian
+    let meta = if p.peek_group() == Some(0x0002) {
+        p.object(data.len(), Some(0x0002))?
+    } else {
+        DicomObject::default()
+    };
+    p.explicit = match meta.string((0x0002, 0x0010)) {
+        Some(ref ts) if ts == EXPLICIT_VR_LITTLE_ENDIAN => true,
+        Some(ref ts) if ts == IMPLICIT_VR_LITTLE_ENDIAN => false,
+        Some(ts) => {
+            return Err(invalid(&format!(
+                "unsupported transfer syntax {}, only uncompressed little endian is supported",
+                ts
+            )))
+        }
+        // no meta information, guess from the bytes where an explicit VR would be
+        None => data
+            .get(p.pos + 4..p.pos + 6)
+            .is_some_and(|vr| vr.iter().all(|b| b.is_ascii_uppercase())),
+    };
+    p.object(data.len(), None)
+}
+
+// Truncate to the size of a header string field.
+fn fit(s: &str, size: usize) -> String {
+    s.chars().filter(|c| c.is_ascii()).take(size).collect()
+}
+
+/// Parse an uncompressed little endian DICOM image (RT Image, CR, DX, ...)
+/// and map its geometry and acquisition attributes to a HND header. Signed
+/// pixels must not be negative, HND holds unsigned values only.
+pub fn read_dicom(data: &[u8]) -> Result<(hnd_header_t, RawImage<u32>), io::Error> {
+    let ds = parse(data)?;
+    let rows = ds.us((0x0028, 0x0010)).ok_or_else(|| invalid("no Rows"))? as usize;
+    let columns = ds.us((0x0028, 0x0011)).ok_or_else(|| invalid("no Columns"))? as usize;
+    let bits = ds.us((0x0028, 0x0100)).unwrap_or(16);
+    let stored = ds.us((0x0028, 0x0101)).unwrap_or(bits);
+    let signed = ds.us((0x0028, 0x0103)) == Some(1);
+    if ds.us((0x0028, 0x0002)).unwrap_or(1) != 1 {
+        return Err(invalid("only grayscale images are supported"));
+    }
+    if ds.number((0x0028, 0x0008)).unwrap_or(1.0) > 1.0 {
+        return Err(invalid("multi-frame images are not supported"));
+    }
+    let size = match bits {
+        8 | 16 | 32 => bits as usize / 8,
+        _ => return Err(invalid("only 8, 16 and 32 bits per pixel are supported")),
+    };
+    if stored == 0 || stored > bits {
+        return Err(invalid(&format!("{} bits stored in {} bits allocated", stored, bits)));
+    }
+    if rows < 2 || columns < 1 {
+        return Err(invalid(&format!("a {}x{} image cannot be stored as HND", columns, rows)));
+    }
+    let pixels = &ds.elements.get(&PIXEL_DATA).ok_or_else(|| invalid("no pixel data"))?.value;
+    if pixels.len() < rows * columns * size {
+        return Err(invalid("pixel data is too short"));
+    }
+    let mask = if stored == 32 { u32::MAX } else { (1u32 << stored) - 1 };
+    let values = pixels[..rows * columns * size]
+        .chunks_exact(size)
+        .map(|c| {
+            let v = match size {
+                1 => c[0] as u32,
+                2 => u16::from_le_bytes([c[0], c[1]]) as u32,
+                _ => u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
+            } & mask;
+            if signed && v >> (stored - 1) & 1 == 1 {
+                Err(invalid("negative pixel values cannot be stored as HND"))
+            } else {
+                Ok(v)
+            }
+        })
+        .collect::<Result<Vec<u32>, io::Error>>()?;
+    let img = RawImage::new(columns, rows, values)?;
+
+    let mut h = hnd_header_t::new();
+    h.sFileType = VARIAN_FILE_TYPE.to_string();
+    // unset like in files written by Varian, unless the DICOM file has them
+    h.dSFD = UNSET;
+    h.dCollX1 = UNSET;
+    h.dCollX2 = UNSET;
+    h.dCollY1 = UNSET;
+    h.dCollY2 = UNSET;
+    h.dCollRtn = UNSET;
+    h.dFieldX = UNSET;
+    h.dFieldY = UNSET;
+    h.dPatientSupportAngle = UNSET;
+    h.dTableTopEccentricAngle = UNSET;
+    h.dCouchVrt = UNSET;
+    h.dCouchLng = UNSET;
+    h.dCouchLat = UNSET;
+    h.SizeX = columns as u32;
+    h.SizeY = rows as u32;
+    if let Some(id) = ds.string((0x0010, 0x0020)) {
+        h.sPatientID = fit(&id, 16);
+    }
+    let date = ds.string((0x0008, 0x0023)).or_else(|| ds.string((0x0008, 0x0020)));
+    if let Some(date) = date.map(|d| format_da(&d)).filter(|d| !d.is_empty()) {
+        h.sCreationDate = date;
+    }
+    let time = ds.string((0x0008, 0x0033)).or_else(|| ds.string((0x0008, 0x0030)));
+    if let Some(t) = time.map(|t| format_tm(&t)).filter(|t| t.len() == 6) {
+        h.sCreationTime = format!("{}:{}:{}", &t[0..2], &t[2..4], &t[4..6]);
+    }
+    if let Some(kv) = ds.number((0x0018, 0x0060)) {
+        h.dXRayKV = kv;
+    }
+    if let Some(ma) = ds.number((0x0018, 0x1151)) {
+        h.dXRayMA = ma;
+    }
+
+    // RT Image attributes first, then the projection radiography ones
+    let sad = ds.number((0x3002, 0x0022)).or_else(|| ds.number((0x0018, 0x1111)));
+    let sid = ds.number((0x3002, 0x0026)).or_else(|| ds.number((0x0018, 0x1110)));
+    if let Some(sad) = sad {
+        h.dSAD = sad / 10.0;
+    }
+    let translation = ds.numbers((0x3002, 0x000d));
+    if translation.len() == 3 {
+        h.dIDUPosLat = translation[0] / 10.0;
+        h.dIDUPosLng = translation[1] / 10.0;
+        h.dIDUPosVrt = translation[2] / 10.0;
+    }
+    if let (Some(sad), Some(sid)) = (sad, sid) {
+        h.dIDUPosVrt = (sad - sid) / 10.0;
+    }
+    if let Some(angle) = ds.number((0x3002, 0x000e)) {
+        h.dIDUPosRtn = angle;
+    }
+
+    // spacing is row spacing, then column spacing
+    let plane = ds.numbers((0x3002, 0x0011));
+    let imager = ds.numbers((0x0018, 0x1164));
+    let pixel = ds.numbers((0x0028, 0x0030));
+    if plane.len() == 2 {
+        h.dImageResolutionY = plane[0];
+        h.dImageResolutionX = plane[1];
+        if let (Some(sad), Some(sid)) = (sad, sid) {
+            h.dIDUResolutionY = plane[0] * sid / sad;
+            h.dIDUResolutionX = plane[1] * sid / sad;
+        }
+    } else if imager.len() == 2 {
+        h.dIDUResolutionY = imager[0];
+        h.dIDUResolutionX = imager[1];
+        let scale = match (sad, sid) {
+            (Some(sad), Some(sid)) if sid > 0.0 => sad / sid,
+            _ => 1.0,
+        };
+        h.dImageResolutionY = imager[0] * scale;
+        h.dImageResolutionX = imager[1] * scale;
+    } else if pixel.len() == 2 {
+        h.dImageResolutionY = pixel[0];
+        h.dImageResolutionX = pixel[1];
+    }
+
+    if let Some(angle) = ds.number((0x300a, 0x011e)) {
+        h.dGantryRtn = angle;
+    }
+    if let Some(angle) = ds.number((0x300a, 0x0120)) {
+        h.dCollRtn = angle;
+    }
+    if let Some(angle) = ds.number((0x300a, 0x0122)) {
+        h.dPatientSupportAngle = angle;
+    }
+    for exposure in ds.items((0x3002, 0x0030)).iter().take(1) {
+        for device in exposure.items((0x300a, 0x00b6)) {
+            let jaws = device.numbers((0x300a, 0x011c));
+            if jaws.len() != 2 {
+                continue;
+            }
+            match device.string((0x300a, 0x00b8)).as_deref() {
+                Some("X") | Some("ASYMX") => {
+                    h.dCollX1 = jaws[0] / 10.0;
+                    h.dCollX2 = jaws[1] / 10.0;
+                }
+                Some("Y") | Some("ASYMY") => {
+                    h.dCollY1 = jaws[0] / 10.0;
+                    h.dCollY2 = jaws[1] / 10.0;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok((h, img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_ds() {
+        assert_eq!(format_ds(1000.0), "1000");
+        assert_eq!(format_ds(-71.01111111111112), "-71.0111111111");
+        assert_eq!(format_ds(0.2586666666666667), "0.2586666667");
+        assert!(format_ds(1.5601452701858437e9).len() <= 16);
+        assert_eq!(format_ds(-0.0), "0");
+    }
+
+    #[test]
+    fn test_write_rt_image() {
+        let mut f = std::fs::File::open("test/test_data_1.hnd").unwrap();
+        let header = crate::read_header(&mut f).unwrap();
+        let img = crate::read_image(&mut f).unwrap();
+        let mut buf = Vec::new();
+        write_rt_image(&mut buf, &header, &img, 32, &DicomIds::new()).unwrap();
+        assert_eq!(&buf[128..132], b"DICM");
+        // pixel data is the last element
+        let n = img.data().len() * 4;
+        assert_eq!(&buf[buf.len() - n - 12..buf.len() - n - 8], &[0xe0, 0x7f, 0x10, 0x00]);
+        assert_eq!(&buf[buf.len() - n..buf.len() - n + 4], &img.data()[0].to_le_bytes());
+
+        let (h, img2) = read_dicom(&buf).unwrap();
+        assert_eq!(img2.data(), img.data());
+        assert_eq!(h.dSAD, header.dSAD);
+        assert_eq!(h.dIDUPosVrt, header.dIDUPosVrt);
+        assert_eq!(h.sCreationDate, header.sCreationDate);
+        assert_eq!(h.sCreationTime, header.sCreationTime);
+        assert!((h.dGantryRtn - header.dGantryRtn).abs() < 1e-9);
+        assert!((h.dImageResolutionX - header.dImageResolutionX).abs() < 1e-9);
+        assert!((h.dIDUResolutionX - header.dIDUResolutionX).abs() < 1e-9);
+
+        let uid = generate_uid();
+        assert!(uid.len() <= 64);
+        assert_ne!(uid, generate_uid());
+    }
+
+    // A 2 column image without preamble or meta group, implicit VR.
+    fn implicit_vr(rows: u16, signed: bool, pixels: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut element = |group: u16, element: u16, value: &[u8]| {
+            data.extend_from_slice(&group.to_le_bytes());
+            data.extend_from_slice(&element.to_le_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        };
+        element(0x0018, 0x1110, b"1500");
+        element(0x0018, 0x1111, b"1000");
+        element(0x0018, 0x1164, b"0.3\\0.4 ");
+        element(0x0028, 0x0010, &rows.to_le_bytes());
+        element(0x0028, 0x0011, &2u16.to_le_bytes());
+        element(0x0028, 0x0100, &16u16.to_le_bytes());
+        element(0x0028, 0x0103, &(signed as u16).to_le_bytes());
+        element(0x7fe0, 0x0010, pixels);
+        data
+    }
+
+    #[test]
+    fn test_read_implicit_vr() {
+        let (h, img) = read_dicom(&implicit_vr(2, false, &[1, 0, 0xff, 0xff, 2, 0, 3, 0])).unwrap();
+        assert_eq!(img.data(), [1, 65535, 2, 3]);
+        assert_eq!((h.SizeX, h.SizeY), (2, 2));
+        assert_eq!((h.dSAD, h.dIDUPosVrt), (100.0, -50.0));
+        assert_eq!(h.dIDUResolutionX, 0.4);
+        assert!((h.dImageResolutionY - 0.2).abs() < 1e-12);
+
+        // -1 in the second pixel
+        assert!(read_dicom(&implicit_vr(2, true, &[1, 0, 0xff, 0xff, 2, 0, 3, 0])).is_err());
+        assert!(read_dicom(&implicit_vr(2, true, &[1, 0, 0xff, 0x7f, 2, 0, 3, 0])).is_ok());
+        // a single row cannot be encoded
+        assert!(read_dicom(&implicit_vr(1, false, &[1, 0, 2, 0])).is_err());
+    }
+}
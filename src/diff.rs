@@ -0,0 +1,132 @@
+// Field by field comparison of HND headers.
+
+use crate::modal::{hnd_header_t, HeaderValue, HEADER_FIELDS};
+
+/// Allowed difference between two f64 fields: `|a - b| <= abs + rel * max(|a|, |b|)`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tolerance {
+    pub abs: f64,
+    pub rel: f64,
+}
+
+impl Tolerance {
+    fn equal(&self, a: f64, b: f64) -> bool {
+        if a.is_nan() || b.is_nan() {
+            return a.is_nan() && b.is_nan();
+        }
+        a == b || (a - b).abs() <= self.abs + self.rel * a.abs().max(b.abs())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub a: HeaderValue,
+    pub b: HeaderValue,
+}
+
+/// List every header field that differs between `a` and `b`, skipping the
+/// fields named in `ignore`.
+pub fn diff_headers(
+    a: &hnd_header_t,
+    b: &hnd_header_t,
+    tol: &Tolerance,
+    ignore: &[&str],
+) -> Vec<FieldDiff> {
+    let mut diffs = Vec::new();
+    for field in HEADER_FIELDS {
+        if ignore.contains(field) {
+            continue;
+        }
+        let (va, vb) = (a.field(field).unwrap(), b.field(field).unwrap());
+        let same = match (&va, &vb) {
+            (HeaderValue::F64(x), HeaderValue::F64(y)) => tol.equal(*x, *y),
+            _ => va == vb,
+        };
+        if !same {
+            diffs.push(FieldDiff { field, a: va, b: vb });
+        }
+    }
+    diffs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameMatch {
+    /// Pair frames by their position in the scan.
+    Index,
+    /// Pair each frame with the frame of the other scan whose
+    /// `dCTProjectionAngle` is nearest.
+    Angle,
+}
+
+// Difference between two angles in degree, in [0, 180].
+fn angle_distance(a: f64, b: f64) -> f64 {
+    let d = (a - b).rem_euclid(360.0);
+    d.min(360.0 - d)
+}
+
+/// Pair the frames of scan `a` with frames of scan `b`. Frames of `a`
+/// without a partner are paired with `None`.
+pub fn match_frames(a: &[hnd_header_t], b: &[hnd_header_t], mode: FrameMatch) -> Vec<(usize, Option<usize>)> {
+    match mode {
+        FrameMatch::Index => (0..a.len())
+            .map(|i| (i, if i < b.len() { Some(i) } else { None }))
+            .collect(),
+        FrameMatch::Angle => a
+            .iter()
+            .enumerate()
+            .map(|(i, ha)| {
+                let nearest = b
+                    .iter()
+                    .enumerate()
+                    .map(|(j, hb)| (j, angle_distance(ha.dCTProjectionAngle, hb.dCTProjectionAngle)))
+                    .fold(None, |best: Option<(usize, f64)>, (j, d)| match best {
+                        Some((_, bd)) if bd <= d => best,
+                        _ => Some((j, d)),
+                    });
+                (i, nearest.map(|(j, _)| j))
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_headers() {
+        let mut f = std::fs::File::open("test/test_data_1.hnd").unwrap();
+        let a = crate::read_header(&mut f).unwrap();
+        let mut b = a.clone();
+        assert!(diff_headers(&a, &b, &Tolerance::default(), &[]).is_empty());
+
+        b.dSAD += 0.001;
+        b.sModality = "CT".to_string();
+        let diffs = diff_headers(&a, &b, &Tolerance::default(), &[]);
+        let fields: Vec<&str> = diffs.iter().map(|d| d.field).collect();
+        assert_eq!(fields, ["sModality", "dSAD"]);
+
+        let tol = Tolerance { abs: 0.01, rel: 0.0 };
+        assert_eq!(diff_headers(&a, &b, &tol, &[]).len(), 1);
+        assert!(diff_headers(&a, &b, &tol, &["sModality"]).is_empty());
+    }
+
+    #[test]
+    fn test_match_frames() {
+        let frame = |angle| hnd_header_t {
+            dCTProjectionAngle: angle,
+            ..Default::default()
+        };
+        let a = vec![frame(0.0), frame(10.0), frame(179.0)];
+        let b = vec![frame(-179.0), frame(9.0)];
+        assert_eq!(
+            match_frames(&a, &b, FrameMatch::Index),
+            [(0, Some(0)), (1, Some(1)), (2, None)]
+        );
+        assert_eq!(
+            match_frames(&a, &b, FrameMatch::Angle),
+            [(0, Some(1)), (1, Some(1)), (2, Some(0))]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+// Small dense least squares for the calibration fits.
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting. `None` if
+/// the matrix is singular or anything is not finite.
+pub fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    if a.iter().flatten().chain(&b).any(|v| !v.is_finite()) {
+        return None;
+    }
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-300 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (upper, lower) = a.split_at_mut(col + 1);
+        for (i, r) in lower.iter_mut().enumerate() {
+            let f = r[col] / upper[col][col];
+            for (v, p) in r[col..].iter_mut().zip(&upper[col][col..]) {
+                *v -= f * p;
+            }
+            b[col + 1 + i] -= f * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - s) / a[row][row];
+    }
+    if x.iter().all(|v| v.is_finite()) {
+        Some(x)
+    } else {
+        None
+    }
+}
+
+/// Least squares solution of `rows * x = rhs` through the normal equations.
+pub fn least_squares(rows: &[Vec<f64>], rhs: &[f64]) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+    let mut ata = vec![vec![0.0; n]; n];
+    let mut atb = vec![0.0; n];
+    for (row, y) in rows.iter().zip(rhs) {
+        for i in 0..n {
+            atb[i] += row[i] * y;
+            for j in 0..n {
+                ata[i][j] += row[i] * row[j];
+            }
+        }
+    }
+    solve(ata, atb)
+}
+
+/// Least squares with `x >= 0`: columns whose coefficient comes out
+/// negative are dropped (set to zero) and the rest refitted.
+pub fn nonnegative_least_squares(rows: &[Vec<f64>], rhs: &[f64]) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+    let mut active: Vec<usize> = (0..n).collect();
+    while !active.is_empty() {
+        let sub: Vec<Vec<f64>> = rows.iter().map(|r| active.iter().map(|i| r[*i]).collect()).collect();
+        let x = least_squares(&sub, rhs)?;
+        match x.iter().enumerate().min_by(|a, b| a.1.total_cmp(b.1)) {
+            Some((i, v)) if *v < 0.0 => {
+                active.remove(i);
+            }
+            _ => {
+                let mut out = vec![0.0; n];
+                for (i, v) in active.iter().zip(x) {
+                    out[*i] = v;
+                }
+                return Some(out);
+            }
+        }
+    }
+    Some(vec![0.0; n])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_squares() {
+        // y = 1 + 2 x
+        let rows: Vec<Vec<f64>> = (0..5).map(|x| vec![1.0, x as f64]).collect();
+        let rhs: Vec<f64> = (0..5).map(|x| 1.0 + 2.0 * x as f64).collect();
+        let x = least_squares(&rows, &rhs).unwrap();
+        assert!((x[0] - 1.0).abs() < 1e-9 && (x[1] - 2.0).abs() < 1e-9);
+
+        // y = 3 - x has no non-negative fit with a slope
+        let rhs: Vec<f64> = (0..5).map(|x| 3.0 - x as f64).collect();
+        let x = nonnegative_least_squares(&rows, &rhs).unwrap();
+        assert_eq!(x[1], 0.0);
+        assert!((x[0] - 1.0).abs() < 1e-9);
+
+        let mut rhs = rhs;
+        rhs[2] = f64::NAN;
+        assert!(least_squares(&rows, &rhs).is_none());
+        assert!(nonnegative_least_squares(&rows, &rhs).is_none());
+        assert!(solve(vec![vec![1.0, 2.0], vec![2.0, 4.0]], vec![1.0, 2.0]).is_none());
+    }
+}
@@ -0,0 +1,295 @@
+// Flexmap correction: the imager arm sags with the gantry angle, moving the
+// detector (and a little the source) away from where the header puts it.
+//
+// A flexmap holds the displacement per projection angle in mm, detector u
+// along the columns and v up the rows as in the phantom module, and
+// optionally the source in the same directions. Displacements in between
+// are interpolated linearly, around the full circle.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::modal::{hnd_header_t, is_set};
+use crate::phantom::ConeBeam;
+use crate::{HndImage, RawImage};
+
+/// Displacement of detector and source at one angle, in mm.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FlexShift {
+    pub detector: [f64; 2],
+    pub source: [f64; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flexmap {
+    /// Projection angle in degree and displacement, sorted by angle in
+    /// [0, 360).
+    pub entries: Vec<(f64, FlexShift)>,
+}
+
+impl Flexmap {
+    pub fn new(mut entries: Vec<(f64, FlexShift)>) -> Flexmap {
+        for e in &mut entries {
+            e.0 = e.0.rem_euclid(360.0);
+        }
+        entries.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Flexmap { entries }
+    }
+
+    /// Parse the text form: one `angle u v [source_u source_v]` line per
+    /// entry, `#` comments.
+    pub fn parse(text: &str) -> Result<Flexmap, String> {
+        let mut entries = Vec::new();
+        for row in crate::parse_frame_table(text)? {
+            if let Some(v) = row.iter().find(|v| !v.is_finite()) {
+                return Err(format!("{} is not a finite angle or displacement", v));
+            }
+            let shift = match row.len() {
+                3 => FlexShift {
+                    detector: [row[1], row[2]],
+                    source: [0.0, 0.0],
+                },
+                5 => FlexShift {
+                    detector: [row[1], row[2]],
+                    source: [row[3], row[4]],
+                },
+                n => return Err(format!("expected angle, u, v and optionally the source u, v, got {} values", n)),
+            };
+            entries.push((row[0], shift));
+        }
+        if entries.is_empty() {
+            return Err("the flexmap is empty".to_string());
+        }
+        Ok(Flexmap::new(entries))
+    }
+
+    pub fn load(path: &Path) -> Result<Flexmap, io::Error> {
+        crate::scan::parse_file(path, Flexmap::parse)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# angle detector_u detector_v source_u source_v (mm)\n");
+        for (angle, s) in &self.entries {
+            out.push_str(&format!(
+                "{} {} {} {} {}\n",
+                angle, s.detector[0], s.detector[1], s.source[0], s.source[1]
+            ));
+        }
+        out
+    }
+
+    /// The displacement at `angle` (degree), none for an empty map.
+    pub fn at(&self, angle: f64) -> FlexShift {
+        let n = self.entries.len();
+        match n {
+            0 => return FlexShift::default(),
+            1 => return self.entries[0].1,
+            _ => {}
+        }
+        let angle = angle.rem_euclid(360.0);
+        let next = self.entries.iter().position(|e| e.0 > angle).unwrap_or(0);
+        let prev = (next + n - 1) % n;
+        let (a0, s0) = self.entries[prev];
+        let (a1, s1) = self.entries[next];
+        let span = (a1 - a0).rem_euclid(360.0);
+        let t = if span > 0.0 { (angle - a0).rem_euclid(360.0) / span } else { 0.0 };
+        let lerp = |x: f64, y: f64| x + t * (y - x);
+        FlexShift {
+            detector: [lerp(s0.detector[0], s1.detector[0]), lerp(s0.detector[1], s1.detector[1])],
+            source: [lerp(s0.source[0], s1.source[0]), lerp(s0.source[1], s1.source[1])],
+        }
+    }
+}
+
+/// Move the detector position in the header by the displacement, so that an
+/// exported geometry is the real one. The source has no place in the header.
+pub fn apply_flexmap_header(h: &mut hnd_header_t, shift: &FlexShift) {
+    let add = |v: f64, mm: f64| if is_set(v) { v + mm / 10.0 } else { mm / 10.0 };
+    h.dIDUPosLat = add(h.dIDUPosLat, shift.detector[0]);
+    h.dIDUPosLng = add(h.dIDUPosLng, shift.detector[1]);
+}
+
+/// Resample the projection onto the detector position the header claims,
+/// `pitch` the pixel spacing in mm.
+pub fn apply_flexmap_image(img: &RawImage<f64>, shift: &FlexShift, pitch: [f64; 2]) -> RawImage<f64> {
+    let (w, h) = (img.width, img.height);
+    let (dx, dy) = (shift.detector[0] / pitch[0], shift.detector[1] / pitch[1]);
+    let at = |x: i64, y: i64| {
+        let x = x.max(0).min(w as i64 - 1) as usize;
+        let y = y.max(0).min(h as i64 - 1) as usize;
+        img.data[y * w + x]
+    };
+    let mut data = Vec::with_capacity(w * h);
+    for row in 0..h {
+        // v grows up the rows
+        let fy = row as f64 + dy;
+        let (y0, ty) = (fy.floor() as i64, fy - fy.floor());
+        for col in 0..w {
+            let fx = col as f64 - dx;
+            let (x0, tx) = (fx.floor() as i64, fx - fx.floor());
+            let top = at(x0, y0) * (1.0 - tx) + at(x0 + 1, y0) * tx;
+            let bottom = at(x0, y0 + 1) * (1.0 - tx) + at(x0 + 1, y0 + 1) * tx;
+            data.push(top * (1.0 - ty) + bottom * ty);
+        }
+    }
+    RawImage { width: w, height: h, data }
+}
+
+/// Column and row of a small dense marker: the centroid of the attenuation
+/// above the local background in a `radius` pixel window around the darkest
+/// spot.
+pub fn locate_marker(img: &RawImage<f64>, radius: usize) -> Option<[f64; 2]> {
+    let (w, h) = (img.width, img.height);
+    if w < 3 || h < 3 {
+        return None;
+    }
+    // 3x3 box against single noisy pixels
+    let mut darkest = (f64::INFINITY, 0, 0);
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let mut sum = 0.0;
+            for dy in 0..3 {
+                sum += img.data[(y + dy - 1) * w + x - 1..(y + dy - 1) * w + x + 2].iter().sum::<f64>();
+            }
+            if sum < darkest.0 {
+                darkest = (sum, x, y);
+            }
+        }
+    }
+    let (cx, cy) = (darkest.1, darkest.2);
+    let (x0, x1) = (cx.saturating_sub(radius), (cx + radius).min(w - 1));
+    let (y0, y1) = (cy.saturating_sub(radius), (cy + radius).min(h - 1));
+    let mut border: Vec<f64> = Vec::new();
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            if x == x0 || x == x1 || y == y0 || y == y1 {
+                border.push(img.data[y * w + x]);
+            }
+        }
+    }
+    border.sort_by(|a, b| a.total_cmp(b));
+    let background = border[border.len() / 2];
+    let (mut sum, mut sx, mut sy) = (0.0, 0.0, 0.0);
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let weight = (background - img.data[y * w + x]).max(0.0);
+            sum += weight;
+            sx += weight * x as f64;
+            sy += weight * y as f64;
+        }
+    }
+    if sum > 0.0 {
+        Some([sx / sum, sy / sum])
+    } else {
+        None
+    }
+}
+
+/// The detector displacement of one projection of a marker at `marker`
+/// (mm, patient frame of the phantom module): where the header geometry
+/// expects the marker minus where it is found.
+pub fn marker_shift(header: &hnd_header_t, img: &RawImage<f64>, marker: [f64; 3], radius: usize) -> Result<Option<FlexShift>, io::Error> {
+    let geometry = ConeBeam::from_header(header)?;
+    let expected = geometry.project_point(marker, header.dCTProjectionAngle);
+    Ok(locate_marker(img, radius).map(|[col, row]| {
+        let found = [
+            (col - (img.width as f64 - 1.0) / 2.0) * geometry.pitch[0] + geometry.offset[0],
+            ((img.height as f64 - 1.0) / 2.0 - row) * geometry.pitch[1] + geometry.offset[1],
+        ];
+        FlexShift {
+            detector: [expected[0] - found[0], expected[1] - found[1]],
+            source: [0.0, 0.0],
+        }
+    }))
+}
+
+/// Derive a flexmap from a scan of a single ball bearing at `marker`. Frames
+/// where the marker is not found are left out.
+pub fn derive_flexmap(dir: &Path, marker: [f64; 3], radius: usize) -> Result<Flexmap, io::Error> {
+    let mut entries = Vec::new();
+    for (path, header) in crate::read_scan_headers(dir)? {
+        let img: RawImage<f64> = crate::read_image(&mut File::open(&path)?)?.convert();
+        if let Some(shift) = marker_shift(&header, &img, marker, radius)? {
+            entries.push((header.dCTProjectionAngle, shift));
+        }
+    }
+    if entries.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "marker not found in any frame"));
+    }
+    Ok(Flexmap::new(entries))
+}
+
+/// Correct one HND file, either by moving the detector in the header or by
+/// shifting the image.
+pub fn flexmap_correct_file(input: &Path, output: &Path, flexmap: &Flexmap, image: bool) -> Result<(), io::Error> {
+    let mut fin = File::open(input)?;
+    let mut header = crate::read_header(&mut fin)?;
+    let img = crate::read_image(&mut fin)?;
+    let shift = flexmap.at(header.dCTProjectionAngle);
+    let img = if image {
+        let pitch = crate::scatter::detector_pitch(&header)?;
+        apply_flexmap_image(&img.convert(), &shift, pitch).convert()
+    } else {
+        apply_flexmap_header(&mut header, &shift);
+        img
+    };
+    crate::write_file(&mut File::create(output)?, &HndImage::new(header, &img)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phantom::{Shape, Solid};
+
+    #[test]
+    fn test_interpolation() {
+        let map = Flexmap::parse("# angle u v\n0 1 0\n90 2 -1\n270 0 1 0.5 0\n").unwrap();
+        assert_eq!(map.at(45.0).detector, [1.5, -0.5]);
+        assert_eq!(map.at(-45.0).detector, [0.5, 0.5]);
+        assert_eq!(map.at(315.0).source, [0.25, 0.0]);
+        assert_eq!(Flexmap::parse(&map.to_text()).unwrap(), map);
+
+        assert!(Flexmap::parse("0 1 0\nNaN 2 -1\n").is_err());
+        assert!(Flexmap::parse("0 1 0\ninf 2 -1\n").is_err());
+        assert!(Flexmap::parse("0 1 inf\n").is_err());
+        assert_eq!(Flexmap::new(Vec::new()).at(45.0), FlexShift::default());
+        let nan = Flexmap::new(vec![(f64::NAN, FlexShift::default()), (10.0, FlexShift::default())]);
+        assert_eq!(nan.entries[0].0, 10.0);
+    }
+
+    #[test]
+    fn test_marker_shift() {
+        let mut header = crate::default_scan_header();
+        header.SizeX = 64;
+        header.SizeY = 48;
+        header.dCTProjectionAngle = 30.0;
+        let bb = [Solid {
+            shape: Shape::Ellipsoid,
+            center: [5.0, -3.0, 2.0],
+            axes: [1.0, 1.0, 1.0],
+            phi: 0.0,
+            mu: 0.5,
+        }];
+        // the real detector sits 1.5 mm along u and 0.8 mm down from the
+        // header position
+        let mut actual = ConeBeam::from_header(&header).unwrap();
+        actual.offset = [1.5, -0.8];
+        let p = actual.project(&bb, 30.0);
+        let img = RawImage::new(64, 48, p.iter().map(|p| 1000.0 * (-p).exp()).collect()).unwrap();
+        let shift = marker_shift(&header, &img, [5.0, -3.0, 2.0], 6).unwrap().unwrap();
+        assert!((shift.detector[0] - 1.5).abs() < 0.1, "{:?}", shift);
+        assert!((shift.detector[1] + 0.8).abs() < 0.1, "{:?}", shift);
+
+        // shifting the image puts the marker where the header expects it
+        let corrected = apply_flexmap_image(&img, &shift, actual.pitch);
+        let expected = ConeBeam::from_header(&header).unwrap();
+        let [col, row] = expected.to_pixel(expected.project_point([5.0, -3.0, 2.0], 30.0));
+        let [c, r] = locate_marker(&corrected, 6).unwrap();
+        assert!((c - col).abs() < 0.2 && (r - row).abs() < 0.2);
+
+        apply_flexmap_header(&mut header, &shift);
+        let moved = ConeBeam::from_header(&header).unwrap();
+        assert!((moved.offset[0] - shift.detector[0]).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,360 @@
+// Machine readable renderings of the HND header (JSON and CSV).
+//
+// The JSON of a whole header is the format of `hnd header export` (the
+// `serde` feature): the fields by name in file order, then `Reserved` as
+// hex, and floats JSON has no number for as strings, see `float_name`.
+
+use crate::modal::{hnd_header_t, HeaderFieldError, HeaderValue, HEADER_FIELDS, HEADER_FIELDS_SIZE};
+
+pub(crate) const RESERVED_SIZE: usize = 1024 - HEADER_FIELDS_SIZE;
+// The NaN Rust and most writers produce.
+const NAN_BITS: u64 = 0x7ff8_0000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown output format `{}`", s)),
+        }
+    }
+}
+
+// An empty selection means "all fields".
+fn selected<'a>(fields: &'a [&'a str]) -> &'a [&'a str] {
+    if fields.is_empty() {
+        HEADER_FIELDS
+    } else {
+        fields
+    }
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The name of a float JSON has no number for: `inf`, `-inf`, `NaN`, or
+/// `NaN:0x<bits>` for a NaN with an unusual bit pattern, so that it reads
+/// back to the same bits. `None` for finite values.
+pub(crate) fn float_name(x: f64) -> Option<String> {
+    if x.is_finite() {
+        None
+    } else if x.is_infinite() {
+        Some(if x > 0.0 { "inf" } else { "-inf" }.to_string())
+    } else if x.to_bits() == NAN_BITS {
+        Some("NaN".to_string())
+    } else {
+        Some(format!("NaN:0x{:016x}", x.to_bits()))
+    }
+}
+
+/// The float named by `float_name`.
+pub(crate) fn parse_float_name(s: &str) -> Option<f64> {
+    match s {
+        "inf" => Some(f64::INFINITY),
+        "-inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::from_bits(NAN_BITS)),
+        _ => s
+            .strip_prefix("NaN:0x")
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .map(f64::from_bits)
+            .filter(|x| x.is_nan()),
+    }
+}
+
+/// The reserved bytes as hex, trailing zero bytes left out.
+pub(crate) fn reserved_hex(bytes: &[u8]) -> String {
+    let used = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    bytes[..used].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The reserved bytes from `reserved_hex`, padded with zeros.
+pub(crate) fn parse_reserved(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) || hex.len() / 2 > RESERVED_SIZE || !hex.is_ascii() {
+        return Err(format!("Reserved must be at most {} bytes of hex", RESERVED_SIZE));
+    }
+    let mut bytes = (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| format!("Reserved: bad hex {:?}", &hex[2 * i..2 * i + 2])))
+        .collect::<Result<Vec<u8>, String>>()?;
+    bytes.resize(RESERVED_SIZE, 0);
+    Ok(bytes)
+}
+
+fn json_value(v: &HeaderValue) -> String {
+    match v {
+        HeaderValue::Str(s) => json_escape(s),
+        HeaderValue::U32(n) => n.to_string(),
+        HeaderValue::F64(x) => match float_name(*x) {
+            Some(name) => json_escape(&name),
+            None => v.to_string(),
+        },
+    }
+}
+
+pub fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Render the selected header fields as a single JSON object. If `fields`
+/// is empty, all of them and the reserved bytes.
+pub fn header_to_json(h: &hnd_header_t, fields: &[&str]) -> Result<String, HeaderFieldError> {
+    let mut members = Vec::new();
+    for name in selected(fields) {
+        let value = h.field(name)?;
+        members.push(format!("{}: {}", json_escape(name), json_value(&value)));
+    }
+    if fields.is_empty() {
+        members.push(format!("\"Reserved\": {}", json_escape(&reserved_hex(&h.Reserved))));
+    }
+    Ok(format!("{{{}}}", members.join(", ")))
+}
+
+/// The CSV header line matching `header_to_csv`.
+pub fn csv_columns(fields: &[&str]) -> String {
+    selected(fields)
+        .iter()
+        .map(|name| csv_escape(name))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Render the selected header fields as one CSV row.
+pub fn header_to_csv(h: &hnd_header_t, fields: &[&str]) -> Result<String, HeaderFieldError> {
+    let mut cells = Vec::new();
+    for name in selected(fields) {
+        cells.push(csv_escape(&h.field(name)?.to_string()));
+    }
+    Ok(cells.join(","))
+}
+
+// A flat JSON object of strings and numbers, as written by `header_to_json`.
+// Nested values are not supported.
+struct JsonParser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+#[derive(Debug, PartialEq)]
+enum JsonScalar {
+    Str(String),
+    Num(String),
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, what: &str) -> HeaderFieldError {
+        HeaderFieldError::InvalidValue("<json>".to_string(), format!("{} at byte {}", what, self.pos))
+    }
+
+    fn skip_ws(&mut self) {
+        while self.s[self.pos..].starts_with(|c: char| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), HeaderFieldError> {
+        self.skip_ws();
+        if self.s[self.pos..].starts_with(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, HeaderFieldError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((j, 'u')) => {
+                            let start = self.pos + j + 1;
+                            let hex = self.s.get(start..start + 4).ok_or_else(|| self.error("bad escape"))?;
+                            let code = u32::from_str_radix(hex, 16).map_err(|_| self.error("bad escape"))?;
+                            for _ in 0..4 {
+                                chars.next();
+                            }
+                            std::char::from_u32(code).ok_or_else(|| self.error("bad escape"))?
+                        }
+                        _ => return Err(self.error("bad escape")),
+                    };
+                    out.push(escaped);
+                }
+                c => out.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn scalar(&mut self) -> Result<JsonScalar, HeaderFieldError> {
+        self.skip_ws();
+        let rest = &self.s[self.pos..];
+        if rest.starts_with('"') {
+            return Ok(JsonScalar::Str(self.string()?));
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a string or number"));
+        }
+        self.pos += len;
+        Ok(JsonScalar::Num(rest[..len].to_string()))
+    }
+
+    fn object(&mut self) -> Result<Vec<(String, JsonScalar)>, HeaderFieldError> {
+        let mut members = Vec::new();
+        self.expect('{')?;
+        self.skip_ws();
+        if self.s[self.pos..].starts_with('}') {
+            self.pos += 1;
+            return Ok(members);
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            members.push((key, self.scalar()?));
+            self.skip_ws();
+            if self.s[self.pos..].starts_with(',') {
+                self.pos += 1;
+            } else {
+                self.expect('}')?;
+                return Ok(members);
+            }
+        }
+    }
+}
+
+/// Parse a JSON object of header fields, as written by `header_to_json` or
+/// `hnd header export`, on top of `base`. Fields missing from the object keep
+/// their value in `base`, unknown fields are an error.
+pub fn header_from_json(json: &str, base: &hnd_header_t) -> Result<hnd_header_t, HeaderFieldError> {
+    let mut parser = JsonParser { s: json, pos: 0 };
+    let members = parser.object()?;
+    parser.skip_ws();
+    if parser.pos != json.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    let mut h = base.clone();
+    for (name, value) in members {
+        if name == "Reserved" {
+            h.Reserved = match value {
+                JsonScalar::Str(hex) => {
+                    parse_reserved(&hex).map_err(|e| HeaderFieldError::InvalidValue(name, e))?
+                }
+                _ => return Err(HeaderFieldError::TypeMismatch(name)),
+            };
+            continue;
+        }
+        let value = match (h.field(&name)?, value) {
+            (HeaderValue::F64(_), JsonScalar::Str(s)) => match parse_float_name(&s) {
+                Some(x) => HeaderValue::F64(x),
+                None => return Err(HeaderFieldError::InvalidValue(name, format!("`{}` is not a number", s))),
+            },
+            (HeaderValue::Str(_), JsonScalar::Str(s)) => HeaderValue::Str(s),
+            (_, JsonScalar::Num(n)) => {
+                h.set_field_str(&name, &n)?;
+                continue;
+            }
+            _ => return Err(HeaderFieldError::TypeMismatch(name)),
+        };
+        h.set_field(&name, value)?;
+    }
+    Ok(h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_to_json_and_csv() {
+        let mut f = std::fs::File::open("test/test_data_1.hnd").unwrap();
+        let header = crate::read_header(&mut f).unwrap();
+
+        let json = header_to_json(&header, &["SizeX", "dCTProjectionAngle", "sFileType"]).unwrap();
+        assert_eq!(
+            json,
+            "{\"SizeX\": 1024, \"dCTProjectionAngle\": -71.01111111111112, \"sFileType\": \"VARIAN_VA_INTERNAL_HND_1.0\"}"
+        );
+
+        assert_eq!(csv_columns(&["SizeY", "dCTNormChamber"]), "SizeY,dCTNormChamber");
+        assert_eq!(header_to_csv(&header, &["SizeY", "dCTNormChamber"]).unwrap(), "768,1164");
+
+        assert!(header_to_json(&header, &["NoSuchField"]).is_err());
+
+        let mut tiny = header.clone();
+        tiny.dGating4DInfoX = 1e-307;
+        tiny.dGating4DInfoY = 1e300;
+        let fields = ["dGating4DInfoX", "dGating4DInfoY"];
+        assert_eq!(header_to_csv(&tiny, &fields).unwrap(), "1e-307,1e300");
+        assert_eq!(header_to_json(&tiny, &fields).unwrap(), "{\"dGating4DInfoX\": 1e-307, \"dGating4DInfoY\": 1e300}");
+        let parsed = header_from_json(&header_to_json(&tiny, &[]).unwrap(), &header).unwrap();
+        assert_eq!(parsed.dGating4DInfoX, 1e-307);
+
+        // the format of `hnd header export`
+        let mut odd = header.clone();
+        odd.dCouchLat = f64::NEG_INFINITY;
+        odd.dGating4DInfoY = f64::from_bits(0xfff4_0000_0000_0001);
+        odd.Reserved = vec![0; RESERVED_SIZE];
+        odd.Reserved[3] = 0xab;
+        let json = header_to_json(&odd, &[]).unwrap();
+        assert!(json.contains("\"dCouchLat\": \"-inf\""), "{}", json);
+        assert!(json.contains("\"dGating4DInfoY\": \"NaN:0xfff4000000000001\""));
+        assert!(json.ends_with("\"Reserved\": \"000000ab\"}"));
+        let parsed = header_from_json(&json, &hnd_header_t::new()).unwrap();
+        assert_eq!(parsed.to_raw(), odd.to_raw());
+        assert!(header_from_json("{\"dCouchLat\": null}", &header).is_err());
+        assert!(header_from_json("{\"dCouchLat\": \"wide\"}", &header).is_err());
+
+        let json = header_to_json(&header, &[]).unwrap();
+        let parsed = header_from_json(&json, &hnd_header_t::new()).unwrap();
+        for name in HEADER_FIELDS {
+            assert_eq!(parsed.field(name).unwrap(), header.field(name).unwrap());
+        }
+        assert!(header_from_json("{\"SizeX\": \"wide\"}", &header).is_err());
+        assert!(header_from_json("{\"NoSuchField\": 1}", &header).is_err());
+        assert_eq!(csv_escape("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
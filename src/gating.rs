@@ -0,0 +1,303 @@
+// Respiratory sorting of projections for 4D-CBCT.
+//
+// The breathing signal is one of `dGating4DInfoX/Y/Z` (by default the one
+// that varies most), sampled at `dGating4DInfoTime`, else `dGatingTimeTag`,
+// else the frame order. Phase runs from 0 at one end-inhale peak to 1 at the
+// next; amplitude bins hold equal numbers of frames.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::modal::{hnd_header_t, is_set};
+
+/// Gating fields that are unset, or left as uninitialized memory by the
+/// acquisition (tiny subnormal numbers), count as missing.
+fn gating_value(v: f64) -> Option<f64> {
+    if !is_set(v) || (v != 0.0 && v.abs() < 1e-30) {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinMode {
+    Phase,
+    Amplitude,
+}
+
+impl FromStr for BinMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<BinMode, String> {
+        match s {
+            "phase" => Ok(BinMode::Phase),
+            "amplitude" => Ok(BinMode::Amplitude),
+            _ => Err(format!("unknown binning {:?}, expected phase or amplitude", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GatingAxis {
+    X,
+    Y,
+    Z,
+    /// The axis with the largest variance.
+    Auto,
+}
+
+impl FromStr for GatingAxis {
+    type Err = String;
+    fn from_str(s: &str) -> Result<GatingAxis, String> {
+        match s {
+            "x" => Ok(GatingAxis::X),
+            "y" => Ok(GatingAxis::Y),
+            "z" => Ok(GatingAxis::Z),
+            "auto" => Ok(GatingAxis::Auto),
+            _ => Err(format!("unknown axis {:?}, expected x, y, z or auto", s)),
+        }
+    }
+}
+
+fn variance(values: &[Option<f64>]) -> f64 {
+    let v: Vec<f64> = values.iter().filter_map(|v| *v).collect();
+    if v.len() < 2 {
+        return 0.0;
+    }
+    let mean = v.iter().sum::<f64>() / v.len() as f64;
+    v.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / v.len() as f64
+}
+
+/// Breathing amplitude of every frame, `None` where it is missing.
+pub fn gating_signal(headers: &[hnd_header_t], axis: GatingAxis) -> Vec<Option<f64>> {
+    let column = |f: fn(&hnd_header_t) -> f64| headers.iter().map(|h| gating_value(f(h))).collect::<Vec<_>>();
+    let x = column(|h| h.dGating4DInfoX);
+    let y = column(|h| h.dGating4DInfoY);
+    let z = column(|h| h.dGating4DInfoZ);
+    match axis {
+        GatingAxis::X => x,
+        GatingAxis::Y => y,
+        GatingAxis::Z => z,
+        GatingAxis::Auto => {
+            let mut best = vec![(variance(&x), x), (variance(&y), y), (variance(&z), z)];
+            best.sort_by(|a, b| b.0.total_cmp(&a.0));
+            best.remove(0).1
+        }
+    }
+}
+
+/// Sample time of every frame: `dGating4DInfoTime` when every frame has a
+/// distinct one, else `dGatingTimeTag` when usable, else the frame number.
+pub fn gating_times(headers: &[hnd_header_t]) -> Vec<f64> {
+    let distinct = |t: &[Option<f64>]| {
+        let mut t: Vec<f64> = match t.iter().cloned().collect::<Option<Vec<f64>>>() {
+            Some(t) => t,
+            None => return false,
+        };
+        t.sort_by(f64::total_cmp);
+        t.windows(2).all(|w| w[0] < w[1])
+    };
+    let info: Vec<Option<f64>> = headers.iter().map(|h| gating_value(h.dGating4DInfoTime)).collect();
+    if distinct(&info) {
+        return info.into_iter().map(|t| t.unwrap()).collect();
+    }
+    let tags: Vec<Option<f64>> = headers.iter().map(|h| gating_value(h.dGatingTimeTag)).collect();
+    if distinct(&tags) {
+        return tags.into_iter().map(|t| t.unwrap()).collect();
+    }
+    (0..headers.len()).map(|i| i as f64).collect()
+}
+
+/// Respiratory phase in [0, 1) of samples `(time, amplitude)`, 0 at the
+/// end-inhale peaks. Peaks are the maxima of the stretches above the mean
+/// amplitude. Samples before the first or after the last peak get their
+/// phase from the neighbouring cycle, as long as they lie within one cycle
+/// of it.
+pub fn respiratory_phase(times: &[f64], amplitude: &[Option<f64>]) -> Vec<Option<f64>> {
+    let mut order: Vec<usize> = (0..times.len()).filter(|i| amplitude[*i].is_some()).collect();
+    order.sort_by(|a, b| times[*a].total_cmp(&times[*b]));
+    let a: Vec<f64> = order.iter().map(|i| amplitude[*i].unwrap()).collect();
+    let mut phase = vec![None; times.len()];
+    if a.len() < 3 {
+        return phase;
+    }
+    // three point moving average against noise
+    let smooth: Vec<f64> = (0..a.len())
+        .map(|k| {
+            let (lo, hi) = (k.saturating_sub(1), (k + 1).min(a.len() - 1));
+            a[lo..=hi].iter().sum::<f64>() / (hi - lo + 1) as f64
+        })
+        .collect();
+    let mean = smooth.iter().sum::<f64>() / smooth.len() as f64;
+    let mut peaks: Vec<f64> = Vec::new();
+    let mut k = 0;
+    while k < smooth.len() {
+        if smooth[k] > mean {
+            let start = k;
+            while k < smooth.len() && smooth[k] > mean {
+                k += 1;
+            }
+            let top = (start..k).max_by(|i, j| smooth[*i].total_cmp(&smooth[*j])).unwrap();
+            peaks.push(times[order[top]]);
+        } else {
+            k += 1;
+        }
+    }
+    if peaks.len() < 2 {
+        return phase;
+    }
+    let n = peaks.len();
+    for i in order {
+        let t = times[i];
+        let p = match peaks.iter().position(|p| *p > t) {
+            Some(0) => {
+                let period = peaks[1] - peaks[0];
+                (t - peaks[0]) / period + 1.0
+            }
+            Some(k) => (t - peaks[k - 1]) / (peaks[k] - peaks[k - 1]),
+            None => (t - peaks[n - 1]) / (peaks[n - 1] - peaks[n - 2]),
+        };
+        if (0.0..1.0).contains(&p) {
+            phase[i] = Some(p);
+        }
+    }
+    phase
+}
+
+/// The frame indices of every bin in frame order and the phase or amplitude
+/// of every frame.
+pub type FrameBins = (Vec<Vec<usize>>, Vec<Option<f64>>);
+
+/// Sort the frames of a scan into `n` respiratory bins. Frames without
+/// gating data or outside the breathing cycles are in no bin.
+pub fn bin_frames(headers: &[hnd_header_t], mode: BinMode, n: usize, axis: GatingAxis) -> Result<FrameBins, String> {
+    let amplitude = gating_signal(headers, axis);
+    if variance(&amplitude) == 0.0 {
+        return Err("the scan has no respiratory signal in the gating fields".to_string());
+    }
+    let values = match mode {
+        BinMode::Phase => respiratory_phase(&gating_times(headers), &amplitude),
+        BinMode::Amplitude => amplitude,
+    };
+    Ok((bin_values(&values, mode, n)?, values))
+}
+
+/// Bin frames by their phase in [0, 1) or by amplitude, into bins of equal
+/// phase width or equal frame count. Frames without a value are in no bin.
+pub fn bin_values(values: &[Option<f64>], mode: BinMode, n: usize) -> Result<Vec<Vec<usize>>, String> {
+    if n == 0 {
+        return Err("the number of bins must be positive".to_string());
+    }
+    let mut bins = vec![Vec::new(); n];
+    match mode {
+        BinMode::Phase => {
+            for (i, p) in values.iter().enumerate() {
+                if let Some(p) = p {
+                    bins[((p.rem_euclid(1.0) * n as f64) as usize).min(n - 1)].push(i);
+                }
+            }
+        }
+        BinMode::Amplitude => {
+            let mut order: Vec<usize> = (0..values.len()).filter(|i| values[*i].is_some()).collect();
+            order.sort_by(|a, b| values[*a].unwrap().total_cmp(&values[*b].unwrap()));
+            let count = order.len();
+            for (rank, i) in order.into_iter().enumerate() {
+                bins[rank * n / count].push(i);
+            }
+            for bin in &mut bins {
+                bin.sort();
+            }
+        }
+    }
+    if bins.iter().all(|b| b.is_empty()) {
+        return Err("no frame falls into a complete breathing cycle".to_string());
+    }
+    Ok(bins)
+}
+
+/// Write one projection list per bin into `dir`, `<mode>_<bin>.txt` with a
+/// line `file angle value` per frame.
+pub fn write_bin_lists(
+    dir: &Path,
+    scan: &[(PathBuf, hnd_header_t)],
+    bins: &[Vec<usize>],
+    values: &[Option<f64>],
+    mode: BinMode,
+) -> Result<Vec<PathBuf>, io::Error> {
+    fs::create_dir_all(dir)?;
+    let name = match mode {
+        BinMode::Phase => "phase",
+        BinMode::Amplitude => "amplitude",
+    };
+    let mut written = Vec::new();
+    for (b, frames) in bins.iter().enumerate() {
+        let mut text = format!("# {} bin {} of {}: file angle {}\n", name, b + 1, bins.len(), name);
+        for i in frames {
+            let (path, header) = &scan[*i];
+            text.push_str(&format!(
+                "{} {} {}\n",
+                path.display(),
+                header.dCTProjectionAngle,
+                values[*i].unwrap_or(f64::NAN)
+            ));
+        }
+        let path = dir.join(format!("{}_{:02}.txt", name, b));
+        fs::write(&path, text)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 s breathing cycle sampled every 0.1 s, peaks at 1, 5, 9, ... s
+    fn breathing_scan(n: usize) -> Vec<hnd_header_t> {
+        (0..n)
+            .map(|i| {
+                let mut h = hnd_header_t::new();
+                let t = i as f64 * 0.1;
+                h.dGatingTimeTag = 1.5e9 + t;
+                h.dGating4DInfoX = 1e-307;
+                h.dGating4DInfoZ = 25.0;
+                h.dGating4DInfoY = 10.0 * ((t - 1.0) * std::f64::consts::PI / 2.0).cos();
+                h.dCTProjectionAngle = i as f64;
+                h
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_phase_bins() {
+        let headers = breathing_scan(200);
+        let (bins, phase) = bin_frames(&headers, BinMode::Phase, 4, GatingAxis::Auto).unwrap();
+        assert!((phase[30].unwrap() - 0.5).abs() < 0.03);
+        assert!(phase[10].unwrap() < 0.03 || phase[10].unwrap() > 0.97);
+        // every bin holds a quarter of the cycles
+        for bin in &bins {
+            assert!((bin.len() as i64 - 50).abs() <= 3, "{}", bin.len());
+        }
+        assert!(bins[2].contains(&30));
+    }
+
+    #[test]
+    fn test_amplitude_bins() {
+        let headers = breathing_scan(200);
+        let (bins, _) = bin_frames(&headers, BinMode::Amplitude, 5, GatingAxis::Y).unwrap();
+        assert!(bins.iter().all(|b| b.len() == 40));
+        assert!(bins[4].contains(&10) && bins[0].contains(&30));
+    }
+
+    #[test]
+    fn test_missing_gating() {
+        let mut headers = breathing_scan(20);
+        for h in &mut headers {
+            h.dGating4DInfoY = 2e-307;
+        }
+        assert!(bin_frames(&headers, BinMode::Phase, 10, GatingAxis::Auto).is_err());
+        assert_eq!(gating_signal(&headers, GatingAxis::X), vec![None; 20]);
+    }
+}
@@ -0,0 +1,408 @@
+// Acquisition geometry of each frame in IEC 61217 fixed coordinates (mm):
+// X to the right seen from the foot of the couch, Y towards the gantry, Z
+// up. At projection angle 0 the source is above the isocentre, and it moves
+// to +X at 90 degree.
+//
+// The phantom module uses a frame with z along the rotation axis and the
+// source at -y at angle 0; a point (x, y, z) there is (x, z, -y) here. The
+// ASTRA and TIGRE exports use that frame for the volume, z being its slice
+// axis in both toolboxes.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::flexmap::{FlexShift, Flexmap};
+use crate::format::{csv_escape, json_escape};
+use crate::modal::hnd_header_t;
+use crate::phantom::ConeBeam;
+
+/// Source and detector of one frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AcquisitionGeometry {
+    /// Projection angle in degree.
+    pub angle: f64,
+    pub source: [f64; 3],
+    /// Centre of the first pixel (column 0, row 0).
+    pub detector_origin: [f64; 3],
+    /// Step from one column to the next.
+    pub u: [f64; 3],
+    /// Step from one row to the next (downwards, towards -Y).
+    pub v: [f64; 3],
+    /// Columns and rows of the detector.
+    pub size: [usize; 2],
+}
+
+fn add(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f64; 3], s: f64) -> [f64; 3] {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn norm(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+// IEC 61217 to the volume frame of ASTRA and TIGRE.
+fn to_volume(a: [f64; 3]) -> [f64; 3] {
+    [a[0], -a[2], a[1]]
+}
+
+// Inverse of the matrix with columns a, b, c.
+fn inverse(a: [f64; 3], b: [f64; 3], c: [f64; 3]) -> Option<[[f64; 3]; 3]> {
+    let cross = |x: [f64; 3], y: [f64; 3]| {
+        [
+            x[1] * y[2] - x[2] * y[1],
+            x[2] * y[0] - x[0] * y[2],
+            x[0] * y[1] - x[1] * y[0],
+        ]
+    };
+    let (bc, ca, ab) = (cross(b, c), cross(c, a), cross(a, b));
+    let det = a[0] * bc[0] + a[1] * bc[1] + a[2] * bc[2];
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    Some([scale(bc, 1.0 / det), scale(ca, 1.0 / det), scale(ab, 1.0 / det)])
+}
+
+impl AcquisitionGeometry {
+    /// The geometry of `cone` at projection angle `angle` (degree).
+    pub fn from_cone_beam(cone: &ConeBeam, angle: f64) -> AcquisitionGeometry {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let source = [cone.sad * sin, 0.0, cone.sad * cos];
+        let toward = [-sin, 0.0, -cos];
+        let u_axis = [cos, 0.0, -sin];
+        let up = [0.0, 1.0, 0.0];
+        let centre = add(source, scale(toward, cone.sdd));
+        let first_u = -(cone.size[0] as f64 - 1.0) / 2.0 * cone.pitch[0] + cone.offset[0];
+        let first_v = (cone.size[1] as f64 - 1.0) / 2.0 * cone.pitch[1] + cone.offset[1];
+        AcquisitionGeometry {
+            angle,
+            source,
+            detector_origin: add(centre, add(scale(u_axis, first_u), scale(up, first_v))),
+            u: scale(u_axis, cone.pitch[0]),
+            v: scale(up, -cone.pitch[1]),
+            size: cone.size,
+        }
+    }
+
+    pub fn from_header(h: &hnd_header_t) -> Result<AcquisitionGeometry, io::Error> {
+        Ok(AcquisitionGeometry::from_cone_beam(&ConeBeam::from_header(h)?, h.dCTProjectionAngle))
+    }
+
+    /// Move detector and source by a flexmap displacement, u along the
+    /// columns and v up the rows.
+    pub fn shifted(&self, shift: &FlexShift) -> AcquisitionGeometry {
+        let u_axis = scale(self.u, 1.0 / norm(self.u));
+        let up = scale(self.v, -1.0 / norm(self.v));
+        let along = |s: [f64; 2]| add(scale(u_axis, s[0]), scale(up, s[1]));
+        AcquisitionGeometry {
+            source: add(self.source, along(shift.source)),
+            detector_origin: add(self.detector_origin, along(shift.detector)),
+            ..*self
+        }
+    }
+
+    /// The 3x4 matrix that maps a point (X, Y, Z, 1) to (c w, r w, w) with
+    /// column c and row r of its projection.
+    pub fn projection_matrix(&self) -> [[f64; 4]; 3] {
+        let inv = inverse(self.u, self.v, sub(self.detector_origin, self.source))
+            .expect("detector axes and source are not independent");
+        let mut p = [[0.0; 4]; 3];
+        for (row, r) in inv.iter().enumerate() {
+            p[row][..3].copy_from_slice(r);
+            p[row][3] = -(r[0] * self.source[0] + r[1] * self.source[1] + r[2] * self.source[2]);
+        }
+        p
+    }
+
+    /// Centre of the detector, between pixels for an even size.
+    pub fn detector_centre(&self) -> [f64; 3] {
+        let half = |n: usize| (n as f64 - 1.0) / 2.0;
+        add(self.detector_origin, add(scale(self.u, half(self.size[0])), scale(self.v, half(self.size[1]))))
+    }
+
+    /// The row of an ASTRA `cone_vec` geometry: source, detector centre,
+    /// column step and row step in the volume frame.
+    pub fn astra_cone_vec(&self) -> [f64; 12] {
+        let mut row = [0.0; 12];
+        for (k, a) in [self.source, self.detector_centre(), self.u, self.v].iter().enumerate() {
+            row[3 * k..3 * k + 3].copy_from_slice(&to_volume(*a));
+        }
+        row
+    }
+
+    /// Column and row of the projection of `point`.
+    pub fn project(&self, point: [f64; 3]) -> [f64; 2] {
+        let p = self.projection_matrix();
+        let h: Vec<f64> = p
+            .iter()
+            .map(|r| r[0] * point[0] + r[1] * point[1] + r[2] * point[2] + r[3])
+            .collect();
+        [h[0] / h[2], h[1] / h[2]]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeometryFormat {
+    /// One text file per frame with the 3x4 matrix.
+    Matrices,
+    Csv,
+    Json,
+    /// ASTRA `cone_vec` rows, one per frame.
+    AstraConeVec,
+    /// TIGRE cone-beam geometry as JSON.
+    Tigre,
+}
+
+impl FromStr for GeometryFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<GeometryFormat, String> {
+        match s {
+            "matrices" => Ok(GeometryFormat::Matrices),
+            "csv" => Ok(GeometryFormat::Csv),
+            "json" => Ok(GeometryFormat::Json),
+            "astra-cone-vec" => Ok(GeometryFormat::AstraConeVec),
+            "tigre" => Ok(GeometryFormat::Tigre),
+            _ => Err(format!("unknown geometry format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for AcquisitionGeometry {
+    /// The projection matrix, one row per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in &self.projection_matrix() {
+            writeln!(f, "{} {} {} {}", row[0] + 0.0, row[1] + 0.0, row[2] + 0.0, row[3] + 0.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Geometry of every frame of a scan in file order, moved by `flexmap` if
+/// given.
+pub fn scan_geometry(dir: &Path, flexmap: Option<&Flexmap>) -> Result<Vec<(PathBuf, AcquisitionGeometry)>, io::Error> {
+    let mut out = Vec::new();
+    for (path, header) in crate::read_scan_headers(dir)? {
+        let mut g = AcquisitionGeometry::from_header(&header)?;
+        if let Some(map) = flexmap {
+            g = g.shifted(&map.at(header.dCTProjectionAngle));
+        }
+        out.push((path, g));
+    }
+    Ok(out)
+}
+
+fn json_vec(v: &[f64]) -> String {
+    // adding zero turns -0 into 0
+    let items: Vec<String> = v.iter().map(|x| (x + 0.0).to_string()).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// CSV with one line per frame: index, file, angle and the matrix row by
+/// row.
+pub fn geometry_csv(frames: &[(PathBuf, AcquisitionGeometry)]) -> String {
+    let mut out = String::from("frame,file,angle");
+    for r in 0..3 {
+        for c in 0..4 {
+            out.push_str(&format!(",p{}{}", r, c));
+        }
+    }
+    out.push('\n');
+    for (i, (path, g)) in frames.iter().enumerate() {
+        out.push_str(&format!("{},{},{}", i, csv_escape(&path.display().to_string()), g.angle));
+        for row in &g.projection_matrix() {
+            for v in row {
+                out.push_str(&format!(",{}", v + 0.0));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// JSON array with one object per frame: file, angle, source, detector
+/// origin, u and v and the matrix.
+pub fn geometry_json(frames: &[(PathBuf, AcquisitionGeometry)]) -> String {
+    let items: Vec<String> = frames
+        .iter()
+        .enumerate()
+        .map(|(i, (path, g))| {
+            let rows: Vec<String> = g.projection_matrix().iter().map(|r| json_vec(r)).collect();
+            format!(
+                "  {{\"frame\": {}, \"file\": {}, \"angle\": {}, \"source\": {}, \"detector_origin\": {}, \"u\": {}, \"v\": {}, \"matrix\": [{}]}}",
+                i,
+                json_escape(&path.display().to_string()),
+                g.angle,
+                json_vec(&g.source),
+                json_vec(&g.detector_origin),
+                json_vec(&g.u),
+                json_vec(&g.v),
+                rows.join(", ")
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", items.join(",\n"))
+}
+
+/// ASTRA `cone_vec` vectors as text, one row of 12 values per frame, for
+/// `numpy.loadtxt`. Pixel (0, 0) is the first pixel of the HND image, so the
+/// projections go in as read, rows top to bottom.
+pub fn geometry_astra(frames: &[(PathBuf, AcquisitionGeometry)]) -> String {
+    let mut out = String::from("# srcX srcY srcZ dX dY dZ uX uY uZ vX vY vZ (mm)\n");
+    for (_, g) in frames {
+        let row: Vec<String> = g.astra_cone_vec().iter().map(|x| (x + 0.0).to_string()).collect();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+/// TIGRE cone-beam geometry as JSON, in TIGRE's names and order (`[V, U]`).
+/// TIGRE puts the source at `DSO (cos a, sin a, 0)`, so its angle `a` is
+/// the projection angle less 90 degree, in radian. `offDetector` holds one
+/// `[V, U]` pair per frame, U along the columns and V up the rows. TIGRE
+/// wants the projections with V growing with the row index, so flip them
+/// upside down. A flexmap displacement of the source moves the source
+/// distance and the offsets only approximately.
+pub fn geometry_tigre(frames: &[(PathBuf, AcquisitionGeometry)]) -> Result<String, io::Error> {
+    let first = match frames.first() {
+        Some((_, g)) => g,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no frames")),
+    };
+    let (mut dso, mut dsd) = (0.0, 0.0);
+    let (mut angles, mut offsets) = (Vec::new(), Vec::new());
+    for (_, g) in frames {
+        if g.size != first.size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the frames differ in detector size"));
+        }
+        let axis = scale(g.source, -1.0 / norm(g.source));
+        let centre = sub(g.detector_centre(), g.source);
+        let distance = dot(centre, axis);
+        let off = sub(centre, scale(axis, distance));
+        dso += norm(g.source);
+        dsd += distance;
+        angles.push((g.angle - 90.0).to_radians());
+        offsets.push(json_vec(&[-dot(off, g.v) / norm(g.v), dot(off, g.u) / norm(g.u)]));
+    }
+    let n = frames.len() as f64;
+    let pitch = [norm(first.v), norm(first.u)];
+    let size = [first.size[1] as f64, first.size[0] as f64];
+    Ok(format!(
+        "{{\n  \"mode\": \"cone\",\n  \"DSO\": {},\n  \"DSD\": {},\n  \"nDetector\": {},\n  \"dDetector\": {},\n  \"sDetector\": {},\n  \"offDetector\": [{}],\n  \"angles\": {}\n}}\n",
+        dso / n,
+        dsd / n,
+        json_vec(&size),
+        json_vec(&pitch),
+        json_vec(&[size[0] * pitch[0], size[1] * pitch[1]]),
+        offsets.join(", "),
+        json_vec(&angles)
+    ))
+}
+
+/// Write the geometry in `format` to `output`, a directory for
+/// `Matrices` (one `<file stem>.txt` per frame) and a file otherwise.
+pub fn write_geometry(output: &Path, frames: &[(PathBuf, AcquisitionGeometry)], format: GeometryFormat) -> Result<(), io::Error> {
+    match format {
+        GeometryFormat::Matrices => {
+            fs::create_dir_all(output)?;
+            for (path, g) in frames {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                fs::write(output.join(format!("{}.txt", stem)), g.to_string())?;
+            }
+            Ok(())
+        }
+        GeometryFormat::Csv => fs::write(output, geometry_csv(frames)),
+        GeometryFormat::Json => fs::write(output, geometry_json(frames)),
+        GeometryFormat::AstraConeVec => fs::write(output, geometry_astra(frames)),
+        GeometryFormat::Tigre => fs::write(output, geometry_tigre(frames)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection_matrix() {
+        let mut h = crate::default_scan_header();
+        h.SizeX = 64;
+        h.SizeY = 48;
+        h.dIDUPosLat = 1.2;
+        h.dIDUPosLng = -0.4;
+        let cone = ConeBeam::from_header(&h).unwrap();
+        for angle in &[0.0, 37.0, 90.0, 200.0] {
+            let g = AcquisitionGeometry::from_cone_beam(&cone, *angle);
+            for p in &[[0.0, 0.0, 0.0], [12.0, -30.0, 5.0], [-40.0, 8.0, -17.0]] {
+                // phantom frame to IEC 61217
+                let iec = [p[0], p[2], -p[1]];
+                let expected = cone.to_pixel(cone.project_point(*p, *angle));
+                let [c, r] = g.project(iec);
+                assert!((c - expected[0]).abs() < 1e-9 && (r - expected[1]).abs() < 1e-9);
+            }
+        }
+        // the source sits above the isocentre at angle 0
+        let g = AcquisitionGeometry::from_cone_beam(&cone, 0.0);
+        assert_eq!(g.source, [0.0, 0.0, 1000.0]);
+
+        let shifted = g.shifted(&FlexShift {
+            detector: [cone.pitch[0], 0.0],
+            source: [0.0, 0.0],
+        });
+        let [c, _] = shifted.project([0.0, 0.0, 0.0]);
+        let [c0, _] = g.project([0.0, 0.0, 0.0]);
+        assert!((c0 - c - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_exports() {
+        let g = AcquisitionGeometry::from_header(&crate::default_scan_header()).unwrap();
+        let frames = vec![(PathBuf::from("Proj_00000.hnd"), g), (PathBuf::from("Proj_00001.hnd"), g)];
+        let csv = geometry_csv(&frames);
+        assert_eq!(csv.lines().count(), 3);
+        assert_eq!(csv.lines().nth(1).unwrap().split(',').count(), 15);
+        let json = geometry_json(&frames);
+        assert!(json.starts_with("[\n  {\"frame\": 0, \"file\": \"Proj_00000.hnd\""));
+        assert_eq!(g.to_string().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_astra_tigre() {
+        let mut h = crate::default_scan_header();
+        h.SizeX = 64;
+        h.SizeY = 48;
+        h.dIDUPosLat = 1.2;
+        h.dCTProjectionAngle = 90.0;
+        let cone = ConeBeam::from_header(&h).unwrap();
+        let g = AcquisitionGeometry::from_header(&h).unwrap();
+        // at 90 degree the source is at +X and the columns run along +y of
+        // the volume frame, so the offset moves the detector centre there
+        let v = g.astra_cone_vec();
+        assert!((v[0] - cone.sad).abs() < 1e-9 && v[1].abs() < 1e-9);
+        assert!((v[3] - (cone.sad - cone.sdd)).abs() < 1e-9);
+        assert!((v[4] - 12.0).abs() < 1e-9 && v[5].abs() < 1e-9);
+        assert!((v[7] - cone.pitch[0]).abs() < 1e-9 && (v[11] + cone.pitch[1]).abs() < 1e-9);
+        let frames = vec![(PathBuf::from("Proj_00000.hnd"), g)];
+        assert_eq!(geometry_astra(&frames).lines().nth(1).unwrap().split(' ').count(), 12);
+
+        let tigre = geometry_tigre(&frames).unwrap();
+        assert!(tigre.contains(&format!("\"DSO\": {},", cone.sad)), "{}", tigre);
+        assert!(tigre.contains("\"nDetector\": [48, 64]"), "{}", tigre);
+        let off = tigre.split("\"offDetector\": [[").nth(1).unwrap().split("]]").next().unwrap();
+        let off: Vec<f64> = off.split(", ").map(|v| v.parse().unwrap()).collect();
+        assert!(off[0].abs() < 1e-9 && (off[1] - 12.0).abs() < 1e-9, "{}", tigre);
+        assert!(tigre.contains("\"angles\": [0]"), "{}", tigre);
+    }
+}
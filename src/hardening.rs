@@ -0,0 +1,201 @@
+// Beam-hardening correction of line integrals by water precorrection
+// (Kachelriess et al. 2006): a polynomial maps the measured line integral p
+// to the line integral of monochromatic water, q = c1 p + c2 p^2 + ...
+//
+// The polynomial depends on the spectrum, so a table holds one per tube
+// voltage (`dXRayKV`) and bowtie filter. The bowtie is not in the HND header
+// and has to be named by the user, `none` without one.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::modal::is_set;
+use crate::normalize::{line_integrals, OpenField};
+use crate::phantom::{ConeBeam, Shape, Solid, MU_WATER};
+use crate::RawImage;
+
+/// The correction polynomial for one tube voltage and bowtie.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHardening {
+    pub kv: f64,
+    pub bowtie: String,
+    /// Coefficients of p, p^2, ...
+    pub coefficients: Vec<f64>,
+}
+
+impl BeamHardening {
+    pub fn apply(&self, p: f64) -> f64 {
+        let mut power = 1.0;
+        let mut q = 0.0;
+        for c in &self.coefficients {
+            power *= p;
+            q += c * power;
+        }
+        q
+    }
+
+    /// Correct the line integrals of a projection in place.
+    pub fn correct(&self, img: &mut RawImage<f64>) {
+        for p in img.data.iter_mut() {
+            *p = self.apply(*p);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BeamHardeningTable {
+    pub entries: Vec<BeamHardening>,
+}
+
+impl BeamHardeningTable {
+    /// Parse the text form: one `kV bowtie c1 c2 ...` line per entry, `#`
+    /// comments.
+    pub fn parse(text: &str) -> Result<BeamHardeningTable, String> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() < 3 {
+                return Err(format!("line {}: expected kV, bowtie and coefficients", i + 1));
+            }
+            let number = |v: &str| v.parse::<f64>().map_err(|_| format!("line {}: {:?} is not a number", i + 1, v));
+            entries.push(BeamHardening {
+                kv: number(words[0])?,
+                bowtie: words[1].to_string(),
+                coefficients: words[2..].iter().map(|v| number(v)).collect::<Result<Vec<f64>, String>>()?,
+            });
+        }
+        Ok(BeamHardeningTable { entries })
+    }
+
+    pub fn load(path: &Path) -> Result<BeamHardeningTable, io::Error> {
+        crate::scan::parse_file(path, BeamHardeningTable::parse)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# kV bowtie coefficients of p, p^2, ...\n");
+        for e in &self.entries {
+            let c: Vec<String> = e.coefficients.iter().map(|c| c.to_string()).collect();
+            out.push_str(&format!("{} {} {}\n", e.kv, e.bowtie, c.join(" ")));
+        }
+        out
+    }
+
+    /// The entry for the tube voltage `kv` (within 0.5 kV) and `bowtie`.
+    pub fn select(&self, kv: f64, bowtie: &str) -> Result<&BeamHardening, io::Error> {
+        if !is_set(kv) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "header has no tube voltage"));
+        }
+        self.entries
+            .iter()
+            .find(|e| (e.kv - kv).abs() <= 0.5 && e.bowtie.eq_ignore_ascii_case(bowtie))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no beam-hardening correction for {} kV and bowtie {}", kv, bowtie),
+                )
+            })
+    }
+
+    /// Add `entry`, replacing the one of the same kV and bowtie.
+    pub fn insert(&mut self, entry: BeamHardening) {
+        self.entries
+            .retain(|e| !((e.kv - entry.kv).abs() <= 0.5 && e.bowtie.eq_ignore_ascii_case(&entry.bowtie)));
+        self.entries.push(entry);
+        self.entries.sort_by(|a, b| a.kv.total_cmp(&b.kv).then(a.bowtie.cmp(&b.bowtie)));
+    }
+}
+
+/// Fit the coefficients of p, ..., p^order to pairs of measured and ideal
+/// line integrals.
+pub fn fit_water_precorrection(samples: &[(f64, f64)], order: usize) -> Result<Vec<f64>, String> {
+    if order == 0 || samples.len() < order {
+        return Err(format!("{} samples are too few for order {}", samples.len(), order));
+    }
+    let rows: Vec<Vec<f64>> = samples
+        .iter()
+        .map(|(p, _)| (1..=order as i32).map(|k| p.powi(k)).collect())
+        .collect();
+    let rhs: Vec<f64> = samples.iter().map(|(_, q)| *q).collect();
+    crate::fit::least_squares(&rows, &rhs).ok_or_else(|| "the fit is singular".to_string())
+}
+
+/// Pairs of measured and ideal line integrals from a scan of a water
+/// cylinder of `radius` mm centred on the rotation axis and longer than the
+/// field of view. The ideal values use `MU_WATER`. Only every `stride`-th
+/// pixel in both directions is taken.
+pub fn water_samples(dir: &Path, radius: f64, open_field: &OpenField, stride: usize) -> Result<Vec<(f64, f64)>, io::Error> {
+    let cylinder = [Solid {
+        shape: Shape::Cylinder,
+        center: [0.0, 0.0, 0.0],
+        axes: [radius, radius, 10.0 * radius],
+        phi: 0.0,
+        mu: MU_WATER,
+    }];
+    let stride = stride.max(1);
+    let mut samples = Vec::new();
+    for path in crate::list_scan_files(dir)? {
+        let mut fin = File::open(&path)?;
+        let header = crate::read_header(&mut fin)?;
+        let img: RawImage<f64> = crate::read_image(&mut fin)?.convert();
+        let measured = line_integrals(&img, &open_field.frame_i0(&header, img.width, img.height)?);
+        let ideal = ConeBeam::from_header(&header)?.project(&cylinder, header.dCTProjectionAngle);
+        for y in (0..img.height).step_by(stride) {
+            for x in (0..img.width).step_by(stride) {
+                let i = y * img.width + x;
+                // leave out the edge, where the geometry is least certain
+                if ideal[i] > 0.1 * MU_WATER * radius {
+                    samples.push((measured.data[i], ideal[i]));
+                }
+            }
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let table = BeamHardeningTable::parse("# calibration\n100 full 1.0 0.01\n125 half 1.0 0.02 0.001\n").unwrap();
+        assert_eq!(table.select(125.2, "Half").unwrap().coefficients, vec![1.0, 0.02, 0.001]);
+        assert!(table.select(110.0, "full").is_err());
+        assert!(table.select(100.0, "none").is_err());
+        assert_eq!(BeamHardeningTable::parse(&table.to_text()).unwrap(), table);
+
+        let mut table = table;
+        table.insert(BeamHardening {
+            kv: 100.0,
+            bowtie: "full".to_string(),
+            coefficients: vec![1.1],
+        });
+        assert_eq!(table.entries.len(), 2);
+        assert_eq!(table.select(100.0, "full").unwrap().apply(2.0), 2.2);
+    }
+
+    #[test]
+    fn test_fit_water_precorrection() {
+        // hardening flattens the measured line integral of thick water
+        let samples: Vec<(f64, f64)> = (1..40)
+            .map(|i| {
+                let q = i as f64 * 0.1;
+                (q - 0.04 * q * q, q)
+            })
+            .collect();
+        let c = fit_water_precorrection(&samples, 3).unwrap();
+        let bh = BeamHardening {
+            kv: 125.0,
+            bowtie: "none".to_string(),
+            coefficients: c,
+        };
+        for (p, q) in &samples {
+            assert!((bh.apply(*p) - q).abs() < 0.01);
+        }
+    }
+}
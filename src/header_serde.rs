@@ -0,0 +1,242 @@
+// Serde support for the HND header (feature `serde`).
+//
+// The header serializes as a map of its fields under their names in
+// `HEADER_FIELDS`, in file order, followed by `Reserved`: the unused rest of
+// the 1024 bytes as hex, trailing zero bytes left out. Floats that JSON
+// cannot hold are strings, `inf`, `-inf`, `NaN`, or `NaN:0x<bits>` for a NaN
+// with an unusual bit pattern, so a header read back writes the same 1024
+// bytes. This is the JSON of `format::header_to_json` as well. Strings hold
+// one char per header byte (Latin-1), so non-ASCII bytes and leftovers after
+// the terminating NUL survive too. On the way in they go through `set_field`
+// and must fit their field.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::format::{float_name, parse_float_name, parse_reserved, reserved_hex, RESERVED_SIZE};
+use crate::modal::{hnd_header_t, HeaderValue, HEADER_FIELDS};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl FromStr for HeaderFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<HeaderFormat, String> {
+        match s {
+            "json" => Ok(HeaderFormat::Json),
+            "toml" => Ok(HeaderFormat::Toml),
+            "yaml" | "yml" => Ok(HeaderFormat::Yaml),
+            _ => Err(format!("unknown header format {:?}, expected json, toml or yaml", s)),
+        }
+    }
+}
+
+impl HeaderFormat {
+    /// The format a file name extension stands for.
+    pub fn from_path(path: &std::path::Path) -> Option<HeaderFormat> {
+        path.extension()?.to_str()?.to_ascii_lowercase().parse().ok()
+    }
+}
+
+struct Float(f64);
+
+impl Serialize for Float {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match float_name(self.0) {
+            Some(name) => s.serialize_str(&name),
+            None => s.serialize_f64(self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Float {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Float, D::Error> {
+        struct FloatVisitor;
+        impl<'de> Visitor<'de> for FloatVisitor {
+            type Value = Float;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number, inf, -inf or NaN")
+            }
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Float, E> {
+                Ok(Float(v))
+            }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Float, E> {
+                Ok(Float(v as f64))
+            }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Float, E> {
+                Ok(Float(v as f64))
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Float, E> {
+                parse_float_name(v)
+                    .map(Float)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+        d.deserialize_any(FloatVisitor)
+    }
+}
+
+impl Serialize for hnd_header_t {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut map = s.serialize_map(Some(HEADER_FIELDS.len() + 1))?;
+        for name in HEADER_FIELDS {
+            match self.field(name).map_err(serde::ser::Error::custom)? {
+                HeaderValue::Str(v) => map.serialize_entry(name, &v)?,
+                HeaderValue::U32(v) => map.serialize_entry(name, &v)?,
+                HeaderValue::F64(v) => map.serialize_entry(name, &Float(v))?,
+            }
+        }
+        map.serialize_entry("Reserved", &reserved_hex(&self.Reserved))?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for hnd_header_t {
+    /// Every field is required but `Reserved`, which defaults to zeros.
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<hnd_header_t, D::Error> {
+        struct HeaderVisitor;
+        impl<'de> Visitor<'de> for HeaderVisitor {
+            type Value = hnd_header_t;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of HND header fields")
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<hnd_header_t, A::Error> {
+                let mut h = hnd_header_t::new();
+                h.Reserved = vec![0; RESERVED_SIZE];
+                let mut seen = vec![false; HEADER_FIELDS.len()];
+                let mut reserved = false;
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == "Reserved" {
+                        if reserved {
+                            return Err(de::Error::duplicate_field("Reserved"));
+                        }
+                        reserved = true;
+                        h.Reserved = parse_reserved(&map.next_value::<String>()?).map_err(de::Error::custom)?;
+                        continue;
+                    }
+                    let index = match HEADER_FIELDS.iter().position(|n| *n == key) {
+                        Some(i) => i,
+                        None => return Err(de::Error::unknown_field(&key, HEADER_FIELDS)),
+                    };
+                    if seen[index] {
+                        return Err(de::Error::duplicate_field(HEADER_FIELDS[index]));
+                    }
+                    seen[index] = true;
+                    let value = match h.field(&key).map_err(de::Error::custom)? {
+                        HeaderValue::Str(_) => HeaderValue::Str(map.next_value()?),
+                        HeaderValue::U32(_) => HeaderValue::U32(map.next_value()?),
+                        HeaderValue::F64(_) => HeaderValue::F64(map.next_value::<Float>()?.0),
+                    };
+                    h.set_field(&key, value).map_err(de::Error::custom)?;
+                }
+                if let Some(i) = seen.iter().position(|s| !s) {
+                    return Err(de::Error::missing_field(HEADER_FIELDS[i]));
+                }
+                Ok(h)
+            }
+        }
+        d.deserialize_map(HeaderVisitor)
+    }
+}
+
+/// The header as text in `format`.
+pub fn export_header(h: &hnd_header_t, format: HeaderFormat) -> Result<String, String> {
+    match format {
+        HeaderFormat::Json => serde_json::to_string_pretty(h).map(|s| s + "\n").map_err(|e| e.to_string()),
+        HeaderFormat::Toml => toml::to_string(h).map_err(|e| e.to_string()),
+        HeaderFormat::Yaml => serde_yaml::to_string(h).map_err(|e| e.to_string()),
+    }
+}
+
+/// Parse a header written by `export_header`.
+pub fn import_header(text: &str, format: HeaderFormat) -> Result<hnd_header_t, String> {
+    match format {
+        HeaderFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        HeaderFormat::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+        HeaderFormat::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_round_trip() {
+        let mut raw = vec![0; 1024];
+        std::fs::File::open("test/test_data_1.hnd").unwrap().read_exact(&mut raw).unwrap();
+        let mut header = hnd_header_t::from_raw(raw.clone());
+        assert_eq!(header.to_raw(), raw);
+        for format in &[HeaderFormat::Json, HeaderFormat::Toml, HeaderFormat::Yaml] {
+            let text = export_header(&header, *format).unwrap();
+            assert_eq!(import_header(&text, *format).unwrap().to_raw(), raw, "{:?}", format);
+        }
+
+        // values JSON has no numbers for, and leftovers in the reserved bytes
+        header.dGating4DInfoX = 1e-307;
+        header.dGating4DInfoY = f64::from_bits(0xfff4_0000_0000_0001);
+        header.dGating4DInfoZ = f64::NAN;
+        header.dCouchLat = f64::NEG_INFINITY;
+        header.Reserved = vec![0; RESERVED_SIZE];
+        header.Reserved[3] = 0xab;
+        let raw = header.to_raw();
+        for format in &[HeaderFormat::Json, HeaderFormat::Toml, HeaderFormat::Yaml] {
+            let text = export_header(&header, *format).unwrap();
+            assert_eq!(import_header(&text, *format).unwrap().to_raw(), raw, "{:?}", format);
+        }
+        let json = export_header(&header, HeaderFormat::Json).unwrap();
+        assert!(json.contains("\"Reserved\": \"000000ab\""), "{}", json);
+        assert!(json.contains("\"dGating4DInfoY\": \"NaN:0xfff4000000000001\""));
+        let plain = crate::format::header_to_json(&header, &[]).unwrap();
+        assert_eq!(import_header(&plain, HeaderFormat::Json).unwrap().to_raw(), raw);
+        let parsed = crate::format::header_from_json(&json, &hnd_header_t::new()).unwrap();
+        assert_eq!(parsed.to_raw(), raw);
+
+        assert!(import_header("{\"SizeX\": 1}", HeaderFormat::Json).unwrap_err().contains("missing field"));
+        let long = json.replace("\"sImageType\": \"", "\"sImageType\": \"TOO LONG");
+        assert!(import_header(&long, HeaderFormat::Json).is_err());
+        assert!(import_header(&json.replace("\"SizeX\"", "\"Width\""), HeaderFormat::Json).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_string_bytes() {
+        let mut raw = vec![0; 1024];
+        std::fs::File::open("test/test_data_1.hnd").unwrap().read_exact(&mut raw).unwrap();
+        // sPatientID at byte 60: a Latin-1 letter, and garbage after the NUL
+        raw[60..76].copy_from_slice(b"M\xfcller\0\x01\xff\x7f\0\0\0\0\0\0");
+        let header = hnd_header_t::from_raw(raw.clone());
+        assert_eq!(header.to_raw(), raw);
+        for format in &[HeaderFormat::Json, HeaderFormat::Toml, HeaderFormat::Yaml] {
+            let text = export_header(&header, *format).unwrap();
+            assert_eq!(import_header(&text, *format).unwrap().to_raw(), raw, "{:?}", format);
+        }
+        let json = crate::format::header_to_json(&header, &[]).unwrap();
+        let parsed = crate::format::header_from_json(&json, &hnd_header_t::new()).unwrap();
+        assert_eq!(parsed.to_raw(), raw);
+    }
+
+    #[test]
+    fn test_import_in_place() {
+        // what `hnd header import` does with the output the same as the target
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.hnd");
+        std::fs::copy("test/test_data_1.hnd", &path).unwrap();
+        let before = std::fs::read(&path).unwrap();
+        let mut header = crate::read_header(&mut std::fs::File::open(&path).unwrap()).unwrap();
+        header.sPatientID = "ANON".to_string();
+        let text = export_header(&header, HeaderFormat::Yaml).unwrap();
+        crate::copy_with_header(&path, &path, &import_header(&text, HeaderFormat::Yaml).unwrap()).unwrap();
+        let after = std::fs::read(&path).unwrap();
+        assert_eq!(after.len(), before.len());
+        assert_eq!(after[1024..], before[1024..]);
+        assert_eq!(after[..1024], header.to_raw()[..]);
+    }
+}
@@ -0,0 +1,307 @@
+// Detector lag correction by recursive deconvolution of a multi-exponential
+// linear time-invariant model (Hsieh et al., Proc. SPIE 3977, 2000).
+//
+// A frame x_k leaves the signal b_n exp(-a_n t) of every term n in the
+// following frames, t counted in frame intervals. A term with an infinite
+// rate is the direct, lag free part of the response.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::modal::ImageConvError;
+use crate::{HndImage, RawImage};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LagTerm {
+    /// Decay rate per frame interval, `inf` for the direct term.
+    pub rate: f64,
+    pub amplitude: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LagModel {
+    pub terms: Vec<LagTerm>,
+}
+
+impl LagModel {
+    /// Parse the text form: one `rate amplitude` pair per line, `#` comments.
+    pub fn parse(text: &str) -> Result<LagModel, String> {
+        let mut terms = Vec::new();
+        for row in crate::parse_frame_table(text)? {
+            if row.len() != 2 {
+                return Err(format!("expected rate and amplitude, got {} values", row.len()));
+            }
+            if row[0].is_nan() || row[0] <= 0.0 {
+                return Err(format!("rate {} is not positive", row[0]));
+            }
+            if !row[1].is_finite() {
+                return Err(format!("amplitude {} is not a number", row[1]));
+            }
+            terms.push(LagTerm {
+                rate: row[0],
+                amplitude: row[1],
+            });
+        }
+        if terms.iter().map(|t| t.amplitude).sum::<f64>() <= 0.0 {
+            return Err("amplitudes do not add up to a positive response".to_string());
+        }
+        Ok(LagModel { terms })
+    }
+
+    pub fn load(path: &Path) -> Result<LagModel, io::Error> {
+        crate::scan::parse_file(path, LagModel::parse)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# rate per frame, amplitude\n");
+        for t in &self.terms {
+            out.push_str(&format!("{} {}\n", t.rate, t.amplitude));
+        }
+        out
+    }
+
+    /// Total response to a single frame, 1 for a model that preserves the
+    /// signal.
+    pub fn gain(&self) -> f64 {
+        self.terms
+            .iter()
+            .map(|t| t.amplitude / (1.0 - (-t.rate).exp()))
+            .sum()
+    }
+}
+
+/// Corrects the frames of a scan one at a time, in acquisition order.
+pub struct LagCorrector {
+    model: LagModel,
+    /// Per term the lagged signal of the previous frames at the last frame.
+    state: Vec<Vec<f64>>,
+    last_time: Option<f64>,
+    interval: f64,
+}
+
+impl LagCorrector {
+    /// `interval` is the nominal frame interval in seconds, used to scale the
+    /// decay by the real time between frames. With 0 every frame counts as
+    /// one interval.
+    pub fn new(model: LagModel, interval: f64) -> LagCorrector {
+        LagCorrector {
+            state: vec![Vec::new(); model.terms.len()],
+            model,
+            last_time: None,
+            interval,
+        }
+    }
+
+    /// The lag free signal of the next frame, taken at `time` seconds.
+    pub fn correct(&mut self, img: &RawImage<u32>, time: Option<f64>) -> Result<RawImage<f64>, ImageConvError> {
+        let steps = match (self.last_time, time) {
+            (Some(last), Some(now)) if self.interval > 0.0 && now > last => (now - last) / self.interval,
+            _ => 1.0,
+        };
+        self.last_time = time;
+        let n = img.data().len();
+        for s in &mut self.state {
+            if s.len() != n {
+                *s = vec![0.0; n];
+            }
+        }
+        let decay: Vec<f64> = self.model.terms.iter().map(|t| (-t.rate * steps).exp()).collect();
+        let direct: f64 = self.model.terms.iter().map(|t| t.amplitude).sum();
+
+        let mut out = Vec::with_capacity(n);
+        for (i, y) in img.data().iter().enumerate() {
+            let mut lagged = 0.0;
+            for (k, t) in self.model.terms.iter().enumerate() {
+                self.state[k][i] *= decay[k];
+                lagged += t.amplitude * self.state[k][i];
+            }
+            let x = (*y as f64 - lagged) / direct;
+            for s in &mut self.state {
+                s[i] += x;
+            }
+            out.push(x);
+        }
+        RawImage::new(img.width, img.height, out)
+    }
+}
+
+// Median spacing of the time tags in acquisition order, 0 without usable
+// tags.
+fn nominal_interval(times: &[f64]) -> f64 {
+    let mut steps: Vec<f64> = times.windows(2).map(|w| w[1] - w[0]).filter(|d| *d > 0.0).collect();
+    if steps.len() + 1 != times.len() || steps.is_empty() {
+        return 0.0;
+    }
+    steps.sort_by(f64::total_cmp);
+    steps[steps.len() / 2]
+}
+
+/// Lag correct the scan in `input` and write it to `output` with the same
+/// file names. Frames are processed in the order given by `time_order`.
+pub fn lag_correct_scan(input: &Path, output: &Path, model: &LagModel) -> Result<usize, io::Error> {
+    let scan = crate::read_scan_headers(input)?;
+    let headers: Vec<_> = scan.iter().map(|(_, h)| h.clone()).collect();
+    let order = crate::time_order(&headers);
+    let times: Vec<f64> = order.iter().map(|i| headers[*i].dGatingTimeTag).collect();
+    let interval = nominal_interval(&times);
+    std::fs::create_dir_all(output)?;
+
+    let mut corrector = LagCorrector::new(model.clone(), interval);
+    for i in order {
+        let (path, header) = &scan[i];
+        let img = crate::read_image(&mut File::open(path)?)?;
+        let time = if interval > 0.0 { Some(header.dGatingTimeTag) } else { None };
+        let corrected = corrector.correct(&img, time)?;
+        let hnd_image = HndImage::new(header.clone(), &corrected.convert())?;
+        crate::write_file(&mut File::create(output.join(path.file_name().unwrap()))?, &hnd_image)?;
+    }
+    Ok(scan.len())
+}
+
+/// Mean signal of every frame of a scan in acquisition order.
+pub fn frame_means(dir: &Path) -> Result<Vec<f64>, io::Error> {
+    let scan = crate::read_scan_headers(dir)?;
+    let headers: Vec<_> = scan.iter().map(|(_, h)| h.clone()).collect();
+    let mut means = Vec::new();
+    for i in crate::time_order(&headers) {
+        let img = crate::read_image(&mut File::open(&scan[i].0)?)?;
+        let n = img.data().len().max(1) as f64;
+        means.push(img.data().iter().map(|v| *v as f64).sum::<f64>() / n);
+    }
+    Ok(means)
+}
+
+/// Default decay rates for `fit_lag_model`, time constants of 1, 5 and 30
+/// frames.
+pub const DEFAULT_LAG_RATES: [f64; 3] = [1.0, 0.2, 0.033];
+
+/// Fit the amplitudes of the given decay rates to a step response: dark
+/// frames, frames under constant exposure long enough to reach a steady
+/// state, then dark frames again. `signal` is the mean of each frame in
+/// acquisition order. The fitted model has a gain of 1.
+pub fn fit_lag_model(signal: &[f64], rates: &[f64]) -> Result<LagModel, String> {
+    if signal.iter().any(|s| !s.is_finite()) {
+        return Err("the sequence has frames without a finite mean".to_string());
+    }
+    if rates.iter().any(|r| r.is_nan() || *r <= 0.0) {
+        return Err("decay rates must be positive".to_string());
+    }
+    let dark = signal.iter().cloned().fold(f64::INFINITY, f64::min);
+    let bright = signal.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if bright <= dark {
+        return Err("the sequence has no step".to_string());
+    }
+    let half = (dark + bright) / 2.0;
+    let rise = signal.iter().position(|s| *s > half).unwrap();
+    let fall = rise
+        + signal[rise..]
+            .iter()
+            .position(|s| *s < half)
+            .ok_or("the sequence has no falling edge")?;
+    if rise == 0 || signal.len() - fall < rates.len() || fall - rise < rates.len() {
+        return Err("the sequence needs dark frames before and after the exposure".to_string());
+    }
+    let mut plateau: Vec<f64> = signal[rise..fall].to_vec();
+    plateau.sort_by(f64::total_cmp);
+    let level = plateau[plateau.len() / 2] - dark;
+    let z = |k: usize| (signal[k] - dark) / level;
+
+    // With c_n = b_n / (1 - exp(-a_n)) the rising edge lacks
+    // sum c_n exp(-a_n (m + 1)) of the steady state m frames after the
+    // onset, and m frames after the beam is off sum c_n exp(-a_n m) is left.
+    let mut rows = Vec::new();
+    let mut rhs = Vec::new();
+    for m in 0..fall - rise {
+        rows.push(rates.iter().map(|a| (-a * (m + 1) as f64).exp()).collect());
+        rhs.push(1.0 - z(rise + m));
+    }
+    for m in 1..signal.len() - fall + 1 {
+        rows.push(rates.iter().map(|a| (-a * m as f64).exp()).collect());
+        rhs.push(z(fall - 1 + m));
+    }
+    let c = crate::fit::nonnegative_least_squares(&rows, &rhs).ok_or("the fit is singular")?;
+    let direct = 1.0 - c.iter().sum::<f64>();
+    if direct <= 0.0 {
+        return Err("the lag exceeds the signal".to_string());
+    }
+    let mut terms = vec![LagTerm {
+        rate: f64::INFINITY,
+        amplitude: direct,
+    }];
+    for (a, c) in rates.iter().zip(c) {
+        if c > 0.0 {
+            terms.push(LagTerm {
+                rate: *a,
+                amplitude: c * (1.0 - (-a).exp()),
+            });
+        }
+    }
+    Ok(LagModel { terms })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> LagModel {
+        LagModel::parse("# direct and two terms\ninf 0.9\n0.5 0.02\n0.05 0.002\n").unwrap()
+    }
+
+    fn decay(rate: f64, frames: usize) -> f64 {
+        if frames == 0 {
+            1.0
+        } else {
+            (-rate * frames as f64).exp()
+        }
+    }
+
+    // Apply the lag model to a sequence of frame values.
+    fn lagged(model: &LagModel, x: &[f64]) -> Vec<f64> {
+        (0..x.len())
+            .map(|k| {
+                model
+                    .terms
+                    .iter()
+                    .map(|t| (0..=k).map(|i| x[i] * t.amplitude * decay(t.rate, k - i)).sum::<f64>())
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lag_correction() {
+        let model = model();
+        let x: Vec<f64> = (0..50).map(|k| if (5..30).contains(&k) { 1000.0 } else { 0.0 }).collect();
+        let y = lagged(&model, &x);
+        let mut corrector = LagCorrector::new(model, 0.0);
+        for (k, y) in y.iter().enumerate() {
+            let img = RawImage::new(1, 1, vec![y.round() as u32]).unwrap();
+            let out = corrector.correct(&img, None).unwrap();
+            assert!((out.data()[0] - x[k]).abs() < 1.0, "frame {}: {}", k, out.data()[0]);
+        }
+    }
+
+    #[test]
+    fn test_fit_lag_model() {
+        let truth = LagModel {
+            terms: vec![
+                LagTerm { rate: f64::INFINITY, amplitude: 0.95 },
+                LagTerm { rate: 0.2, amplitude: 0.008 },
+            ],
+        };
+        let x: Vec<f64> = (0..400).map(|k| if (10..200).contains(&k) { 1.0 } else { 0.0 }).collect();
+        let gain = truth.gain();
+        let y: Vec<f64> = lagged(&truth, &x).iter().map(|v| 100.0 + 1000.0 * v / gain).collect();
+        let fitted = fit_lag_model(&y, &[1.0, 0.2, 0.033]).unwrap();
+        assert!((fitted.gain() - 1.0).abs() < 1e-6);
+        let term = fitted.terms.iter().find(|t| t.rate == 0.2).unwrap();
+        assert!((term.amplitude - 0.008 / gain).abs() < 1e-4, "{:?}", fitted);
+
+        let mut y = y;
+        y[300] = f64::NAN;
+        assert!(fit_lag_model(&y, &[1.0, 0.2]).is_err());
+        assert!(LagModel::parse("NaN 0.5\n").is_err());
+        assert!(LagModel::parse("inf 0.9\n0.2 NaN\n").is_err());
+    }
+}
@@ -1,17 +1,70 @@
 #![allow(non_snake_case)]
 #![allow(non_camel_case_types)]
+// the extern "C" functions take their pointers from C callers
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
-use std::convert::{From, Into, TryFrom, TryInto};
+use std::convert::TryInto;
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::mem;
+use std::path::Path;
 
 mod modal;
 mod control;
+mod format;
+mod anonymize;
+mod scan;
+mod diff;
+mod compare;
+mod check;
+mod deflate;
+mod preview;
+mod tiff;
+mod dicom;
+mod npy;
+mod nrrd;
+mod phantom;
+mod transform;
+mod fit;
+mod lag;
+mod scatter;
+mod normalize;
+mod hardening;
+mod gating;
+mod shroud;
+mod report;
+mod flexmap;
+mod geometry;
+#[cfg(feature = "serde")]
+mod header_serde;
 
 pub use modal::hnd_header_t;
+pub use modal::{HeaderFieldError, HeaderValue, HEADER_FIELDS, HEADER_FIELDS_SIZE};
+pub use anonymize::{anonymize_tree, shift_date, shift_time, PseudonymMap, IDENTIFYING_FIELDS};
+pub use scan::{apply_frame_row, frame_index, list_scan_files, parse_frame_table, read_scan_headers, split_raw_frames, time_order};
+pub use diff::{diff_headers, match_frames, FieldDiff, FrameMatch, Tolerance};
+pub use compare::{compare_images, difference_image, CompareStats};
+pub use check::{check_file, check_scan, pixels_out_of_range, stream_length, worst_severity, Issue, Severity};
+pub use preview::{make_preview, render, shrink, write_pgm, write_png, BitDepth, PreviewOptions, Window};
+pub use tiff::{read_tiff, write_tiff};
+pub use dicom::{generate_uid, read_dicom, write_rt_image, DicomIds};
+pub use npy::{npy_header, write_npy, write_scan_npz, NpzWriter};
+pub use nrrd::{angular_step, nrrd_header, write_nrrd, NrrdEncoding};
+pub use phantom::{default_scan_header, phantom, shepp_logan, simulate_scan, water_cylinder, ConeBeam, PhantomKind, Shape, SimulateOptions, Solid};
+pub use format::{header_from_json, csv_columns, csv_escape, header_to_csv, header_to_json, json_escape, OutputFormat};
 pub use modal::ImageConvError;
+pub use transform::{apply_transform, Pixel, Transform};
+pub use lag::{fit_lag_model, frame_means, lag_correct_scan, LagCorrector, LagModel, LagTerm, DEFAULT_LAG_RATES};
+pub use normalize::{line_integrals, normalize_frame, write_normalized_npy, write_normalized_npz, NormalizeOptions, OpenField};
+pub use hardening::{fit_water_precorrection, water_samples, BeamHardening, BeamHardeningTable};
+pub use gating::{bin_frames, bin_values, gating_signal, gating_times, respiratory_phase, write_bin_lists, BinMode, FrameBins, GatingAxis};
+pub use shroud::{amsterdam_shroud, breathing_trace, shroud_column, BreathingTrace, Shroud};
+pub use report::{Range, ReportOptions, ScanReport};
+pub use flexmap::{apply_flexmap_header, apply_flexmap_image, derive_flexmap, flexmap_correct_file, locate_marker, marker_shift, FlexShift, Flexmap};
+pub use geometry::{geometry_astra, geometry_csv, geometry_json, geometry_tigre, scan_geometry, write_geometry, AcquisitionGeometry, GeometryFormat};
+#[cfg(feature = "serde")]
+pub use header_serde::{export_header, import_header, HeaderFormat};
+pub use scatter::{detector_pitch, scatter_correct, scatter_correct_file, scatter_estimate, KernelTerm, ScatterModel, ScatterOptions};
 pub use modal::decode;
 pub use modal::{encode_u16, encode_u32};
 
@@ -22,6 +75,7 @@ pub struct HndImage {
     data: hnd_data_t,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct RawImage<T> {
     width: usize,
     height: usize,
@@ -60,6 +114,67 @@ impl Size2D for hnd_header_t {
     }
 }
 
+impl<T> RawImage<T> {
+    pub fn new(width: usize, height: usize, data: Vec<T>) -> Result<RawImage<T>, ImageConvError> {
+        if data.len() != width * height {
+            return Err(ImageConvError::SizeMismatch);
+        }
+        Ok(RawImage { width, height, data })
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<T> {
+        self.data
+    }
+}
+
+/// Pixels as little endian 16 or 32 bit words, for the image writers. 16
+/// bits fail if a pixel does not fit.
+pub(crate) fn pixel_bytes(img: &RawImage<u32>, bits: u16) -> Result<Vec<u8>, io::Error> {
+    let mut out = Vec::with_capacity(img.data().len() * bits as usize / 8);
+    match bits {
+        16 => {
+            for v in img.data() {
+                if *v > u16::MAX as u32 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("pixel value {} does not fit into 16 bits", v),
+                    ));
+                }
+                out.extend_from_slice(&(*v as u16).to_le_bytes());
+            }
+        }
+        32 => img.data().iter().for_each(|v| out.extend_from_slice(&v.to_le_bytes())),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "bits must be 16 or 32")),
+    }
+    Ok(out)
+}
+
+impl HndImage {
+    /// Encode `img` into an HND image. `SizeX`, `SizeY` and `FileLength`
+    /// (the uncompressed size, as Varian writes it) of the header are set to
+    /// match the image. Fails with `ImageConvError::TooSmall` for images
+    /// without columns or with fewer than two rows, which HND cannot hold.
+    pub fn new(header: hnd_header_t, img: &RawImage<u32>) -> Result<HndImage, ImageConvError> {
+        let data = encode_u32(&img.data, img.width, img.height)?;
+        Ok(HndImage::with_data(header, img.width, img.height, data))
+    }
+
+    fn with_data(mut header: hnd_header_t, width: usize, height: usize, data: hnd_data_t) -> HndImage {
+        header.SizeX = width as u32;
+        header.SizeY = height as u32;
+        header.FileLength = (1024 + width * height * 4) as u32;
+        HndImage { header, data }
+    }
+
+    pub fn header(&self) -> &hnd_header_t {
+        &self.header
+    }
+}
+
 impl TryInto<RawImage<u32>> for HndImage {
     type Error = ImageConvError;
     fn try_into(self) -> Result<RawImage<u32>, Self::Error> {
@@ -68,8 +183,8 @@ impl TryInto<RawImage<u32>> for HndImage {
         let data = decode(&self.data, width, height)?;
 
         Ok(RawImage {
-            width: width,
-            height: height,
+            width,
+            height,
             data,
         })
     }
@@ -79,14 +194,15 @@ impl TryInto<RawImage<u32>> for HndImage {
 impl TryInto<HndImage> for RawImage<u32> {
     type Error = ImageConvError;
     fn try_into(self) -> Result<HndImage, Self::Error> {
-        Err(ImageConvError::SomeErr)
+        HndImage::new(hnd_header_t::new(), &self)
     }
 }
 
 impl TryInto<HndImage> for RawImage<u16> {
     type Error = ImageConvError;
     fn try_into(self) -> Result<HndImage, Self::Error> {
-        Err(ImageConvError::SomeErr)
+        let data = encode_u16(&self.data, self.width, self.height)?;
+        Ok(HndImage::with_data(hnd_header_t::new(), self.width, self.height, data))
     }
 }
 
@@ -111,53 +227,159 @@ pub fn read_header(f: &mut File) -> Result<hnd_header_t, io::Error> {
     Ok(hnd_head)
 }
 
+#[allow(dead_code)]
 fn write_header(f: &mut File, h: &hnd_header_t) -> Result<(), io::Error> {
-    f.write(h.sFileType.as_ref())?;
+    f.write_all(h.sFileType.as_ref())?;
     Ok(())
 }
 
 fn read_data(f: &mut File) -> Result<hnd_data_t, io::Error> {
-    let header: hnd_header_t = read_header(f)?;
-
-    let w = header.SizeX;
-    let h = header.SizeY;
-    let len = w * h;
     let mut buf = Vec::new();
 
     // Skip HND header
-    let n = f.seek(SeekFrom::Start(1024));
-    let s = f.read_to_end(&mut buf)?;
+    f.seek(SeekFrom::Start(1024))?;
+    f.read_to_end(&mut buf)?;
 
     Ok(buf)
 }
 
 fn read_header_to_raw(f: &File) -> Result<modal::hnd_header_buf_t, io::Error> {
+    // the header is always at the start, wherever the file was left
+    let mut f = f;
+    f.seek(SeekFrom::Start(0))?;
     let mut reader = BufReader::new(f);
-    let mut buf: modal::hnd_header_buf_t = Vec::with_capacity(1024); 
-    buf.resize(1024, 0);
-    let n: usize = reader.read(&mut buf[..1024])?;
-    println!("DEBUG: read in {} bytes in total.", n);
-    return Ok(buf);
+    let mut buf: modal::hnd_header_buf_t = vec![0; 1024];
+    reader.read_exact(&mut buf[..1024])?;
+    Ok(buf)
 }
 
 pub fn read_file(f: &mut File) -> Result<HndImage, io::Error> {
     Ok(HndImage {
-        header: read_header(f).unwrap(),
-        data: read_data(f).unwrap(),
+        header: read_header(f)?,
+        data: read_data(f)?,
     })
 }
 
+/// Read and decode the image of an HND file.
+pub fn read_image(f: &mut File) -> Result<RawImage<u32>, io::Error> {
+    let hnd = read_file(f)?;
+    Ok(hnd.try_into()?)
+}
+
+/// Read a headerless raw image of 2 or 4 bytes per pixel (native byte order).
+pub fn read_raw_image(
+    f: &mut File,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+) -> Result<RawImage<u32>, io::Error> {
+    let len = f.metadata()?.len() - f.stream_position()?;
+    if len != (width * height * bytes_per_pixel) as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "raw file has {} bytes, expected {}x{}x{}",
+                len,
+                width,
+                height,
+                bytes_per_pixel
+            ),
+        ));
+    }
+    read_raw_frame(f, width, height, bytes_per_pixel)
+}
+
+/// Read the next frame of a raw file holding several frames back to back.
+pub fn read_raw_frame<R: Read>(
+    r: &mut R,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+) -> Result<RawImage<u32>, io::Error> {
+    if bytes_per_pixel != 2 && bytes_per_pixel != 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bytes per pixel must be 2 or 4",
+        ));
+    }
+    let mut buf = vec![0u8; width * height * bytes_per_pixel];
+    r.read_exact(&mut buf)?;
+    let data: Vec<u32> = match bytes_per_pixel {
+        2 => buf
+            .chunks_exact(2)
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]) as u32)
+            .collect(),
+        _ => buf
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+    };
+    Ok(RawImage::new(width, height, data)?)
+}
+
 pub fn write_file(f: &mut File, hnd: &HndImage) -> Result<(), io::Error> {
     let raw_header: modal::hnd_header_buf_t = hnd.header.to_raw();
-    f.write(&raw_header)?;
-    f.write(hnd.data.as_ref())?;
+    f.write_all(&raw_header)?;
+    f.write_all(hnd.data.as_ref())?;
     Ok(())
 }
 
+/// Replace the header of an HND file, leaving the compressed image data
+/// byte-identical. The new file is written to a temporary file in the same
+/// directory and then renamed over the original.
+pub fn rewrite_header(path: &Path, header: &hnd_header_t) -> Result<(), io::Error> {
+    copy_with_header(path, path, header)
+}
+
+/// Write a copy of the HND file `src` to `dst` with its header replaced. Like
+/// `rewrite_header` it goes through a temporary file next to `dst`, so `dst`
+/// may be `src` itself. The copy gets the permissions of `src`.
+pub fn copy_with_header(src: &Path, dst: &Path, header: &hnd_header_t) -> Result<(), io::Error> {
+    let dir = match dst.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let mut fin = File::open(src)?;
+    let permissions = fin.metadata()?.permissions();
+    fin.seek(SeekFrom::Start(1024))?;
+
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(&header.to_raw())?;
+    io::copy(&mut fin, &mut tmp)?;
+    tmp.as_file().sync_all()?;
+    tmp.as_file().set_permissions(permissions)?;
+    tmp.persist(dst).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Whether `path` has an `.hnd` extension (any case).
+pub fn is_hnd_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.to_string_lossy().eq_ignore_ascii_case("hnd"))
+}
+
+/// Whether `path` has a `.tif` or `.tiff` extension (any case).
+pub fn is_tiff_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| {
+        let e = e.to_string_lossy();
+        e.eq_ignore_ascii_case("tif") || e.eq_ignore_ascii_case("tiff")
+    })
+}
+
+pub fn is_dicom_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| {
+        let e = e.to_string_lossy();
+        e.eq_ignore_ascii_case("dcm") || e.eq_ignore_ascii_case("dicom")
+    })
+}
+
+pub fn has_extension(path: &Path, ext: &str) -> bool {
+    path.extension().is_some_and(|e| e.to_string_lossy().eq_ignore_ascii_case(ext))
+}
+
 pub fn convert_to_raw(fin: &mut File, fout: &mut File) -> Result<(), io::Error> {
-    let mut hnd_header_raw: modal::hnd_header_buf_t = Vec::with_capacity(1024);
-    hnd_header_raw.resize(1024, 0);
-    fin.read(&mut hnd_header_raw[..1024])?;
+    let mut hnd_header_raw: modal::hnd_header_buf_t = vec![0; 1024];
+    fin.read_exact(&mut hnd_header_raw[..1024])?;
 
     let mut hnd_data_buf: Vec<u8> = Vec::new();
     fin.read_to_end(&mut hnd_data_buf)?;
@@ -180,11 +402,18 @@ pub fn convert_to_raw(fin: &mut File, fout: &mut File) -> Result<(), io::Error>
     // let raw_image_buf: Vec<u8> = unsafe { std::mem::transmute(raw_image) };
     println!("{}", raw_image_buf.len());
 
-    fout.write(raw_image_buf.as_slice())?;
+    fout.write_all(raw_image_buf.as_slice())?;
     Ok(())
 }
 
 #[cfg(test)]
+#[allow(
+    unused_imports,
+    unused_variables,
+    clippy::items_after_test_module,
+    clippy::missing_transmute_annotations,
+    clippy::unsound_collection_transmute
+)]
 mod tests {
     #[test]
     fn it_works() {
@@ -308,6 +537,41 @@ mod tests {
     #[test]
     fn test_read_raw_16() {}
 
+    #[test]
+    fn test_rewrite_header() {
+        use crate::*;
+        use std::io::Read;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.hnd");
+        std::fs::copy("test/test_data_1.hnd", &path).unwrap();
+
+        let mut f = std::fs::File::open(&path).unwrap();
+        let mut header = crate::read_header(&mut f).unwrap();
+        assert_eq!(header.to_raw().len(), 1024);
+
+        header.set_field_str("dSAD", "1000").unwrap();
+        header.set_field_str("sModality", "CT").unwrap();
+        assert!(header.set_field_str("SizeX", "-1").is_err());
+        assert!(header.set_field_str("sImageType", "TOO LONG").is_err());
+        assert!(header.set_field("dSAD", HeaderValue::U32(1)).is_err());
+        crate::rewrite_header(&path, &header).unwrap();
+
+        let mut original = Vec::new();
+        std::fs::File::open("test/test_data_1.hnd").unwrap().read_to_end(&mut original).unwrap();
+        let mut rewritten = Vec::new();
+        std::fs::File::open(&path).unwrap().read_to_end(&mut rewritten).unwrap();
+        assert_eq!(original.len(), rewritten.len());
+        assert_eq!(original[1024..], rewritten[1024..]);
+        assert_eq!(original[HEADER_FIELDS_SIZE..1024], rewritten[HEADER_FIELDS_SIZE..1024]);
+
+        let mut f = std::fs::File::open(&path).unwrap();
+        let header2 = crate::read_header(&mut f).unwrap();
+        assert_eq!(header2.dSAD, 1000.0);
+        assert_eq!(header2.sModality, "CT");
+        assert_eq!(header2.dCTProjectionAngle, header.dCTProjectionAngle);
+    }
+
     #[test]
     fn test_header_convertion() {
         use crate::*;
@@ -333,6 +597,17 @@ mod tests {
         assert_eq!(header2.dCTProjectionAngle, -71.01111111111112);
         assert_eq!(header2.dCTNormChamber, 1164.0);
     }
+
+    #[test]
+    fn test_hnd_image_too_small() {
+        use crate::*;
+        let row = RawImage::new(10, 1, vec![0u32; 10]).unwrap();
+        assert!(matches!(HndImage::new(hnd_header_t::new(), &row), Err(ImageConvError::TooSmall)));
+        let empty = RawImage::new(0, 4, Vec::<u32>::new()).unwrap();
+        assert!(HndImage::new(hnd_header_t::new(), &empty).is_err());
+        let column = RawImage::new(1, 2, vec![7u32, 9]).unwrap();
+        assert!(HndImage::new(hnd_header_t::new(), &column).is_ok());
+    }
 }
 
 // #[repr(C)]
@@ -369,7 +644,7 @@ pub extern "C" fn encode(img: *mut u8, width: usize, height: usize, bytes_per_pi
                 let raw_ptr = data.as_mut_ptr();
                 std::mem::forget(v);
                 std::mem::forget(data);
-                return raw_ptr;
+                raw_ptr
             }
             4 => {
                 let v = Vec::from_raw_parts(img as *mut u32, len, len);
@@ -379,7 +654,7 @@ pub extern "C" fn encode(img: *mut u8, width: usize, height: usize, bytes_per_pi
                 let raw_ptr = data.as_mut_ptr();
                 std::mem::forget(v);
                 std::mem::forget(data);
-                return raw_ptr;
+                raw_ptr
             }
             _ => panic!()
         }
@@ -454,7 +729,7 @@ pub extern "C" fn hnd_header_to_raw(ptr: *mut modal::hnd_header_t) -> *const u8
         std::mem::forget(raw_header_buf);
         return raw_ptr;
     }
-    return std::ptr::null(); 
+    std::ptr::null()
 }
 
 #[no_mangle]
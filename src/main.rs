@@ -1,12 +1,9 @@
-use std::convert::{From, Into, TryFrom, TryInto};
+use std::convert::{From, Into};
 use std::error::Error;
 use std::fs::{File, OpenOptions};
-use std::io;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::mem;
+use std::io::{BufReader, Read, Write};
 use std::str::FromStr;
 use clap::clap_app;
-use hnd;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = clap_app!(hnd =>
@@ -22,69 +19,876 @@ fn main() -> Result<(), Box<dyn Error>> {
         // )
         (@subcommand show =>
             (about: "print out header information.")
-            (@arg filename: +required "Sets the input file"))
+            (@arg filename: +required +multiple "Sets the input file(s)")
+            (@arg format: -f --format +takes_value possible_value[text json csv] default_value("text")
+                "Output format")
+            (@arg field: --field +takes_value +multiple number_of_values(1)
+                "Only print this header field, can be repeated"))
+        (@subcommand set =>
+            (about: "Edit header fields in place, the image data is left untouched.")
+            (@arg files: +required +multiple "HND files to edit")
+            (@arg field: --field +required +takes_value +multiple number_of_values(1)
+                "Field assignment NAME=VALUE, can be repeated")
+            (@arg dry_run: -n --("dry-run") "Only print the changes"))
+        (@subcommand anonymize =>
+            (about: "Replace patient identifying header fields with pseudonyms.")
+            (@arg input: +required "Input HND file or directory")
+            (@arg output: +required "Output HND file or directory")
+            (@arg map: -m --map <FILE> +required "Pseudonym mapping file, created or extended")
+            (@arg reverse: -r --reverse "Restore the original values using the mapping file"))
+        (@subcommand compare =>
+            (about: "Compare the pixels of two images, HND or RAW.")
+            (@arg a: +required "Reference image")
+            (@arg b: +required "Image to compare")
+            (@arg width: -w --width +takes_value "Width of RAW inputs")
+            (@arg height: -h --height +takes_value "Height of RAW inputs")
+            (@arg n_bytes: -b --bytes +takes_value possible_values(&["2", "4"]) "Bytes per pixel of RAW inputs")
+            (@arg diff: -d --diff +takes_value "Write the signed difference b - a as 32-bit RAW"))
+        (@subcommand check =>
+            (about: "Validate HND files or scan directories. Exits with 2 on errors.")
+            (@arg inputs: +required +multiple "HND files or scan directories")
+            (@arg quiet: -q --quiet "Only print warnings and errors")
+            (@arg strict: --strict "Exit with 1 on warnings"))
+        (@subcommand preview =>
+            (about: "Render a windowed PNG or PGM preview. Directories are converted frame by frame.")
+            (@arg input: +required "Input HND file or scan directory")
+            (@arg output: +required "Output .png/.pgm file, or directory for a scan")
+            (@arg window: --window +takes_value requires[level] "Window width, overrides nWindow")
+            (@arg level: --level +takes_value requires[window] "Window level, overrides nLevel")
+            (@arg auto: --auto +takes_value conflicts_with[window] "Window from the P..100-P percentiles")
+            (@arg invert: --invert "Invert the gray scale")
+            (@arg bits: --bits +takes_value possible_values(&["8", "16"]) default_value("8") "Bits per pixel")
+            (@arg max_size: --("max-size") +takes_value "Shrink to at most this many pixels per side")
+            (@arg format: --format +takes_value possible_value[png pgm] default_value("png")
+                "Image format in directory mode"))
         (@subcommand conv =>
-            (about: "Convert HND to RAW.")
-            (@arg input: +required "Sets the input file")
-            (@arg output: +required "Sets the output file"))
+            (about: "Convert HND to RAW, to TIFF if the output ends in .tif/.tiff, to NumPy if it ends in .npy, to NRRD if it ends in .nrrd/.nhdr or to a DICOM RT Image if it ends in .dcm. A scan directory is converted to .npz, NRRD, or else to a DICOM series.")
+            (@arg input: +required "Sets the input file or scan directory")
+            (@arg output: +required "Sets the output file or directory")
+            (@arg bits: --bits +takes_value possible_values(&["16", "32"]) default_value("32")
+                "Bits per pixel of TIFF, NumPy, NRRD and DICOM output")
+            (@arg encoding: --encoding +takes_value possible_value[raw gzip] default_value("raw")
+                "Encoding of NRRD output"))
         (@subcommand raw =>
-            (about: "Create HND from RAW.")
+            (about: "Create HND from RAW, or from a TIFF written by conv.")
             (@arg input: +required "Sets the input file")
             (@arg output: +required "Sets the output file")
-            (@arg width: -w --width <INT> +required +takes_value "Width of the image")
-            (@arg height: -h --height <INT> +required +takes_value "Height of the image")
+            (@arg width: -w --width [INT] +takes_value "Width of the image, required for RAW")
+            (@arg height: -h --height [INT] +takes_value "Height of the image, required for RAW")
             (@arg x_res: --x_res [DOUBLE] +takes_value "X resolution")
             (@arg y_res: --y_res [DOUBLE] +takes_value "Y resolution")
             (@arg angle: -a --angle [DOUBLE] +takes_value "Projection angle in degree")
-            (@arg n_bytes: -b --bytes <SHORT> +takes_value "Bytes per pixel")
-            //(@arg n_images: -n <INT> -required +takes_value "number of images in the input file")
+            (@arg n_bytes: -b --bytes [SHORT] +takes_value "Bytes per pixel, required for RAW")
+            (@arg n_images: -n --n_images [INT] +takes_value
+                "Number of frames in the RAW file, writes <output>_00000.hnd... if more than one")
+            (@arg angle_step: --angle_step [DOUBLE] +takes_value conflicts_with[angles]
+                "Angle increment per frame, starting at --angle")
+            (@arg angles: --angles [FILE] +takes_value conflicts_with[angle]
+                "Text file with one line per frame, the angle in the first column")
+            (@arg norm_column: --norm_column [INT] +takes_value requires[angles]
+                "Column of the --angles file holding the norm chamber values, starting at 1")
         )
     )
+    .subcommand(clap_app!(("diff-header") =>
+        (about: "List the header fields that differ between two HND files or two scans.")
+        (@arg a: +required "First HND file or scan directory")
+        (@arg b: +required "Second HND file or scan directory")
+        (@arg scan: --scan "Compare two scan directories frame by frame")
+        (@arg match_by: --match +takes_value possible_value[index angle] default_value("index")
+            "Pair frames by index or by nearest projection angle")
+        (@arg abs_tol: --("abs-tol") +takes_value "Absolute tolerance for floating point fields")
+        (@arg rel_tol: --("rel-tol") +takes_value "Relative tolerance for floating point fields")
+        (@arg ignore: --ignore +takes_value +multiple number_of_values(1) "Skip this field, can be repeated")))
+    .subcommand(clap_app!(transform =>
+        (about: "Crop, flip, rotate, bin or resample HND files and update the header to match. Operations run in this order.")
+        (@arg input: +required "HND file or scan directory")
+        (@arg output: +required "Output file or directory")
+        (@arg crop: --crop +takes_value "Keep the region X,Y,WIDTH,HEIGHT")
+        (@arg flip: --flip +takes_value possible_value[h v] "Mirror horizontally or vertically")
+        (@arg rotate: --rotate +takes_value possible_values(&["90", "180", "270"]) "Rotate clockwise by degree")
+        (@arg bin: --bin +takes_value possible_values(&["2", "4"]) "Average FACTOR x FACTOR blocks")
+        (@arg resample: --resample +takes_value "Resample to WIDTHxHEIGHT")))
+    .subcommand(clap_app!(lag =>
+        (about: "Detector lag (ghosting) correction of projection sequences.")
+        (@subcommand correct =>
+            (about: "Remove the lag of earlier frames from every frame of a scan, in acquisition order.")
+            (@arg input: +required "Scan directory")
+            (@arg output: +required "Directory for the corrected projections")
+            (@arg model: --model +takes_value +required "Lag coefficients, one `rate amplitude` pair per line"))
+        (@subcommand fit =>
+            (about: "Fit lag coefficients to a step response: dark, exposed and dark frames.")
+            (@arg input: +required "Scan directory with the step response")
+            (@arg output: +required "File to write the coefficients to")
+            (@arg rates: --rates +takes_value "Decay rates per frame, comma separated"))))
+    .subcommand(clap_app!(scatter =>
+        (about: "Remove the scatter estimated by kernel superposition from HND files, before log normalization.")
+        (@arg input: +required "HND file or scan directory")
+        (@arg output: +required "Output file or directory")
+        (@arg model: --model +takes_value +required "Scatter kernel model file")
+        (@arg i0: --i0 +takes_value +required "Unattenuated signal, the detector reading in an open field")
+        (@arg iterations: --iterations +takes_value default_value("3") "Iterations of the primary estimate")
+        (@arg bin: --bin +takes_value default_value("4") "Binning of the grid the scatter is computed on")))
+    .subcommand(clap_app!(normalize =>
+        (about: "Write the line integrals ln(I0 / I) of a HND file (.npy) or scan directory (.npz) as float32.")
+        (@arg input: +required "HND file or scan directory")
+        (@arg output: +required "Output .npy or .npz")
+        (@arg i0: --i0 +takes_value "Unattenuated signal, the detector reading in an open field")
+        (@arg air: --air +takes_value conflicts_with[i0] "Air scan, HND file or directory, scaled by the norm chamber of each frame")
+        (@arg scatter: --scatter +takes_value "Remove the scatter of this kernel model before the log")
+        (@arg beam_hardening: --("beam-hardening") +takes_value "Correct the line integrals with this beam-hardening table")
+        (@arg bowtie: --bowtie +takes_value default_value("none") "Bowtie filter of the scan, to select the beam-hardening correction")))
+    .subcommand(clap_app!(("beam-hardening") =>
+        (about: "Water precorrection of beam hardening.")
+        (@subcommand fit =>
+            (about: "Fit the correction for the tube voltage of a scan of a centred water cylinder and add it to a table.")
+            (@arg input: +required "Scan directory of the water cylinder")
+            (@arg table: +required "Table file, created or updated")
+            (@arg radius: --radius +takes_value +required "Radius of the cylinder in mm")
+            (@arg order: --order +takes_value default_value("3") "Order of the polynomial")
+            (@arg bowtie: --bowtie +takes_value default_value("none") "Bowtie filter of the scan")
+            (@arg i0: --i0 +takes_value "Unattenuated signal, the detector reading in an open field")
+            (@arg air: --air +takes_value conflicts_with[i0] "Air scan, HND file or directory, scaled by the norm chamber of each frame"))))
+    .subcommand(clap_app!(("gating-bin") =>
+        (about: "Sort the projections of a scan into respiratory phase or amplitude bins for 4D-CBCT.")
+        (@arg input: +required "Scan directory")
+        (@arg output: +required "Directory for one projection list per bin")
+        (@arg bins: -n --bins +takes_value default_value("10") "Number of bins")
+        (@arg mode: --mode +takes_value possible_value[phase amplitude] default_value("phase") "Sort by phase or amplitude")
+        (@arg axis: --axis +takes_value possible_value[x y z auto] default_value("auto")
+            "dGating4DInfo field that holds the breathing signal")
+        (@arg signal: --signal +takes_value "Breathing trace CSV from hnd shroud instead of the gating fields")))
+    .subcommand(clap_app!(shroud =>
+        (about: "Extract a breathing trace from the projections (Amsterdam shroud) for scans without gating data.")
+        (@arg input: +required "Scan directory")
+        (@arg output: +required "CSV of frame, time, amplitude and phase")
+        (@arg max_shift: --("max-shift") +takes_value default_value("5") "Largest motion between two frames in rows")
+        (@arg degree: --degree +takes_value default_value("3") "Order of the polynomial drift removed from the trace")))
+    .subcommand(clap_app!(("scan-report") =>
+        (about: "Summarize the acquisition geometry of a scan and flag scans unsuitable for reconstruction.")
+        (@arg input: +required "Scan directory")
+        (@arg gap: --gap +takes_value "Steps above this many degree are gaps, by default five times the median step")
+        (@arg tolerance: --tolerance +takes_value default_value("0.1") "Allowed spread of dSAD, dSFD and dIDUPosLat in cm")
+        (@arg quiet: -q --quiet "Do not print info messages")))
+    .subcommand(clap_app!(flexmap =>
+        (about: "Correct the gantry sag of the imager per projection angle.")
+        (@subcommand apply =>
+            (about: "Move the detector position in the header (or shift the image) by the flexmap.")
+            (@arg input: +required "HND file or scan directory")
+            (@arg output: +required "Output file or directory")
+            (@arg table: --table +takes_value +required "Flexmap, one `angle u v [source_u source_v]` line (mm) per entry")
+            (@arg image: --image "Shift the image instead of changing the header"))
+        (@subcommand derive =>
+            (about: "Derive a flexmap from a scan of a single ball bearing.")
+            (@arg input: +required "Scan directory of the ball bearing phantom")
+            (@arg output: +required "Flexmap file to write")
+            (@arg marker: --marker +takes_value default_value("0,0,0") "Position of the ball bearing in mm, X,Y,Z")
+            (@arg radius: --radius +takes_value default_value("10") "Search window around the marker in pixels"))))
+    .subcommand(clap_app!(geometry =>
+        (about: "Export the acquisition geometry (IEC 61217, mm) and the 3x4 projection matrix of every frame.")
+        (@arg input: +required "HND file or scan directory")
+        (@arg output: +required "Output file, or directory for --format matrices")
+        (@arg format: --format +takes_value possible_values(&["matrices", "csv", "json", "astra-cone-vec", "tigre"])
+            default_value("json")
+            "One text matrix per frame, a single CSV, a JSON array, ASTRA cone_vec rows or a TIGRE geometry")
+        (@arg flexmap: --flexmap +takes_value "Move detector and source by this flexmap")))
+    .subcommand(clap_app!(header =>
+        (about: "Convert the header of a HND file to and from JSON, TOML or YAML (needs the serde feature).")
+        (@subcommand export =>
+            (about: "Write the complete header, reserved bytes included, as text.")
+            (@arg input: +required "HND file")
+            (@arg output: "Output file, standard output if not given")
+            (@arg format: --format +takes_value possible_value[json toml yaml]
+                "Text format, by default from the output file name, else JSON"))
+        (@subcommand import =>
+            (about: "Replace the header of a HND file with one exported before, the image data is left untouched.")
+            (@arg input: +required "Exported header")
+            (@arg target: +required "HND file, changed in place unless --output is given")
+            (@arg output: -o --output +takes_value "Write the result to this file instead")
+            (@arg format: --format +takes_value possible_value[json toml yaml]
+                "Text format, by default from the input file name"))))
+    .subcommand(clap_app!(simulate =>
+        (about: "Simulate a cone-beam scan of an analytic phantom.")
+        (@arg output: +required "Directory for the projections")
+        (@arg phantom: --phantom +takes_value possible_values(&["shepp-logan", "water"]) default_value("shepp-logan")
+            "Phantom to project")
+        (@arg radius: --radius +takes_value default_value("100") "Phantom radius in mm")
+        (@arg template: --template +takes_value "HND file to take the geometry from")
+        (@arg width: -w --width +takes_value "Detector columns, overrides the template")
+        (@arg height: -h --height +takes_value "Detector rows, overrides the template")
+        (@arg n_images: -n --n_images +takes_value default_value("360") "Number of projections")
+        (@arg start: --start +takes_value default_value("0") "First projection angle in degree")
+        (@arg arc: --arc +takes_value default_value("360") "Rotation covered by the scan in degree")
+        (@arg flux: --flux +takes_value default_value("50000") "Unattenuated counts per pixel")
+        (@arg noise: --noise "Add Poisson noise")
+        (@arg seed: --seed +takes_value default_value("1") "Seed of the noise")))
+    .subcommand(clap_app!(("from-dicom") =>
+        (about: "Create HND from an uncompressed little endian DICOM image (RT Image, CR, DX).")
+        (@arg input: +required "Sets the input DICOM file")
+        (@arg output: +required "Sets the output HND file")))
     .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("show") {
-        let filename = matches.value_of("filename").unwrap();
-        let mut f = File::open(filename)?;
-        let metadata = f.metadata()?;
-        println!("{:?}", metadata.file_type());
-        hnd::print_header(&mut f)?;
-    } else if let Some(matches) = matches.subcommand_matches("test") {
+        let filenames: Vec<&str> = matches.values_of("filename").unwrap().collect();
+        let format: hnd::OutputFormat = matches.value_of("format").unwrap().parse()?;
+        let fields: Vec<&str> = matches.values_of("field").map_or(Vec::new(), |v| v.collect());
+
+        if format == hnd::OutputFormat::Csv {
+            println!("file,{}", hnd::csv_columns(&fields));
+        }
+        if format == hnd::OutputFormat::Json && filenames.len() > 1 {
+            println!("[");
+        }
+        for (i, filename) in filenames.iter().enumerate() {
+            let mut f = File::open(filename)?;
+            let header = hnd::read_header(&mut f)?;
+            match format {
+                hnd::OutputFormat::Text => {
+                    if filenames.len() > 1 {
+                        println!("==> {} <==", filename);
+                    }
+                    if fields.is_empty() {
+                        println!("{}", header);
+                    } else {
+                        for name in &fields {
+                            println!("{}:\t{}", name, header.field(name)?);
+                        }
+                    }
+                }
+                hnd::OutputFormat::Json => {
+                    let json = hnd::header_to_json(&header, &fields)?;
+                    if filenames.len() > 1 {
+                        let sep = if i + 1 < filenames.len() { "," } else { "" };
+                        println!("  {{\"file\": {}, \"header\": {}}}{}", hnd::json_escape(filename), json, sep);
+                    } else {
+                        println!("{}", json);
+                    }
+                }
+                hnd::OutputFormat::Csv => {
+                    let row = hnd::header_to_csv(&header, &fields)?;
+                    println!("{},{}", hnd::csv_escape(filename), row);
+                }
+            }
+        }
+        if format == hnd::OutputFormat::Json && filenames.len() > 1 {
+            println!("]");
+        }
+    } else if let Some(matches) = matches.subcommand_matches("set") {
+        let mut assignments = Vec::new();
+        for a in matches.values_of("field").unwrap() {
+            match a.find('=') {
+                Some(i) => assignments.push((&a[..i], &a[i + 1..])),
+                None => return Err(format!("expected NAME=VALUE, got `{}`", a).into()),
+            }
+        }
+        let dry_run = matches.is_present("dry_run");
+
+        for filename in matches.values_of("files").unwrap() {
+            let mut f = File::open(filename)?;
+            let mut header = hnd::read_header(&mut f)?;
+            drop(f);
+
+            let mut changed = false;
+            for (name, value) in &assignments {
+                let old = header.field(name)?;
+                header.set_field_str(name, value)?;
+                let new = header.field(name)?;
+                if old != new {
+                    println!("{}: {}: {} -> {}", filename, name, old, new);
+                    changed = true;
+                }
+            }
+            if changed && !dry_run {
+                hnd::rewrite_header(std::path::Path::new(filename), &header)?;
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("anonymize") {
+        let input = std::path::Path::new(matches.value_of("input").unwrap());
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        let map_path = std::path::Path::new(matches.value_of("map").unwrap());
+        let reverse = matches.is_present("reverse");
+
+        let mut map = if map_path.exists() {
+            hnd::PseudonymMap::load(map_path)?
+        } else if reverse {
+            return Err(format!("mapping file {} not found", map_path.display()).into());
+        } else {
+            hnd::PseudonymMap::new()
+        };
+        let save_to = if reverse { None } else { Some(map_path) };
+        let n = hnd::anonymize_tree(input, output, &mut map, save_to, reverse)?;
+        if !reverse {
+            map.save(map_path)?;
+        }
+        println!("{} file(s) written to {}", n, output.display());
+    } else if let Some(matches) = matches.subcommand_matches("transform") {
+        let mut transforms = Vec::new();
+        if let Some(crop) = matches.value_of("crop") {
+            let v = crop.split(',').map(usize::from_str).collect::<Result<Vec<usize>, _>>()?;
+            if v.len() != 4 {
+                return Err("--crop takes X,Y,WIDTH,HEIGHT".into());
+            }
+            transforms.push(hnd::Transform::Crop { x: v[0], y: v[1], width: v[2], height: v[3] });
+        }
+        match matches.value_of("flip") {
+            Some("h") => transforms.push(hnd::Transform::FlipHorizontal),
+            Some("v") => transforms.push(hnd::Transform::FlipVertical),
+            _ => {}
+        }
+        if let Some(degree) = matches.value_of("rotate") {
+            transforms.push(hnd::Transform::Rotate90(u32::from_str(degree)? / 90));
+        }
+        if let Some(factor) = matches.value_of("bin") {
+            transforms.push(hnd::Transform::Bin(usize::from_str(factor)?));
+        }
+        if let Some(size) = matches.value_of("resample") {
+            let v = size.split('x').map(usize::from_str).collect::<Result<Vec<usize>, _>>()?;
+            if v.len() != 2 {
+                return Err("--resample takes WIDTHxHEIGHT".into());
+            }
+            transforms.push(hnd::Transform::Resample { width: v[0], height: v[1] });
+        }
+
+        let transform_file = |input: &std::path::Path, output: &std::path::Path| -> Result<(), Box<dyn Error>> {
+            let mut fin = File::open(input)?;
+            let mut header = hnd::read_header(&mut fin)?;
+            let mut img = hnd::read_image(&mut fin)?;
+            for t in &transforms {
+                img = hnd::apply_transform(&mut header, &img, t)?;
+            }
+            hnd::write_file(&mut File::create(output)?, &hnd::HndImage::new(header, &img)?)?;
+            Ok(())
+        };
+        let input = std::path::Path::new(matches.value_of("input").unwrap());
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        if input.is_dir() {
+            std::fs::create_dir_all(output)?;
+            let files = hnd::list_scan_files(input)?;
+            for path in &files {
+                transform_file(path, &output.join(path.file_name().unwrap()))?;
+            }
+            println!("{} file(s) written to {}", files.len(), output.display());
+        } else {
+            transform_file(input, output)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("lag") {
+        if let Some(matches) = matches.subcommand_matches("correct") {
+            let model = hnd::LagModel::load(std::path::Path::new(matches.value_of("model").unwrap()))?;
+            let output = std::path::Path::new(matches.value_of("output").unwrap());
+            let n = hnd::lag_correct_scan(std::path::Path::new(matches.value_of("input").unwrap()), output, &model)?;
+            println!("{} projection(s) written to {}", n, output.display());
+        } else if let Some(matches) = matches.subcommand_matches("fit") {
+            let rates = match matches.value_of("rates") {
+                Some(list) => list.split(',').map(|v| f64::from_str(v.trim())).collect::<Result<Vec<_>, _>>()?,
+                None => hnd::DEFAULT_LAG_RATES.to_vec(),
+            };
+            let signal = hnd::frame_means(std::path::Path::new(matches.value_of("input").unwrap()))?;
+            let model = hnd::fit_lag_model(&signal, &rates)?;
+            std::fs::write(matches.value_of("output").unwrap(), model.to_text())?;
+            print!("{}", model.to_text());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("scatter") {
+        let model = hnd::ScatterModel::load(std::path::Path::new(matches.value_of("model").unwrap()))?;
+        let i0 = f64::from_str(matches.value_of("i0").unwrap())?;
+        let opts = hnd::ScatterOptions {
+            iterations: usize::from_str(matches.value_of("iterations").unwrap())?,
+            bin: usize::from_str(matches.value_of("bin").unwrap())?,
+            ..Default::default()
+        };
+        let input = std::path::Path::new(matches.value_of("input").unwrap());
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        if input.is_dir() {
+            std::fs::create_dir_all(output)?;
+            let files = hnd::list_scan_files(input)?;
+            for path in &files {
+                hnd::scatter_correct_file(path, &output.join(path.file_name().unwrap()), &model, i0, &opts)?;
+            }
+            println!("{} file(s) written to {}", files.len(), output.display());
+        } else {
+            hnd::scatter_correct_file(input, output, &model, i0, &opts)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("normalize") {
+        let mut opts = hnd::NormalizeOptions {
+            open_field: Some(open_field(matches)?),
+            bowtie: matches.value_of("bowtie").unwrap().to_string(),
+            ..Default::default()
+        };
+        if let Some(path) = matches.value_of("scatter") {
+            opts.scatter = Some((hnd::ScatterModel::load(std::path::Path::new(path))?, Default::default()));
+        }
+        if let Some(path) = matches.value_of("beam_hardening") {
+            opts.beam_hardening = Some(hnd::BeamHardeningTable::load(std::path::Path::new(path))?);
+        }
+        let input = std::path::Path::new(matches.value_of("input").unwrap());
+        let output = matches.value_of("output").unwrap();
+        if input.is_dir() {
+            let n = hnd::write_normalized_npz(File::create(output)?, input, &opts)?;
+            println!("{} projection(s) written to {}", n, output);
+        } else {
+            let mut fin = File::open(input)?;
+            let header = hnd::read_header(&mut fin)?;
+            let img = hnd::read_image(&mut fin)?;
+            hnd::write_normalized_npy(&mut File::create(output)?, &header, &img, &opts)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("beam-hardening") {
+        if let Some(matches) = matches.subcommand_matches("fit") {
+            let input = std::path::Path::new(matches.value_of("input").unwrap());
+            let table_path = std::path::Path::new(matches.value_of("table").unwrap());
+            let radius = f64::from_str(matches.value_of("radius").unwrap())?;
+            let samples = hnd::water_samples(input, radius, &open_field(matches)?, 4)?;
+            let coefficients = hnd::fit_water_precorrection(&samples, usize::from_str(matches.value_of("order").unwrap())?)?;
+            let kv = hnd::read_scan_headers(input)?[0].1.dXRayKV;
+            let mut table = if table_path.exists() {
+                hnd::BeamHardeningTable::load(table_path)?
+            } else {
+                hnd::BeamHardeningTable::default()
+            };
+            table.insert(hnd::BeamHardening {
+                kv,
+                bowtie: matches.value_of("bowtie").unwrap().to_string(),
+                coefficients,
+            });
+            std::fs::write(table_path, table.to_text())?;
+            print!("{}", table.to_text());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("gating-bin") {
+        let scan = hnd::read_scan_headers(std::path::Path::new(matches.value_of("input").unwrap()))?;
+        let headers: Vec<hnd::hnd_header_t> = scan.iter().map(|(_, h)| h.clone()).collect();
+        let mode: hnd::BinMode = matches.value_of("mode").unwrap().parse()?;
+        let n_bins = usize::from_str(matches.value_of("bins").unwrap())?;
+        let (bins, values) = match matches.value_of("signal") {
+            Some(path) => {
+                let trace = hnd::BreathingTrace::load(std::path::Path::new(path))?;
+                let values = trace.values(scan.len(), mode == hnd::BinMode::Phase);
+                (hnd::bin_values(&values, mode, n_bins)?, values)
+            }
+            None => hnd::bin_frames(&headers, mode, n_bins, matches.value_of("axis").unwrap().parse()?)?,
+        };
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        let written = hnd::write_bin_lists(output, &scan, &bins, &values, mode)?;
+        for (path, bin) in written.iter().zip(&bins) {
+            println!("{}\t{} frame(s)", path.display(), bin.len());
+        }
+        let unbinned = scan.len() - bins.iter().map(|b| b.len()).sum::<usize>();
+        if unbinned > 0 {
+            println!("{} frame(s) without gating data or outside a full breathing cycle", unbinned);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("shroud") {
+        let trace = hnd::BreathingTrace::from_scan(
+            std::path::Path::new(matches.value_of("input").unwrap()),
+            usize::from_str(matches.value_of("max_shift").unwrap())?,
+            usize::from_str(matches.value_of("degree").unwrap())?,
+        )?;
+        std::fs::write(matches.value_of("output").unwrap(), trace.to_csv())?;
+        let phased = trace.phase.iter().filter(|p| p.is_some()).count();
+        println!("{} frame(s), {} with a phase", trace.frame.len(), phased);
+    } else if let Some(matches) = matches.subcommand_matches("scan-report") {
+        let opts = hnd::ReportOptions {
+            gap_threshold: matches.value_of("gap").map(f64::from_str).transpose()?,
+            geometry_tolerance: f64::from_str(matches.value_of("tolerance").unwrap())?,
+            ..Default::default()
+        };
+        let report = hnd::ScanReport::new(std::path::Path::new(matches.value_of("input").unwrap()), &opts)?;
+        print!("{}", report);
+        for issue in &report.issues {
+            if !(matches.is_present("quiet") && issue.severity == hnd::Severity::Info) {
+                println!("{}", issue);
+            }
+        }
+        if !report.suitable() {
+            std::process::exit(2);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("flexmap") {
+        if let Some(matches) = matches.subcommand_matches("apply") {
+            let flexmap = hnd::Flexmap::load(std::path::Path::new(matches.value_of("table").unwrap()))?;
+            let image = matches.is_present("image");
+            let input = std::path::Path::new(matches.value_of("input").unwrap());
+            let output = std::path::Path::new(matches.value_of("output").unwrap());
+            if input.is_dir() {
+                std::fs::create_dir_all(output)?;
+                let files = hnd::list_scan_files(input)?;
+                for path in &files {
+                    hnd::flexmap_correct_file(path, &output.join(path.file_name().unwrap()), &flexmap, image)?;
+                }
+                println!("{} file(s) written to {}", files.len(), output.display());
+            } else {
+                hnd::flexmap_correct_file(input, output, &flexmap, image)?;
+            }
+        } else if let Some(matches) = matches.subcommand_matches("derive") {
+            let v = matches.value_of("marker").unwrap().split(',').map(f64::from_str).collect::<Result<Vec<f64>, _>>()?;
+            if v.len() != 3 {
+                return Err("--marker takes X,Y,Z".into());
+            }
+            let flexmap = hnd::derive_flexmap(
+                std::path::Path::new(matches.value_of("input").unwrap()),
+                [v[0], v[1], v[2]],
+                usize::from_str(matches.value_of("radius").unwrap())?,
+            )?;
+            std::fs::write(matches.value_of("output").unwrap(), flexmap.to_text())?;
+            println!("{} angle(s) written to {}", flexmap.entries.len(), matches.value_of("output").unwrap());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("geometry") {
+        let flexmap = matches
+            .value_of("flexmap")
+            .map(|path| hnd::Flexmap::load(std::path::Path::new(path)))
+            .transpose()?;
+        let input = std::path::Path::new(matches.value_of("input").unwrap());
+        let frames = if input.is_dir() {
+            hnd::scan_geometry(input, flexmap.as_ref())?
+        } else {
+            let header = hnd::read_header(&mut File::open(input)?)?;
+            let mut g = hnd::AcquisitionGeometry::from_header(&header)?;
+            if let Some(map) = &flexmap {
+                g = g.shifted(&map.at(header.dCTProjectionAngle));
+            }
+            vec![(input.to_path_buf(), g)]
+        };
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        hnd::write_geometry(output, &frames, matches.value_of("format").unwrap().parse()?)?;
+        println!("geometry of {} frame(s) written to {}", frames.len(), output.display());
+    } else if let Some(matches) = matches.subcommand_matches("header") {
+        #[cfg(feature = "serde")]
+        {
+            if let Some(matches) = matches.subcommand_matches("export") {
+                let header = hnd::read_header(&mut File::open(matches.value_of("input").unwrap())?)?;
+                let output = matches.value_of("output").map(std::path::Path::new);
+                let format = match matches.value_of("format") {
+                    Some(f) => f.parse()?,
+                    None => output.and_then(hnd::HeaderFormat::from_path).unwrap_or(hnd::HeaderFormat::Json),
+                };
+                let text = hnd::export_header(&header, format)?;
+                match output {
+                    Some(path) => std::fs::write(path, text)?,
+                    None => print!("{}", text),
+                }
+            } else if let Some(matches) = matches.subcommand_matches("import") {
+                let input = std::path::Path::new(matches.value_of("input").unwrap());
+                let format = match matches.value_of("format") {
+                    Some(f) => f.parse()?,
+                    None => hnd::HeaderFormat::from_path(input)
+                        .ok_or_else(|| format!("cannot tell the format of {}, use --format", input.display()))?,
+                };
+                let header = hnd::import_header(&std::fs::read_to_string(input)?, format)
+                    .map_err(|e| format!("{}: {}", input.display(), e))?;
+                let target = std::path::Path::new(matches.value_of("target").unwrap());
+                let old = hnd::read_header(&mut File::open(target)?)?;
+                if (old.SizeX, old.SizeY) != (header.SizeX, header.SizeY) {
+                    return Err(format!(
+                        "{} is {}x{}, the imported header says {}x{}",
+                        target.display(),
+                        old.SizeX,
+                        old.SizeY,
+                        header.SizeX,
+                        header.SizeY
+                    )
+                    .into());
+                }
+                match matches.value_of("output") {
+                    Some(output) => hnd::copy_with_header(target, std::path::Path::new(output), &header)?,
+                    None => hnd::rewrite_header(target, &header)?,
+                }
+            }
+        }
+        #[cfg(not(feature = "serde"))]
+        {
+            let _ = matches;
+            return Err("hnd was built without the serde feature, rebuild with `--features serde`".into());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("simulate") {
+        let mut template = match matches.value_of("template") {
+            Some(path) => hnd::read_header(&mut File::open(path)?)?,
+            None => hnd::default_scan_header(),
+        };
+        if let Some(width) = matches.value_of("width") {
+            template.SizeX = u32::from_str(width)?;
+        }
+        if let Some(height) = matches.value_of("height") {
+            template.SizeY = u32::from_str(height)?;
+        }
+        let opts = hnd::SimulateOptions {
+            phantom: matches.value_of("phantom").unwrap().parse()?,
+            radius: f64::from_str(matches.value_of("radius").unwrap())?,
+            n_images: usize::from_str(matches.value_of("n_images").unwrap())?,
+            start_angle: f64::from_str(matches.value_of("start").unwrap())?,
+            arc: f64::from_str(matches.value_of("arc").unwrap())?,
+            flux: f64::from_str(matches.value_of("flux").unwrap())?,
+            noise: matches.is_present("noise"),
+            seed: u64::from_str(matches.value_of("seed").unwrap())?,
+        };
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        let n = hnd::simulate_scan(output, &template, &opts)?;
+        println!("{} projection(s) written to {}", n, output.display());
+    } else if let Some(matches) = matches.subcommand_matches("from-dicom") {
+        let mut buf = Vec::new();
+        File::open(matches.value_of("input").unwrap())?.read_to_end(&mut buf)?;
+        let (header, img) = hnd::read_dicom(&buf)?;
+        let hnd_image = hnd::HndImage::new(header, &img)?;
+        hnd::write_file(&mut File::create(matches.value_of("output").unwrap())?, &hnd_image)?;
+    } else if let Some(matches) = matches.subcommand_matches("diff-header") {
+        let a = std::path::Path::new(matches.value_of("a").unwrap());
+        let b = std::path::Path::new(matches.value_of("b").unwrap());
+        let tol = hnd::Tolerance {
+            abs: matches.value_of("abs_tol").map_or(Ok(0.0), f64::from_str)?,
+            rel: matches.value_of("rel_tol").map_or(Ok(0.0), f64::from_str)?,
+        };
+        let ignore: Vec<&str> = matches.values_of("ignore").map_or(Vec::new(), |v| v.collect());
+        for name in &ignore {
+            hnd::hnd_header_t::field_size(name)?;
+        }
+
+        // quote strings, header strings often hold control characters
+        let show = |v: &hnd::HeaderValue| match v {
+            hnd::HeaderValue::Str(s) => format!("{:?}", s),
+            v => v.to_string(),
+        };
+
+        let mut n_diffs = 0;
+        if matches.is_present("scan") {
+            let scan_a = hnd::read_scan_headers(a)?;
+            let scan_b = hnd::read_scan_headers(b)?;
+            let mode = match matches.value_of("match_by").unwrap() {
+                "angle" => hnd::FrameMatch::Angle,
+                _ => hnd::FrameMatch::Index,
+            };
+            let headers_a: Vec<_> = scan_a.iter().map(|(_, h)| h.clone()).collect();
+            let headers_b: Vec<_> = scan_b.iter().map(|(_, h)| h.clone()).collect();
+            for (i, j) in hnd::match_frames(&headers_a, &headers_b, mode) {
+                let (path_a, ha) = &scan_a[i];
+                match j {
+                    Some(j) => {
+                        let (path_b, hb) = &scan_b[j];
+                        for d in hnd::diff_headers(ha, hb, &tol, &ignore) {
+                            println!(
+                                "{} <> {}: {}: {} != {}",
+                                path_a.display(),
+                                path_b.display(),
+                                d.field,
+                                show(&d.a),
+                                show(&d.b)
+                            );
+                            n_diffs += 1;
+                        }
+                    }
+                    None => {
+                        println!("{}: no matching frame", path_a.display());
+                        n_diffs += 1;
+                    }
+                }
+            }
+            if mode == hnd::FrameMatch::Index && scan_b.len() > scan_a.len() {
+                for (path_b, _) in &scan_b[scan_a.len()..] {
+                    println!("{}: no matching frame", path_b.display());
+                    n_diffs += 1;
+                }
+            }
+        } else {
+            let ha = hnd::read_header(&mut File::open(a)?)?;
+            let hb = hnd::read_header(&mut File::open(b)?)?;
+            for d in hnd::diff_headers(&ha, &hb, &tol, &ignore) {
+                println!("{}: {} != {}", d.field, show(&d.a), show(&d.b));
+                n_diffs += 1;
+            }
+        }
+        if n_diffs > 0 {
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("compare") {
+        let load = |name: &str| -> Result<hnd::RawImage<u32>, Box<dyn Error>> {
+            let path = std::path::Path::new(name);
+            let mut f = File::open(path)?;
+            if hnd::is_hnd_file(path) {
+                return Ok(hnd::read_image(&mut f)?);
+            }
+            let arg = |x: &str| -> Result<usize, Box<dyn Error>> {
+                let v = matches.value_of(x).ok_or(format!("--{} is required for RAW input {}", x, name))?;
+                Ok(usize::from_str(v)?)
+            };
+            Ok(hnd::read_raw_image(&mut f, arg("width")?, arg("height")?, arg("n_bytes")?)?)
+        };
+        let a = load(matches.value_of("a").unwrap())?;
+        let b = load(matches.value_of("b").unwrap())?;
+
+        let stats = hnd::compare_images(&a, &b)?;
+        println!("identical:\t{}", stats.identical());
+        println!("pixels:\t{}", stats.n_pixels);
+        println!("different:\t{}", stats.n_different);
+        println!("max abs diff:\t{}", stats.max_abs_diff);
+        println!("RMSE:\t{}", stats.rmse);
+        println!("PSNR (dB):\t{}", stats.psnr);
+
+        if let Some(output) = matches.value_of("diff") {
+            let diff = hnd::difference_image(&a, &b)?;
+            let mut buf = Vec::with_capacity(diff.data().len() * 4);
+            diff.data().iter().for_each(|v| buf.extend_from_slice(&v.to_ne_bytes()));
+            File::create(output)?.write_all(&buf)?;
+        }
+        if !stats.identical() {
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("check") {
+        let mut issues = Vec::new();
+        for input in matches.values_of("inputs").unwrap() {
+            let path = std::path::Path::new(input);
+            if path.is_dir() {
+                issues.extend(hnd::check_scan(path)?);
+            } else {
+                issues.extend(hnd::check_file(path)?);
+            }
+        }
+        for issue in &issues {
+            if !(matches.is_present("quiet") && issue.severity == hnd::Severity::Info) {
+                println!("{}", issue);
+            }
+        }
+        match hnd::worst_severity(&issues) {
+            Some(hnd::Severity::Error) => std::process::exit(2),
+            Some(hnd::Severity::Warning) if matches.is_present("strict") => std::process::exit(1),
+            _ => {}
+        }
+    } else if let Some(matches) = matches.subcommand_matches("preview") {
+        let arg_f64 = |x: &str| matches.value_of(x).map(f64::from_str).transpose();
+        let mut opts = hnd::PreviewOptions::default();
+        if let (Some(width), Some(level)) = (arg_f64("window")?, arg_f64("level")?) {
+            opts.window = Some(hnd::Window { width, level });
+        }
+        opts.percentile = arg_f64("auto")?;
+        opts.invert = matches.is_present("invert");
+        if matches.value_of("bits") == Some("16") {
+            opts.depth = hnd::BitDepth::Sixteen;
+        }
+        opts.max_size = matches.value_of("max_size").map(usize::from_str).transpose()?;
+
+        let write_preview = |input: &std::path::Path, output: &std::path::Path| -> Result<(), Box<dyn Error>> {
+            let mut f = File::open(input)?;
+            let header = hnd::read_header(&mut f)?;
+            let img = hnd::read_image(&mut f)?;
+            let preview = hnd::make_preview(&header, &img, &opts);
+            let mut fout = std::io::BufWriter::new(File::create(output)?);
+            match output.extension().and_then(|e| e.to_str()) {
+                Some(e) if e.eq_ignore_ascii_case("pgm") => hnd::write_pgm(&mut fout, &preview, opts.depth)?,
+                _ => hnd::write_png(&mut fout, &preview, opts.depth)?,
+            }
+            Ok(())
+        };
+
+        let input = std::path::Path::new(matches.value_of("input").unwrap());
+        let output = std::path::Path::new(matches.value_of("output").unwrap());
+        if input.is_dir() {
+            std::fs::create_dir_all(output)?;
+            let ext = matches.value_of("format").unwrap();
+            for path in hnd::list_scan_files(input)? {
+                let target = output.join(path.file_stem().unwrap()).with_extension(ext);
+                write_preview(&path, &target)?;
+            }
+        } else {
+            write_preview(input, output)?;
+        }
+    } else if let Some(_matches) = matches.subcommand_matches("test") {
         println!("handling test subcommand!");
     } else if let Some(matches) = matches.subcommand_matches("conv") {
         let input = matches.value_of("input").unwrap();
+        let output = matches.value_of("output").unwrap();
+        let bits = u16::from_str(matches.value_of("bits").unwrap())?;
+        let is_nrrd = hnd::has_extension(std::path::Path::new(output), "nrrd")
+            || hnd::has_extension(std::path::Path::new(output), "nhdr");
+        if is_nrrd {
+            let encoding: hnd::NrrdEncoding = matches.value_of("encoding").unwrap().parse()?;
+            let scan = if std::path::Path::new(input).is_dir() {
+                hnd::read_scan_headers(std::path::Path::new(input))?
+            } else {
+                let header = hnd::read_header(&mut File::open(input)?)?;
+                vec![(std::path::PathBuf::from(input), header)]
+            };
+            let headers: Vec<hnd::hnd_header_t> = scan.iter().map(|(_, h)| h.clone()).collect();
+            let frames = scan.iter().map(|(path, _)| hnd::read_image(&mut File::open(path)?));
+            hnd::write_nrrd(std::path::Path::new(output), &headers, frames, bits, encoding)?;
+            return Ok(());
+        }
+        if std::path::Path::new(input).is_dir() && hnd::has_extension(std::path::Path::new(output), "npz") {
+            let scan = hnd::read_scan_headers(std::path::Path::new(input))?;
+            let headers: Vec<hnd::hnd_header_t> = scan.iter().map(|(_, h)| h.clone()).collect();
+            let frames = scan.iter().map(|(path, _)| hnd::read_image(&mut File::open(path)?));
+            let fout = std::io::BufWriter::new(File::create(output)?);
+            hnd::write_scan_npz(fout, &headers, frames, bits)?;
+            return Ok(());
+        }
+        if std::path::Path::new(input).is_dir() {
+            std::fs::create_dir_all(output)?;
+            let mut ids = hnd::DicomIds::new();
+            for (i, path) in hnd::list_scan_files(std::path::Path::new(input))?.iter().enumerate() {
+                let mut fin = File::open(path)?;
+                let header = hnd::read_header(&mut fin)?;
+                let img = hnd::read_image(&mut fin)?;
+                ids.instance_number = i as u32 + 1;
+                let out = std::path::Path::new(output).join(path.with_extension("dcm").file_name().unwrap());
+                let mut fout = std::io::BufWriter::new(File::create(out)?);
+                hnd::write_rt_image(&mut fout, &header, &img, bits, &ids)?;
+            }
+            return Ok(());
+        }
         let mut fin = File::open(input)?;
 
-        let output = matches.value_of("output").unwrap();
+        if hnd::has_extension(std::path::Path::new(output), "npy") {
+            let img = hnd::read_image(&mut fin)?;
+            let mut fout = std::io::BufWriter::new(File::create(output)?);
+            hnd::write_npy(&mut fout, &img, bits)?;
+            return Ok(());
+        }
+        if hnd::is_dicom_file(std::path::Path::new(output)) {
+            let header = hnd::read_header(&mut fin)?;
+            let img = hnd::read_image(&mut fin)?;
+            let mut fout = std::io::BufWriter::new(File::create(output)?);
+            hnd::write_rt_image(&mut fout, &header, &img, bits, &hnd::DicomIds::new())?;
+            return Ok(());
+        }
+        if hnd::is_tiff_file(std::path::Path::new(output)) {
+            let header = hnd::read_header(&mut fin)?;
+            let img = hnd::read_image(&mut fin)?;
+            let mut fout = std::io::BufWriter::new(File::create(output)?);
+            hnd::write_tiff(&mut fout, &header, &img, bits)?;
+            return Ok(());
+        }
         let mut fout = OpenOptions::new()
             .write(true)
             .append(false)
             .create(true)
+            .truncate(true)
             .open(output)?;
 
         hnd::convert_to_raw(&mut fin, &mut fout)?;
     } else if let Some(matches) = matches.subcommand_matches("raw") {
-        let arg_usize = |x: &str| usize::from_str_radix(matches.value_of(x).unwrap(), 10).unwrap();
+        let arg_usize = |x: &str| matches.value_of(x).unwrap().parse::<usize>().unwrap();
 
-        let arg_u32 = |x| u32::from_str_radix(matches.value_of(x).unwrap(), 10).unwrap();
-        let arg_i64 =
-            |x: &str| -> i64 { i64::from_str_radix(matches.value_of(x).unwrap(), 10).unwrap() };
+        let _arg_i64 = |x: &str| -> i64 { matches.value_of(x).unwrap().parse::<i64>().unwrap() };
         let arg_f64 = |x| f64::from_str(matches.value_of(x).unwrap()).unwrap();
 
         let input = matches.value_of("input").unwrap();
         let mut fin = File::open(input)?;
         let metadata = fin.metadata()?;
         let output = matches.value_of("output").unwrap();
-        let mut fout = OpenOptions::new()
-            .write(true)
-            .append(false)
-            .create(true)
-            .open(output)?;
 
-        let mut hnd_header = hnd::hnd_header_t::new();
-        let width = arg_usize("width");
-        let height = arg_usize("height");
-        hnd_header.SizeX = width as u32;
-        hnd_header.SizeY = height as u32;
+        let tiff = if hnd::is_tiff_file(std::path::Path::new(input)) {
+            let mut buf = Vec::new();
+            fin.read_to_end(&mut buf)?;
+            Some(hnd::read_tiff(&buf)?)
+        } else {
+            for x in &["width", "height", "n_bytes"] {
+                if !matches.is_present(x) {
+                    return Err(format!("--{} is required for RAW input", x.replace("n_", "")).into());
+                }
+            }
+            None
+        };
+
+        let mut hnd_header = match &tiff {
+            Some((header, _)) => header.clone(),
+            None => hnd::hnd_header_t::new(),
+        };
+        if tiff.is_none() {
+            hnd_header.SizeX = arg_usize("width") as u32;
+            hnd_header.SizeY = arg_usize("height") as u32;
+        }
+        let width = hnd_header.SizeX as usize;
+        let height = hnd_header.SizeY as usize;
         if matches.is_present("x_res") {
             let x_res = arg_f64("x_res");
             println!("Read in x_res: {} ...OK", x_res);
@@ -102,42 +906,59 @@ fn main() -> Result<(), Box<dyn Error>> {
             println!("Read in angle: {} ...OK", angle);
             hnd_header.dCTProjectionAngle = angle;
         }
-        let n_bytes: u32 = arg_u32("n_bytes");
-        // let n_images = 
-        //     if matches.is_present("n_images") {
-        //         arg_u32("n_images")
-        //     } else {
-        //         1
-        //     };
-        // println!("Read in n_images: {} ...OK", n_images);
-        // let input_file_size = width * height * n_bytes as usize * n_images as usize;
-        // if input_file_size != metadata.len() as usize {
-        //     panic!("fatal error: width * height * nbytes * n_images is not equal file size.");
-        // }
-
-        let mut buf: Vec<u8> = Vec::new();
-        fin.read_to_end(&mut buf)?;
-        match n_bytes {
-            2 => {
-                buf.shrink_to_fit();
-                let len = buf.len() / 2;
-                let raw_image = unsafe{ Vec::<u16>::from_raw_parts(buf.as_mut_ptr() as *mut u16, len, len) };
-                std::mem::forget(buf);
-                let hnd_data = hnd::encode_u16(&raw_image, width, height).unwrap();
-                fout.write(&hnd_header.to_raw())?;
-                fout.write(&hnd_data)?;
-            }
-            4 => {
-                let mut raw_image: Vec<u32> = unsafe { std::mem::transmute(buf) };
-                let hnd_data = hnd::encode_u32(&raw_image, width, height).unwrap();
-                fout.write(&hnd_header.to_raw())?;
-                fout.write(&hnd_data)?;
-            }
-            _ => {
-                panic!("shouldn't be here.");
+        if let Some((_, img)) = tiff {
+            let hnd_image = hnd::HndImage::new(hnd_header, &img)?;
+            hnd::write_file(&mut File::create(output)?, &hnd_image)?;
+            return Ok(());
+        }
+
+        let n_bytes = arg_usize("n_bytes");
+        let n_images = if matches.is_present("n_images") { arg_usize("n_images") } else { 1 };
+        let input_file_size = width * height * n_bytes * n_images;
+        if input_file_size as u64 != metadata.len() {
+            return Err(format!(
+                "width * height * bytes * n_images is {} but the file has {} bytes",
+                input_file_size,
+                metadata.len()
+            )
+            .into());
+        }
+
+        let table = match matches.value_of("angles") {
+            Some(path) => {
+                let table = hnd::parse_frame_table(&std::fs::read_to_string(path)?)?;
+                if table.len() != n_images {
+                    return Err(format!("{} has {} lines, expected {}", path, table.len(), n_images).into());
+                }
+                table
+            }
+            None => Vec::new(),
+        };
+        let norm_column = if matches.is_present("norm_column") { Some(arg_usize("norm_column")) } else { None };
+        let angle_step = if matches.is_present("angle_step") { arg_f64("angle_step") } else { 0.0 };
+
+        let mut fin = BufReader::new(fin);
+        hnd::split_raw_frames(&mut fin, &hnd_header, n_images, n_bytes, std::path::Path::new(output), |i, header| {
+            match table.get(i) {
+                Some(row) => hnd::apply_frame_row(header, row, norm_column)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+                None => header.dCTProjectionAngle += angle_step * i as f64,
             }
+            Ok(())
+        })?;
+        if n_images > 1 {
+            println!("{} files written to {}_*.hnd", n_images, output.trim_end_matches(".hnd"));
         }
     }
 
     Ok(())
 }
+
+// The unattenuated signal from `--i0` or `--air`, one of which is required.
+fn open_field(matches: &clap::ArgMatches) -> Result<hnd::OpenField, Box<dyn Error>> {
+    match (matches.value_of("i0"), matches.value_of("air")) {
+        (Some(i0), _) => Ok(hnd::OpenField::Constant(f64::from_str(i0)?)),
+        (None, Some(air)) => Ok(hnd::OpenField::load(std::path::Path::new(air))?),
+        (None, None) => Err("--i0 or --air is required".into()),
+    }
+}
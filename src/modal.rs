@@ -63,12 +63,23 @@ pub struct hnd_header_t {
     pub dGating4DInfoY: f64,
     pub dGating4DInfoZ: f64,
     pub dGating4DInfoTime: f64,
+    pub Reserved: Vec<u8>, // the unused rest of the 1024 bytes, kept as is
+}
+
+/// The value Varian writes into numeric header fields that are not set.
+pub(crate) const UNSET: f64 = 99999999.0;
+
+/// Whether a numeric header field holds a value: finite and not `UNSET`.
+pub(crate) fn is_set(v: f64) -> bool {
+    v.is_finite() && v != UNSET
 }
 
 // pub type hnd_header_buf_t = [u8; 1024];
 pub type hnd_header_buf_t = Vec<u8>;
+#[allow(dead_code)]
 pub type hnd_data_t = Vec<u8>;
 
+#[allow(dead_code)]
 #[derive(Default)]
 struct hnd_t {
     header: hnd_header_t,
@@ -146,6 +157,7 @@ impl hnd_header_t {
         buf.write_f64(self.dGating4DInfoY);
         buf.write_f64(self.dGating4DInfoZ);
         buf.write_f64(self.dGating4DInfoTime);
+        buf.write_bytes(&self.Reserved);
         // );
         // let mut array: [u8; 1024] = [0; 1024];
         // array.copy_from_slice(&buf.data[0..1024]);
@@ -217,6 +229,7 @@ impl hnd_header_t {
             dGating4DInfoY: buf.read_f64(),          //f64,
             dGating4DInfoZ: buf.read_f64(),          //f64,
             dGating4DInfoTime: buf.read_f64(),       //f64,
+            Reserved: buf.read_bytes(1024 - HEADER_FIELDS_SIZE),
         }
     }
 }
@@ -295,6 +308,188 @@ impl std::fmt::Display for hnd_header_t {
     }
 }
 
+// Field level access to the header by name. The list below follows the
+// on-disk order of the fields, the same order used by to_raw/from_raw.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderValue {
+    Str(String),
+    U32(u32),
+    F64(f64),
+}
+
+impl std::fmt::Display for HeaderValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderValue::Str(v) => write!(f, "{}", v),
+            HeaderValue::U32(v) => write!(f, "{}", v),
+            // plain decimals where they are short, otherwise the shortest
+            // form that reads back the same, e.g. `1e-307` for the subnormal
+            // leftovers in the gating fields
+            HeaderValue::F64(v) if *v == 0.0 || (1e-5..1e16).contains(&v.abs()) => write!(f, "{}", v),
+            HeaderValue::F64(v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeaderFieldError {
+    UnknownField(String),
+    TypeMismatch(String),
+    InvalidValue(String, String),
+}
+
+impl std::fmt::Display for HeaderFieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderFieldError::UnknownField(name) => write!(f, "unknown header field `{}`", name),
+            HeaderFieldError::TypeMismatch(name) => {
+                write!(f, "value has the wrong type for header field `{}`", name)
+            }
+            HeaderFieldError::InvalidValue(name, why) => {
+                write!(f, "invalid value for header field `{}`: {}", name, why)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderFieldError {}
+
+impl From<HeaderFieldError> for std::io::Error {
+    fn from(e: HeaderFieldError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+macro_rules! header_fields {
+    ($($name:ident: $kind:ident[$size:expr],)*) => {
+        /// Names of all header fields in on-disk order.
+        pub const HEADER_FIELDS: &[&str] = &[$(stringify!($name)),*];
+
+        /// Number of bytes taken by the named fields, the rest of the 1024 byte
+        /// header is reserved.
+        pub const HEADER_FIELDS_SIZE: usize = 0 $(+ $size)*;
+
+        impl hnd_header_t {
+            /// Look up a single header field by its name, e.g. `"dCTProjectionAngle"`.
+            pub fn field(&self, name: &str) -> Result<HeaderValue, HeaderFieldError> {
+                match name {
+                    $(stringify!($name) => Ok(HeaderValue::$kind(self.$name.clone())),)*
+                    _ => Err(HeaderFieldError::UnknownField(name.to_string())),
+                }
+            }
+
+            /// Size of a field in the raw header, in bytes.
+            pub fn field_size(name: &str) -> Result<usize, HeaderFieldError> {
+                match name {
+                    $(stringify!($name) => Ok($size),)*
+                    _ => Err(HeaderFieldError::UnknownField(name.to_string())),
+                }
+            }
+
+            /// Set a single header field. The value must have the type of the
+            /// field, strings must be single byte characters (Latin-1) and fit
+            /// into the raw field.
+            pub fn set_field(&mut self, name: &str, value: HeaderValue) -> Result<(), HeaderFieldError> {
+                let size = Self::field_size(name)?;
+                if let HeaderValue::Str(ref v) = value {
+                    if v.chars().any(|c| c > '\u{ff}') {
+                        let why = "not a single byte (Latin-1) string".to_string();
+                        return Err(HeaderFieldError::InvalidValue(name.to_string(), why));
+                    }
+                    if v.chars().count() > size {
+                        let why = format!("longer than {} bytes", size);
+                        return Err(HeaderFieldError::InvalidValue(name.to_string(), why));
+                    }
+                }
+                match (name, value) {
+                    $((stringify!($name), HeaderValue::$kind(v)) => {
+                        self.$name = v;
+                        Ok(())
+                    })*
+                    _ => Err(HeaderFieldError::TypeMismatch(name.to_string())),
+                }
+            }
+        }
+    };
+}
+
+header_fields! {
+    sFileType: Str[32],
+    FileLength: U32[4],
+    chasChecksumSpec: Str[4],
+    nCheckSum: U32[4],
+    sCreationDate: Str[8],
+    sCreationTime: Str[8],
+    sPatientID: Str[16],
+    nPatientSer: U32[4],
+    sSeriesID: Str[16],
+    nSeriesSer: U32[4],
+    sSliceID: Str[16],
+    nSliceSer: U32[4],
+    SizeX: U32[4],
+    SizeY: U32[4],
+    dSliceZPos: F64[8],
+    sModality: Str[16],
+    nWindow: U32[4],
+    nLevel: U32[4],
+    nPixelOffset: U32[4],
+    sImageType: Str[4],
+    dGantryRtn: F64[8],
+    dSAD: F64[8],
+    dSFD: F64[8],
+    dCollX1: F64[8],
+    dCollX2: F64[8],
+    dCollY1: F64[8],
+    dCollY2: F64[8],
+    dCollRtn: F64[8],
+    dFieldX: F64[8],
+    dFieldY: F64[8],
+    dBladeX1: F64[8],
+    dBladeX2: F64[8],
+    dBladeY1: F64[8],
+    dBladeY2: F64[8],
+    dIDUPosLng: F64[8],
+    dIDUPosLat: F64[8],
+    dIDUPosVrt: F64[8],
+    dIDUPosRtn: F64[8],
+    dPatientSupportAngle: F64[8],
+    dTableTopEccentricAngle: F64[8],
+    dCouchVrt: F64[8],
+    dCouchLng: F64[8],
+    dCouchLat: F64[8],
+    dIDUResolutionX: F64[8],
+    dIDUResolutionY: F64[8],
+    dImageResolutionX: F64[8],
+    dImageResolutionY: F64[8],
+    dEnergy: F64[8],
+    dDoseRate: F64[8],
+    dXRayKV: F64[8],
+    dXRayMA: F64[8],
+    dMetersetExposure: F64[8],
+    dAcqAdjustment: F64[8],
+    dCTProjectionAngle: F64[8],
+    dCTNormChamber: F64[8],
+    dGatingTimeTag: F64[8],
+    dGating4DInfoX: F64[8],
+    dGating4DInfoY: F64[8],
+    dGating4DInfoZ: F64[8],
+    dGating4DInfoTime: F64[8],
+}
+
+impl hnd_header_t {
+    /// Parse `text` according to the type of the field and set it.
+    pub fn set_field_str(&mut self, name: &str, text: &str) -> Result<(), HeaderFieldError> {
+        let invalid = |e: &dyn std::fmt::Display| HeaderFieldError::InvalidValue(name.to_string(), e.to_string());
+        let value = match self.field(name)? {
+            HeaderValue::Str(_) => HeaderValue::Str(text.to_string()),
+            HeaderValue::U32(_) => HeaderValue::U32(text.trim().parse().map_err(|e| invalid(&e))?),
+            HeaderValue::F64(_) => HeaderValue::F64(text.trim().parse().map_err(|e| invalid(&e))?),
+        };
+        self.set_field(name, value)
+    }
+}
+
 struct Buf {
     data: Vec<u8>,
     pos: usize,
@@ -302,9 +497,7 @@ struct Buf {
 
 impl Buf {
     fn new() -> Self {
-        let mut data = Vec::<u8>::with_capacity(1024);
-        data.resize(1024, 0);
-        Self { data: data, pos: 0 }
+        Self { data: vec![0; 1024], pos: 0 }
     }
 
     // fn from(d: &[u8]) -> Self {
@@ -319,15 +512,25 @@ impl Buf {
         Self { data: d, pos: 0 }
     }
 
+    // One char per byte (Latin-1), so that any bytes left in a string field,
+    // including garbage after the terminating NUL, are written back as read.
     fn read_string(&mut self, size: usize) -> String {
         let (start, end) = (self.pos, self.pos + size);
         self.pos += size;
-        std::str::from_utf8(&self.data[start..end])
-            .unwrap()
+        self.data[start..end]
+            .iter()
+            .map(|&b| b as char)
+            .collect::<String>()
             .trim_end_matches('\u{0}')
             .to_string()
     }
 
+    fn read_bytes(&mut self, size: usize) -> Vec<u8> {
+        let (start, end) = (self.pos, self.pos + size);
+        self.pos += size;
+        self.data[start..end].to_vec()
+    }
+
     fn read_u32(&mut self) -> u32 {
         let size: usize = 4;
         let (start, end) = (self.pos, self.pos + size);
@@ -348,14 +551,21 @@ impl Buf {
         self.data[self.pos..]
             .iter_mut()
             .zip(
-                data.as_bytes()
-                    .iter()
-                    .chain(Vec::with_capacity(size).iter())
+                data.chars()
+                    .map(|c| u8::try_from(c).unwrap_or(b'?'))
                     .take(size),
             )
-            .for_each(|(to, from)| *to = *from);
+            .for_each(|(to, from)| *to = from);
         self.pos += size;
     }
+    fn write_bytes(&mut self, data: &[u8]) {
+        self.data[self.pos..]
+            .iter_mut()
+            .zip(data.iter())
+            .for_each(|(to, from)| *to = *from);
+        self.pos += data.len();
+    }
+
     fn write_u32(&mut self, data: u32) {
         let size: usize = 4;
         self.data[self.pos..]
@@ -378,11 +588,34 @@ impl Buf {
 #[derive(Debug)]
 pub enum ImageConvError {
     SomeErr,
+    SizeMismatch,
+    OutOfBounds,
+    /// The HND stream needs at least one column and two rows.
+    TooSmall,
+}
+
+impl std::fmt::Display for ImageConvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageConvError::SomeErr => write!(f, "image conversion failed"),
+            ImageConvError::SizeMismatch => write!(f, "image sizes do not match"),
+            ImageConvError::OutOfBounds => write!(f, "region is outside of the image"),
+            ImageConvError::TooSmall => write!(f, "HND images need at least 1 column and 2 rows"),
+        }
+    }
+}
+
+impl std::error::Error for ImageConvError {}
+
+impl From<ImageConvError> for std::io::Error {
+    fn from(e: ImageConvError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+    }
 }
 
 // decode HND image data into raw image data
 
-struct LutIter<'a> {
+pub(crate) struct LutIter<'a> {
     table: &'a [u8],
     size: usize,
     pos: usize,
@@ -391,10 +624,10 @@ struct LutIter<'a> {
 }
 
 impl<'a> LutIter<'a> {
-    fn new(part: &'a [u8], size: usize) -> LutIter<'a> {
+    pub(crate) fn new(part: &'a [u8], size: usize) -> LutIter<'a> {
         LutIter {
             table: part,
-            size: size,
+            size,
             pos: 0,
             idx: 0,
             offset: 0,
@@ -438,6 +671,7 @@ impl<'a> Iterator for LutIter<'a> {
     }
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn decode(raw: &Vec<u8>, width: usize, height: usize) -> Result<Vec<u32>, ImageConvError> {
     let mut output = Vec::with_capacity(width * height * 4);
 
@@ -458,7 +692,7 @@ pub fn decode(raw: &Vec<u8>, width: usize, height: usize) -> Result<Vec<u32>, Im
     }
 
     let lut_size = width * (height - 1) - 1;
-    let mut lut_iter = LutIter::new(&lut, lut_size);
+    let mut lut_iter = LutIter::new(lut, lut_size);
 
     // Decompress the rest
     let mut i = width + 1;
@@ -483,7 +717,7 @@ pub fn decode(raw: &Vec<u8>, width: usize, height: usize) -> Result<Vec<u32>, Im
             Some(2) => {
                 let end = start + 4;
                 pos += 4;
-                i32::from_ne_bytes(raw[start..end].try_into().unwrap()).into()
+                i32::from_ne_bytes(raw[start..end].try_into().unwrap())
             }
             None => {
                 break;
@@ -509,20 +743,20 @@ pub fn decode(raw: &Vec<u8>, width: usize, height: usize) -> Result<Vec<u32>, Im
 
 // encode raw image data into HND image data
 fn compress_data_impl(hnd_data: &mut Vec<u8>, diff: i64, lut_off: &mut usize, lut_idx: &mut usize) {
-    let mut v: u8 = 0;
-    if diff >= i8::min_value().into() && diff <= i8::max_value().into() {
+    let v: u8;
+    if diff >= i8::MIN.into() && diff <= i8::MAX.into() {
         (diff as i8)
             .to_ne_bytes()
             .iter()
             .for_each(|x| hnd_data.push(*x));
         v = 0;
-    } else if diff >= i16::min_value().into() && diff <= i16::max_value().into() {
+    } else if diff >= i16::MIN.into() && diff <= i16::MAX.into() {
         (diff as i16)
             .to_ne_bytes()
             .iter()
             .for_each(|x| hnd_data.push(*x));
         v = 1;
-    } else if diff >= i32::min_value().into() && diff <= i32::max_value().into() {
+    } else if diff >= i32::MIN.into() && diff <= i32::MAX.into() {
         (diff as i32)
             .to_ne_bytes()
             .iter()
@@ -557,11 +791,18 @@ fn compress_data_impl(hnd_data: &mut Vec<u8>, diff: i64, lut_off: &mut usize, lu
     }
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn encode_u32(
     img: &Vec<u32>,
     width: usize,
     height: usize,
 ) -> Result<Vec<u8>, ImageConvError> {
+    if width == 0 || height < 2 {
+        return Err(ImageConvError::TooSmall);
+    }
+    if img.len() < width * height {
+        return Err(ImageConvError::SizeMismatch);
+    }
     // Initialize the hnd_data_t structure
     const PIXEL_SIZE: usize = std::mem::size_of::<u32>();
     let lut_size: usize = (height - 1) * width / 4;
@@ -590,11 +831,18 @@ pub fn encode_u32(
     Ok(hnd_data)
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn encode_u16(
     img: &Vec<u16>,
     width: usize,
     height: usize,
 ) -> Result<Vec<u8>, ImageConvError> {
+    if width == 0 || height < 2 {
+        return Err(ImageConvError::TooSmall);
+    }
+    if img.len() < width * height {
+        return Err(ImageConvError::SizeMismatch);
+    }
     // Initialize the hnd_data_t structure
     const PIXEL_SIZE: usize = std::mem::size_of::<u16>();
     let lut_size: usize = (height - 1) * width / 4;
@@ -623,11 +871,12 @@ pub fn encode_u16(
     Ok(hnd_data)
 }
 
+#[allow(dead_code)]
 impl hnd_t {
     pub fn new(header: hnd_header_t, data: Vec<u8>) -> hnd_t {
         hnd_t {
-            header: header,
-            data: data
+            header,
+            data
         }
     }
 
@@ -643,7 +892,7 @@ impl hnd_t {
         vec![1, 2 , 3]
     }
 
-    pub fn from_raw(raw_data: Vec<u8>, width: usize, height: usize) -> hnd_t {
+    pub fn from_raw(_raw_data: Vec<u8>, _width: usize, _height: usize) -> hnd_t {
         hnd_t {
             ..Default::default()
         }
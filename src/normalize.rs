@@ -0,0 +1,229 @@
+// From detector signal to line integrals, the input of a reconstruction:
+// scatter correction on the signal, log normalization by the unattenuated
+// signal, then beam-hardening correction on the line integrals.
+
+use std::fs::File;
+use std::io;
+use std::io::{Seek, Write};
+use std::path::Path;
+
+use crate::hardening::BeamHardeningTable;
+use crate::modal::{hnd_header_t, is_set};
+use crate::npy::{npy_header, NpzWriter};
+use crate::scatter::{detector_pitch, scatter_correct, ScatterModel, ScatterOptions};
+use crate::{RawImage, Size2D};
+
+/// Line integrals `ln(i0 / I)` with the unattenuated signal `i0` of each
+/// pixel. Signal at or below zero is clamped to a millionth of `i0`.
+pub fn line_integrals(img: &RawImage<f64>, i0: &[f64]) -> RawImage<f64> {
+    RawImage {
+        width: img.width,
+        height: img.height,
+        data: img.data.iter().zip(i0).map(|(v, i0)| (i0 / v.max(1e-6 * i0)).ln()).collect(),
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// The norm chamber reading of a frame, which the detector signal is
+// proportional to but on a scale of its own.
+fn chamber(h: &hnd_header_t) -> Option<f64> {
+    Some(h.dCTNormChamber).filter(|c| is_set(*c) && *c > 0.0)
+}
+
+/// The unattenuated signal, what the detector reads in an open field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpenField {
+    /// The same reading for every pixel of every frame.
+    Constant(f64),
+    /// An air scan: the open field image per unit norm chamber reading.
+    /// Each frame scales it by its own `dCTNormChamber`.
+    Air(RawImage<f64>),
+}
+
+impl OpenField {
+    /// The air scan in an HND file or scan directory, the frames averaged
+    /// after dividing each by its norm chamber reading. Every frame needs
+    /// a chamber reading and every pixel a positive signal.
+    pub fn load(path: &Path) -> Result<OpenField, io::Error> {
+        let files = if path.is_dir() { crate::list_scan_files(path)? } else { vec![path.to_path_buf()] };
+        let mut sum: Option<RawImage<f64>> = None;
+        for file in &files {
+            let mut fin = File::open(file)?;
+            let header = crate::read_header(&mut fin)?;
+            let c = chamber(&header)
+                .ok_or_else(|| invalid(format!("{}: air scan frame without norm chamber reading", file.display())))?;
+            let img = crate::read_image(&mut fin)?;
+            match &mut sum {
+                None => sum = Some(RawImage::new(img.width, img.height, img.data.iter().map(|v| *v as f64 / c).collect())?),
+                Some(sum) if (sum.width, sum.height) == (img.width, img.height) => {
+                    sum.data.iter_mut().zip(&img.data).for_each(|(s, v)| *s += *v as f64 / c);
+                }
+                Some(sum) => return Err(invalid(format!("{} is not {}x{}", file.display(), sum.width, sum.height))),
+            }
+        }
+        let mut air = sum.ok_or_else(|| invalid(format!("{} has no frames", path.display())))?;
+        if air.data.iter().any(|v| *v <= 0.0) {
+            return Err(invalid(format!("{}: air scan has pixels without signal", path.display())));
+        }
+        air.data.iter_mut().for_each(|v| *v /= files.len() as f64);
+        Ok(OpenField::Air(air))
+    }
+
+    /// The unattenuated signal of each pixel of a `width` x `height` frame
+    /// with header `h`.
+    pub fn frame_i0(&self, h: &hnd_header_t, width: usize, height: usize) -> Result<Vec<f64>, io::Error> {
+        match self {
+            OpenField::Constant(i0) if i0.is_finite() && *i0 > 0.0 => Ok(vec![*i0; width * height]),
+            OpenField::Constant(i0) => Err(invalid(format!("unattenuated signal {} is not positive", i0))),
+            OpenField::Air(air) => {
+                if (air.width, air.height) != (width, height) {
+                    return Err(invalid(format!("the air scan is {}x{}, the frame {}x{}", air.width, air.height, width, height)));
+                }
+                let c = chamber(h).ok_or_else(|| invalid("frame without norm chamber reading to scale the air scan by".to_string()))?;
+                Ok(air.data.iter().map(|v| v * c).collect())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NormalizeOptions {
+    /// Unattenuated signal, required.
+    pub open_field: Option<OpenField>,
+    pub scatter: Option<(ScatterModel, ScatterOptions)>,
+    /// Beam-hardening corrections, the one for the `dXRayKV` of the frame
+    /// and `bowtie` is used.
+    pub beam_hardening: Option<BeamHardeningTable>,
+    pub bowtie: String,
+}
+
+/// The line integrals of one frame with all corrections of `opts` applied.
+/// The scatter model sees the mean unattenuated signal of the frame.
+pub fn normalize_frame(header: &hnd_header_t, img: &RawImage<u32>, opts: &NormalizeOptions) -> Result<RawImage<f64>, io::Error> {
+    let i0 = opts
+        .open_field
+        .as_ref()
+        .ok_or_else(|| invalid("no unattenuated signal, give i0 or an air scan".to_string()))?
+        .frame_i0(header, img.width, img.height)?;
+    let mut signal: RawImage<f64> = img.convert();
+    if let Some((model, scatter_opts)) = &opts.scatter {
+        let mean = i0.iter().sum::<f64>() / i0.len().max(1) as f64;
+        signal = scatter_correct(model, &signal, detector_pitch(header)?, mean, scatter_opts)?;
+    }
+    let mut p = line_integrals(&signal, &i0);
+    if let Some(table) = &opts.beam_hardening {
+        table.select(header.dXRayKV, &opts.bowtie)?.correct(&mut p);
+    }
+    Ok(p)
+}
+
+fn f32_bytes(img: &RawImage<f64>) -> Vec<u8> {
+    img.data.iter().flat_map(|v| (*v as f32).to_le_bytes().to_vec()).collect()
+}
+
+/// Write the line integrals of a HND file as `(height, width)` float32 .npy.
+pub fn write_normalized_npy<W: Write>(w: &mut W, header: &hnd_header_t, img: &RawImage<u32>, opts: &NormalizeOptions) -> Result<(), io::Error> {
+    let p = normalize_frame(header, img, opts)?;
+    w.write_all(&npy_header("<f4", &[p.height, p.width]))?;
+    w.write_all(&f32_bytes(&p))
+}
+
+/// Write the line integrals of the scan in `dir` as .npz: `projections`
+/// `(n, height, width)` float32 and `angles` in degree.
+pub fn write_normalized_npz<W: Write + Seek>(w: W, dir: &Path, opts: &NormalizeOptions) -> Result<usize, io::Error> {
+    let scan = crate::read_scan_headers(dir)?;
+    let (width, height) = scan
+        .first()
+        .map(|(_, h)| (h.width(), h.height()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "scan has no frames"))?;
+    let mut npz = NpzWriter::new(w);
+    npz.start_array("projections", "<f4", &[scan.len(), height, width])?;
+    for (path, header) in &scan {
+        if (header.width(), header.height()) != (width, height) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not {}x{}", path.display(), width, height),
+            ));
+        }
+        let img = crate::read_image(&mut File::open(path)?)?;
+        npz.write_data(&f32_bytes(&normalize_frame(header, &img, opts)?))?;
+    }
+    let angles: Vec<u8> = scan.iter().flat_map(|(_, h)| h.dCTProjectionAngle.to_le_bytes().to_vec()).collect();
+    npz.add_array("angles", "<f8", &[scan.len()], &angles)?;
+    npz.finish()?;
+    Ok(scan.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modal::UNSET;
+    use crate::hardening::BeamHardening;
+
+    #[test]
+    fn test_normalize_frame() {
+        let mut header = hnd_header_t::new();
+        header.dCTNormChamber = 1000.0;
+        header.dXRayKV = 125.0;
+        let img = RawImage::new(3, 1, vec![1000, 500, 0]).unwrap();
+        // the norm chamber does not stand in for the unattenuated signal
+        assert!(normalize_frame(&header, &img, &NormalizeOptions::default()).is_err());
+        let opts = NormalizeOptions {
+            open_field: Some(OpenField::Constant(1000.0)),
+            ..Default::default()
+        };
+        let p = normalize_frame(&header, &img, &opts).unwrap();
+        assert_eq!(p.data[0], 0.0);
+        assert!((p.data[1] - 2f64.ln()).abs() < 1e-12);
+        assert!((p.data[2] - 1e6f64.ln()).abs() < 1e-9);
+
+        let opts = NormalizeOptions {
+            open_field: Some(OpenField::Constant(2000.0)),
+            beam_hardening: Some(BeamHardeningTable {
+                entries: vec![BeamHardening {
+                    kv: 125.0,
+                    bowtie: "full".to_string(),
+                    coefficients: vec![1.0, 0.5],
+                }],
+            }),
+            bowtie: "full".to_string(),
+            ..Default::default()
+        };
+        let p = normalize_frame(&header, &img, &opts).unwrap();
+        let q = 2f64.ln();
+        assert!((p.data[0] - (q + 0.5 * q * q)).abs() < 1e-12);
+        header.dXRayKV = 100.0;
+        assert!(normalize_frame(&header, &img, &opts).is_err());
+    }
+
+    #[test]
+    fn test_open_field() {
+        let mut header = hnd_header_t::new();
+        header.dCTNormChamber = 2000.0;
+        let air = OpenField::Air(RawImage::new(3, 1, vec![1.0, 0.5, 0.25]).unwrap());
+        assert_eq!(air.frame_i0(&header, 3, 1).unwrap(), [2000.0, 1000.0, 500.0]);
+        assert!(air.frame_i0(&header, 1, 3).is_err());
+        assert!(OpenField::Constant(0.0).frame_i0(&header, 3, 1).is_err());
+        header.dCTNormChamber = UNSET;
+        assert!(air.frame_i0(&header, 3, 1).is_err());
+
+        // two air frames at different chamber readings average per unit reading
+        let dir = tempfile::tempdir().unwrap();
+        for (i, c) in [1000.0, 2000.0].iter().enumerate() {
+            let mut h = hnd_header_t::new();
+            h.dCTNormChamber = *c;
+            let img = RawImage::new(4, 2, (1..=8).map(|v| v * *c as u32).collect()).unwrap();
+            let hnd_image = crate::HndImage::new(h, &img).unwrap();
+            crate::write_file(&mut File::create(dir.path().join(format!("air_{}.hnd", i))).unwrap(), &hnd_image).unwrap();
+        }
+        let air = OpenField::load(dir.path()).unwrap();
+        assert_eq!(air, OpenField::Air(RawImage::new(4, 2, (1..=8).map(f64::from).collect()).unwrap()));
+        header.dCTNormChamber = 10.0;
+        assert_eq!(air.frame_i0(&header, 4, 2).unwrap()[7], 80.0);
+        // a dead pixel leaves no signal to normalize by
+        assert!(OpenField::load(Path::new("test/test_data_1.hnd")).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+// NumPy .npy arrays and .npz archives (an uncompressed zip of .npy files).
+
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::deflate::crc32_update;
+use crate::modal::hnd_header_t;
+use crate::{pixel_bytes, RawImage, Size2D};
+
+/// The .npy header for an array of `descr` (a NumPy type string such as
+/// `<u4`) with the given shape, padded so the data starts 64 byte aligned.
+pub fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+    let shape = match dims.len() {
+        1 => format!("({},)", dims[0]),
+        _ => format!("({})", dims.join(", ")),
+    };
+    let mut dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+    // magic, version and header length take 10 bytes
+    while (10 + dict.len() + 1) % 64 != 0 {
+        dict.push(' ');
+    }
+    dict.push('\n');
+    let mut out = b"\x93NUMPY\x01\x00".to_vec();
+    out.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+    out.extend_from_slice(dict.as_bytes());
+    out
+}
+
+fn pixel_descr(bits: u16) -> &'static str {
+    if bits == 16 {
+        "<u2"
+    } else {
+        "<u4"
+    }
+}
+
+/// Write `img` as a `(height, width)` array with 16 or 32 bits per pixel.
+pub fn write_npy<W: Write>(w: &mut W, img: &RawImage<u32>, bits: u16) -> Result<(), io::Error> {
+    let data = pixel_bytes(img, bits)?;
+    w.write_all(&npy_header(pixel_descr(bits), &[img.height(), img.width()]))?;
+    w.write_all(&data)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "npz archives larger than 4 GiB are not supported")
+}
+
+struct Entry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    size: u32,
+}
+
+/// Writes an .npz archive one array at a time. Entries are stored without
+/// compression, their size and CRC are filled in when the entry is finished.
+pub struct NpzWriter<W: Write + Seek> {
+    w: W,
+    entries: Vec<Entry>,
+    current: Option<Entry>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> NpzWriter<W> {
+    pub fn new(w: W) -> NpzWriter<W> {
+        NpzWriter {
+            w,
+            entries: Vec::new(),
+            current: None,
+            pos: 0,
+        }
+    }
+
+    /// Start the array `name` (without `.npy`), its data must follow with
+    /// `write_data`.
+    pub fn start_array(&mut self, name: &str, descr: &str, shape: &[usize]) -> Result<(), io::Error> {
+        self.finish_array()?;
+        let name = format!("{}.npy", name);
+        let offset = self.pos;
+        let mut header = Vec::new();
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&0u16.to_le_bytes()); // flags
+        header.extend_from_slice(&0u16.to_le_bytes()); // stored
+        header.extend_from_slice(&0u16.to_le_bytes()); // time
+        header.extend_from_slice(&0x21u16.to_le_bytes()); // date 1980-01-01
+        header.extend_from_slice(&[0; 12]); // crc and sizes, filled in later
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        self.write(&header)?;
+        if offset > u32::MAX as u64 {
+            return Err(too_large());
+        }
+        self.current = Some(Entry {
+            name,
+            offset: offset as u32,
+            crc: 0,
+            size: 0,
+        });
+        self.write_data(&npy_header(descr, shape))
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let entry = self
+            .current
+            .as_mut()
+            .ok_or_else(|| io::Error::other("no array started"))?;
+        entry.crc = crc32_update(entry.crc, data);
+        let size = entry.size as u64 + data.len() as u64;
+        if size > u32::MAX as u64 {
+            return Err(too_large());
+        }
+        entry.size = size as u32;
+        self.write(data)
+    }
+
+    /// Write a complete array.
+    pub fn add_array(&mut self, name: &str, descr: &str, shape: &[usize], data: &[u8]) -> Result<(), io::Error> {
+        self.start_array(name, descr, shape)?;
+        self.write_data(data)
+    }
+
+    /// Write the central directory and return the underlying writer.
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.finish_array()?;
+        let start = self.pos;
+        let entries = std::mem::take(&mut self.entries);
+        for e in &entries {
+            let mut dir = Vec::new();
+            dir.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            dir.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            dir.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            dir.extend_from_slice(&[0; 4]); // flags, stored
+            dir.extend_from_slice(&0u16.to_le_bytes());
+            dir.extend_from_slice(&0x21u16.to_le_bytes());
+            dir.extend_from_slice(&e.crc.to_le_bytes());
+            dir.extend_from_slice(&e.size.to_le_bytes());
+            dir.extend_from_slice(&e.size.to_le_bytes());
+            dir.extend_from_slice(&(e.name.len() as u16).to_le_bytes());
+            dir.extend_from_slice(&[0; 12]); // extra, comment, disk, attributes
+            dir.extend_from_slice(&e.offset.to_le_bytes());
+            dir.extend_from_slice(e.name.as_bytes());
+            self.write(&dir)?;
+        }
+        let size = self.pos - start;
+        if self.pos > u32::MAX as u64 {
+            return Err(too_large());
+        }
+        let mut end = Vec::new();
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // disk numbers
+        end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        end.extend_from_slice(&(size as u32).to_le_bytes());
+        end.extend_from_slice(&(start as u32).to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.write(&end)?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+
+    fn finish_array(&mut self) -> Result<(), io::Error> {
+        if let Some(e) = self.current.take() {
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&e.crc.to_le_bytes());
+            fields.extend_from_slice(&e.size.to_le_bytes());
+            fields.extend_from_slice(&e.size.to_le_bytes());
+            self.w.seek(SeekFrom::Start(e.offset as u64 + 14))?;
+            self.w.write_all(&fields)?;
+            self.w.seek(SeekFrom::Start(self.pos))?;
+            self.entries.push(e);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.w.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// A 0-d unicode array (`<U`) holding `text`, as loaded by
+/// `str(np.load(...)[name])`.
+pub fn unicode_bytes(text: &str) -> (String, Vec<u8>) {
+    let mut data = Vec::new();
+    for c in text.chars() {
+        data.extend_from_slice(&(c as u32).to_le_bytes());
+    }
+    (format!("<U{}", text.chars().count().max(1)), data)
+}
+
+/// Write a scan as .npz: `projections` holds the `(n, height, width)` stack,
+/// `angles`, `norm_chamber` and `gating_time_tag` the per frame
+/// `dCTProjectionAngle`, `dCTNormChamber` and `dGatingTimeTag`, and `header`
+/// the JSON list of all frame headers. `frames` yields header and image of
+/// each frame in turn, so the stack is never held in memory.
+pub fn write_scan_npz<W, I>(w: W, headers: &[hnd_header_t], frames: I, bits: u16) -> Result<W, io::Error>
+where
+    W: Write + Seek,
+    I: IntoIterator<Item = Result<RawImage<u32>, io::Error>>,
+{
+    let first = headers
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "scan has no frames"))?;
+    let (width, height) = (first.width(), first.height());
+    let mut npz = NpzWriter::new(w);
+    npz.start_array("projections", pixel_descr(bits), &[headers.len(), height, width])?;
+    let mut n = 0;
+    for img in frames {
+        let img = img?;
+        if (img.width(), img.height()) != (width, height) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frame {} is {}x{}, the first frame {}x{}",
+                    n,
+                    img.width(),
+                    img.height(),
+                    width,
+                    height
+                ),
+            ));
+        }
+        npz.write_data(&pixel_bytes(&img, bits)?)?;
+        n += 1;
+    }
+    if n != headers.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "number of frames and headers differ"));
+    }
+
+    let column = |f: &dyn Fn(&hnd_header_t) -> f64| {
+        headers.iter().flat_map(|h| f(h).to_le_bytes().to_vec()).collect::<Vec<u8>>()
+    };
+    npz.add_array("angles", "<f8", &[n], &column(&|h| h.dCTProjectionAngle))?;
+    npz.add_array("norm_chamber", "<f8", &[n], &column(&|h| h.dCTNormChamber))?;
+    npz.add_array("gating_time_tag", "<f8", &[n], &column(&|h| h.dGatingTimeTag))?;
+
+    let mut json = Vec::new();
+    for h in headers {
+        json.push(crate::header_to_json(h, &[])?);
+    }
+    let (descr, data) = unicode_bytes(&format!("[{}]", json.join(", ")));
+    npz.add_array("header", &descr, &[], &data)?;
+    npz.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_npy_header() {
+        let h = npy_header("<u4", &[768, 1024]);
+        assert_eq!(h.len() % 64, 0);
+        assert_eq!(&h[..8], b"\x93NUMPY\x01\x00");
+        let dict = String::from_utf8(h[10..].to_vec()).unwrap();
+        assert!(dict.starts_with("{'descr': '<u4', 'fortran_order': False, 'shape': (768, 1024), }"));
+        assert!(dict.ends_with(" \n"));
+        assert!(String::from_utf8_lossy(&npy_header("<f8", &[3])).contains("'shape': (3,)"));
+        assert!(String::from_utf8_lossy(&npy_header("<U2", &[])).contains("'shape': ()"));
+    }
+
+    #[test]
+    fn test_write_scan_npz() {
+        let mut h = hnd_header_t::new();
+        h.SizeX = 2;
+        h.SizeY = 1;
+        let headers = vec![h.clone(), h];
+        let frames = vec![RawImage::new(2, 1, vec![1, 2]), RawImage::new(2, 1, vec![3, 4])];
+        let frames = frames.into_iter().map(|f| f.map_err(io::Error::from));
+        let out = write_scan_npz(Cursor::new(Vec::new()), &headers, frames, 16).unwrap().into_inner();
+
+        // local header of the first entry with its CRC filled in
+        assert_eq!(&out[..4], b"PK\x03\x04");
+        assert_eq!(&out[30..45], b"projections.npy");
+        let size = u32::from_le_bytes([out[18], out[19], out[20], out[21]]) as usize;
+        let data = &out[45..45 + size];
+        assert_eq!(&data[data.len() - 8..], &[1, 0, 2, 0, 3, 0, 4, 0]);
+        assert_eq!(u32::from_le_bytes([out[14], out[15], out[16], out[17]]), crate::deflate::crc32(data));
+        // end of central directory with 5 entries
+        let end = &out[out.len() - 22..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        assert_eq!(end[10], 5);
+    }
+}
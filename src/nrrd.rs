@@ -0,0 +1,260 @@
+// NRRD export of single projections and whole scans, attached (.nrrd) or
+// detached (.nhdr plus data file), raw or gzip encoded.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::check::angle_step;
+use crate::deflate::GzipWriter;
+use crate::modal::{hnd_header_t, HeaderValue, HEADER_FIELDS};
+use crate::pixel_bytes;
+use crate::{RawImage, Size2D};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NrrdEncoding {
+    Raw,
+    Gzip,
+}
+
+impl FromStr for NrrdEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(NrrdEncoding::Raw),
+            "gzip" => Ok(NrrdEncoding::Gzip),
+            _ => Err(format!("unknown encoding {}, expected raw or gzip", s)),
+        }
+    }
+}
+
+// Key/value pairs may not span lines, so newlines, backslashes and other
+// control characters are escaped.
+fn escape_value(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn value_text(v: &HeaderValue) -> String {
+    match v {
+        HeaderValue::Str(s) => escape_value(s),
+        v => v.to_string(),
+    }
+}
+
+/// Mean step between consecutive projection angles in degree, 0 for a
+/// single frame.
+pub fn angular_step(headers: &[hnd_header_t]) -> f64 {
+    if headers.len() < 2 {
+        return 0.0;
+    }
+    let total: f64 = headers
+        .windows(2)
+        .map(|w| angle_step(w[0].dCTProjectionAngle, w[1].dCTProjectionAngle))
+        .sum();
+    total / (headers.len() - 1) as f64
+}
+
+/// The NRRD header for `headers.len()` frames. Fields that are the same in
+/// all frames are written once as `hnd_<field>`, numbers that vary as a
+/// space separated list and strings that vary as `hnd_<field>[<frame>]`.
+pub fn nrrd_header(
+    headers: &[hnd_header_t],
+    bits: u16,
+    encoding: NrrdEncoding,
+    data_file: Option<&str>,
+) -> Result<String, io::Error> {
+    let first = headers
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no frames"))?;
+    let (sx, sy) = (first.dImageResolutionX, first.dImageResolutionY);
+    let mut out = String::from("NRRD0005\n");
+    out.push_str("# written by hnd\n");
+    out.push_str(if bits == 16 { "type: uint16\n" } else { "type: uint32\n" });
+    if headers.len() == 1 {
+        out.push_str("dimension: 2\n");
+        out.push_str("space dimension: 2\n");
+        out.push_str(&format!("sizes: {} {}\n", first.width(), first.height()));
+        out.push_str(&format!("space directions: ({},0) (0,{})\n", sx, sy));
+        out.push_str("space units: \"mm\" \"mm\"\n");
+        out.push_str("space origin: (0,0)\n");
+    } else {
+        // the stack axis is the gantry angle, not a direction in the
+        // detector plane, so it has no space direction of its own
+        out.push_str("dimension: 3\n");
+        out.push_str("space dimension: 2\n");
+        out.push_str(&format!("sizes: {} {} {}\n", first.width(), first.height(), headers.len()));
+        out.push_str(&format!("space directions: ({},0) (0,{}) none\n", sx, sy));
+        out.push_str("kinds: domain domain list\n");
+        out.push_str("space units: \"mm\" \"mm\"\n");
+        out.push_str("space origin: (0,0)\n");
+    }
+    out.push_str("endian: little\n");
+    out.push_str(match encoding {
+        NrrdEncoding::Raw => "encoding: raw\n",
+        NrrdEncoding::Gzip => "encoding: gzip\n",
+    });
+    if let Some(name) = data_file {
+        out.push_str(&format!("data file: {}\n", name));
+    }
+    if headers.len() > 1 {
+        out.push_str(&format!("angular_step:={}\n", angular_step(headers)));
+    }
+
+    for name in HEADER_FIELDS {
+        let values = headers
+            .iter()
+            .map(|h| h.field(name))
+            .collect::<Result<Vec<HeaderValue>, _>>()?;
+        if values.iter().all(|v| *v == values[0]) {
+            out.push_str(&format!("hnd_{}:={}\n", name, value_text(&values[0])));
+        } else if let HeaderValue::Str(_) = values[0] {
+            for (i, v) in values.iter().enumerate() {
+                out.push_str(&format!("hnd_{}[{}]:={}\n", name, i, value_text(v)));
+            }
+        } else {
+            let list: Vec<String> = values.iter().map(value_text).collect();
+            out.push_str(&format!("hnd_{}:={}\n", name, list.join(" ")));
+        }
+    }
+    Ok(out)
+}
+
+fn write_frames<W, I>(w: W, frames: I, bits: u16, encoding: NrrdEncoding) -> Result<(), io::Error>
+where
+    W: Write,
+    I: IntoIterator<Item = Result<RawImage<u32>, io::Error>>,
+{
+    match encoding {
+        NrrdEncoding::Raw => {
+            let mut w = w;
+            for img in frames {
+                w.write_all(&pixel_bytes(&img?, bits)?)?;
+            }
+            w.flush()
+        }
+        NrrdEncoding::Gzip => {
+            let mut gz = GzipWriter::new(w)?;
+            for img in frames {
+                gz.write_all(&pixel_bytes(&img?, bits)?)?;
+            }
+            gz.finish().map(|_| ())
+        }
+    }
+}
+
+/// Write a projection (one header and frame) or a scan as NRRD. The header
+/// is detached if `path` ends in `.nhdr`, with the data next to it in
+/// `<stem>.raw` or `<stem>.raw.gz`. Gzip data is framed in stored blocks,
+/// without compression.
+pub fn write_nrrd<I>(
+    path: &Path,
+    headers: &[hnd_header_t],
+    frames: I,
+    bits: u16,
+    encoding: NrrdEncoding,
+) -> Result<(), io::Error>
+where
+    I: IntoIterator<Item = Result<RawImage<u32>, io::Error>>,
+{
+    let (width, height) = headers.first().map_or((0, 0), |h| (h.width(), h.height()));
+    let mut n = 0;
+    let frames = frames.into_iter().map(|img| {
+        let img = img?;
+        n += 1;
+        if (img.width(), img.height()) != (width, height) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame {} is {}x{}, expected {}x{}", n - 1, img.width(), img.height(), width, height),
+            ));
+        }
+        Ok(img)
+    });
+
+    let detached = path
+        .extension()
+        .is_some_and(|e| e.to_string_lossy().eq_ignore_ascii_case("nhdr"));
+    if detached {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let data_name = match encoding {
+            NrrdEncoding::Raw => format!("{}.raw", stem),
+            NrrdEncoding::Gzip => format!("{}.raw.gz", stem),
+        };
+        let header = nrrd_header(headers, bits, encoding, Some(&data_name))?;
+        std::fs::write(path, header)?;
+        let data = BufWriter::new(File::create(path.with_file_name(&data_name))?);
+        write_frames(data, frames, bits, encoding)?;
+    } else {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(nrrd_header(headers, bits, encoding, None)?.as_bytes())?;
+        w.write_all(b"\n")?;
+        write_frames(w, frames, bits, encoding)?;
+    }
+    if n != headers.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "number of frames and headers differ"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nrrd_header() {
+        let mut a = hnd_header_t::new();
+        a.SizeX = 2;
+        a.SizeY = 1;
+        a.dImageResolutionX = 0.25;
+        a.dImageResolutionY = 0.5;
+        a.dCTProjectionAngle = 359.0;
+        a.sPatientID = "a\nb".to_string();
+        let mut b = a.clone();
+        b.dCTProjectionAngle = 1.0;
+
+        let header = nrrd_header(&[a.clone()], 32, NrrdEncoding::Raw, None).unwrap();
+        assert!(header.contains("sizes: 2 1\n"));
+        assert!(header.contains("space directions: (0.25,0) (0,0.5)\n"));
+        assert!(header.contains("hnd_sPatientID:=a\\nb\n"));
+
+        let header = nrrd_header(&[a, b], 16, NrrdEncoding::Gzip, Some("x.raw.gz")).unwrap();
+        assert!(header.contains("type: uint16\n"));
+        assert!(header.contains("sizes: 2 1 2\n"));
+        assert!(header.contains("space directions: (0.25,0) (0,0.5) none\n"));
+        assert!(header.contains("kinds: domain domain list\n"));
+        assert!(header.contains("angular_step:=2\n"));
+        assert!(header.contains("hnd_dCTProjectionAngle:=359 1\n"));
+        assert!(header.contains("data file: x.raw.gz\n"));
+    }
+
+    #[test]
+    fn test_write_nrrd() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut h = hnd_header_t::new();
+        h.SizeX = 2;
+        h.SizeY = 1;
+        let img = || Ok(RawImage::new(2, 1, vec![1, 2]).unwrap());
+
+        let path = dir.path().join("a.nrrd");
+        write_nrrd(&path, &[h.clone()], vec![img()], 32, NrrdEncoding::Raw).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(&data[data.len() - 10..], b"\n\n\x01\x00\x00\x00\x02\x00\x00\x00");
+
+        let path = dir.path().join("b.nhdr");
+        write_nrrd(&path, &[h.clone(), h], vec![img(), img()], 16, NrrdEncoding::Gzip).unwrap();
+        let data = std::fs::read(dir.path().join("b.raw.gz")).unwrap();
+        assert_eq!(&data[..2], &[0x1f, 0x8b]);
+        assert_eq!(&data[data.len() - 4..], &8u32.to_le_bytes());
+    }
+}
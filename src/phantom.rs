@@ -0,0 +1,449 @@
+// Analytic phantoms and their cone-beam projections, for synthetic scans
+// with known ground truth.
+//
+// The phantom lives in a patient fixed frame in mm with z along the rotation
+// axis. At projection angle 0 the source sits at -y and the detector at +y;
+// the gantry rotates counter-clockwise seen from +z. Detector columns run
+// along the rotated x axis and rows from +z down.
+
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::modal::{hnd_header_t, is_set, UNSET};
+use crate::{HndImage, RawImage};
+
+/// Linear attenuation coefficient of water at CBCT energies, in 1/mm.
+pub const MU_WATER: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Ellipsoid,
+    /// Elliptic cylinder along z, `axes[2]` is the half height.
+    Cylinder,
+}
+
+/// One shape of a phantom. Attenuation adds up where shapes overlap.
+#[derive(Debug, Clone)]
+pub struct Solid {
+    pub shape: Shape,
+    /// Centre in mm.
+    pub center: [f64; 3],
+    /// Semi-axes in mm.
+    pub axes: [f64; 3],
+    /// Rotation about z in degree.
+    pub phi: f64,
+    /// Attenuation in 1/mm.
+    pub mu: f64,
+}
+
+impl Solid {
+    /// Length in mm of the part of the ray `from + t * dir` (`dir` of unit
+    /// length) inside the solid.
+    fn chord(&self, from: [f64; 3], dir: [f64; 3]) -> f64 {
+        let (sin, cos) = self.phi.to_radians().sin_cos();
+        let rel = [from[0] - self.center[0], from[1] - self.center[1], from[2] - self.center[2]];
+        // into the frame of the solid, scaled to the unit sphere or cylinder
+        let p = [
+            (rel[0] * cos + rel[1] * sin) / self.axes[0],
+            (-rel[0] * sin + rel[1] * cos) / self.axes[1],
+            rel[2] / self.axes[2],
+        ];
+        let d = [
+            (dir[0] * cos + dir[1] * sin) / self.axes[0],
+            (-dir[0] * sin + dir[1] * cos) / self.axes[1],
+            dir[2] / self.axes[2],
+        ];
+        let n = match self.shape {
+            Shape::Ellipsoid => 3,
+            Shape::Cylinder => 2,
+        };
+        let a: f64 = d[..n].iter().map(|x| x * x).sum();
+        let b: f64 = 2.0 * (0..n).map(|i| p[i] * d[i]).sum::<f64>();
+        let c: f64 = p[..n].iter().map(|x| x * x).sum::<f64>() - 1.0;
+        let disc = b * b - 4.0 * a * c;
+        let (mut t0, mut t1) = if a == 0.0 {
+            // parallel to the cylinder axis
+            if c > 0.0 {
+                return 0.0;
+            }
+            (f64::NEG_INFINITY, f64::INFINITY)
+        } else if disc <= 0.0 {
+            return 0.0;
+        } else {
+            ((-b - disc.sqrt()) / (2.0 * a), (-b + disc.sqrt()) / (2.0 * a))
+        };
+        if self.shape == Shape::Cylinder {
+            // clip to the slab |z| <= 1
+            if d[2] == 0.0 {
+                if p[2].abs() > 1.0 {
+                    return 0.0;
+                }
+            } else {
+                let (z0, z1) = ((-1.0 - p[2]) / d[2], (1.0 - p[2]) / d[2]);
+                t0 = t0.max(z0.min(z1));
+                t1 = t1.min(z0.max(z1));
+            }
+        }
+        (t1 - t0).max(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhantomKind {
+    SheppLogan,
+    Water,
+}
+
+impl FromStr for PhantomKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shepp-logan" => Ok(PhantomKind::SheppLogan),
+            "water" => Ok(PhantomKind::Water),
+            _ => Err(format!("unknown phantom {}, expected shepp-logan or water", s)),
+        }
+    }
+}
+
+/// The 3D modified Shepp-Logan phantom scaled to `radius` mm, with the skull
+/// at twice and the brain at the attenuation of water. The tilts of the
+/// original are left out, only rotations about z are kept.
+pub fn shepp_logan(radius: f64) -> Vec<Solid> {
+    // intensity, semi-axes, centre, rotation about z
+    let table: [(f64, [f64; 3], [f64; 3], f64); 10] = [
+        (2.0, [0.69, 0.92, 0.81], [0.0, 0.0, 0.0], 0.0),
+        (-1.0, [0.6624, 0.874, 0.78], [0.0, -0.0184, 0.0], 0.0),
+        (-0.2, [0.11, 0.31, 0.22], [0.22, 0.0, 0.0], -18.0),
+        (-0.2, [0.16, 0.41, 0.28], [-0.22, 0.0, 0.0], 18.0),
+        (0.1, [0.21, 0.25, 0.41], [0.0, 0.35, -0.15], 0.0),
+        (0.1, [0.046, 0.046, 0.05], [0.0, 0.1, 0.25], 0.0),
+        (0.1, [0.046, 0.046, 0.05], [0.0, -0.1, 0.25], 0.0),
+        (0.1, [0.046, 0.023, 0.05], [-0.08, -0.605, 0.0], 0.0),
+        (0.1, [0.023, 0.023, 0.02], [0.0, -0.606, 0.0], 0.0),
+        (0.1, [0.023, 0.046, 0.02], [0.06, -0.605, 0.0], 0.0),
+    ];
+    table
+        .iter()
+        .map(|(value, axes, center, phi)| Solid {
+            shape: Shape::Ellipsoid,
+            center: [center[0] * radius, center[1] * radius, center[2] * radius],
+            axes: [axes[0] * radius, axes[1] * radius, axes[2] * radius],
+            phi: *phi,
+            mu: value * MU_WATER,
+        })
+        .collect()
+}
+
+/// A water cylinder of `radius` mm and equal height with four inserts of a
+/// fifth of the radius: bone, air, fat and lung.
+pub fn water_cylinder(radius: f64) -> Vec<Solid> {
+    let mut solids = vec![Solid {
+        shape: Shape::Cylinder,
+        center: [0.0, 0.0, 0.0],
+        axes: [radius, radius, radius],
+        phi: 0.0,
+        mu: MU_WATER,
+    }];
+    // attenuation relative to water
+    let inserts = [0.9, -1.0, -0.1, -0.7];
+    for (i, delta) in inserts.iter().enumerate() {
+        let angle = (i as f64 * 90.0).to_radians();
+        let r = 0.6 * radius;
+        solids.push(Solid {
+            shape: Shape::Cylinder,
+            center: [r * angle.cos(), r * angle.sin(), 0.0],
+            axes: [0.2 * radius, 0.2 * radius, 0.8 * radius],
+            phi: 0.0,
+            mu: delta * MU_WATER,
+        });
+    }
+    solids
+}
+
+pub fn phantom(kind: PhantomKind, radius: f64) -> Vec<Solid> {
+    match kind {
+        PhantomKind::SheppLogan => shepp_logan(radius),
+        PhantomKind::Water => water_cylinder(radius),
+    }
+}
+
+/// Cone-beam geometry of a projection in mm, as far as the header gives it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConeBeam {
+    pub sad: f64,
+    pub sdd: f64,
+    /// Detector pixel pitch.
+    pub pitch: [f64; 2],
+    /// Detector offset, lateral and longitudinal.
+    pub offset: [f64; 2],
+    pub size: [usize; 2],
+}
+
+impl ConeBeam {
+    /// Distances from `dSAD` and `dSFD`, or the detector position
+    /// (`dIDUPosVrt`) when `dSFD` is unset. Lengths are in cm in the header.
+    pub fn from_header(h: &hnd_header_t) -> Result<ConeBeam, io::Error> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("header has no {}", what));
+        if !is_set(h.dSAD) || h.dSAD <= 0.0 {
+            return Err(invalid("source to axis distance"));
+        }
+        let sdd = if is_set(h.dSFD) && h.dSFD > h.dSAD {
+            h.dSFD
+        } else if is_set(h.dIDUPosVrt) && h.dSAD - h.dIDUPosVrt > h.dSAD {
+            h.dSAD - h.dIDUPosVrt
+        } else {
+            return Err(invalid("source to detector distance"));
+        };
+        let pitch = |idu: f64, image: f64| {
+            if is_set(idu) && idu > 0.0 {
+                Some(idu)
+            } else if is_set(image) && image > 0.0 {
+                Some(image * sdd / h.dSAD)
+            } else {
+                None
+            }
+        };
+        let px = pitch(h.dIDUResolutionX, h.dImageResolutionX).ok_or_else(|| invalid("pixel spacing"))?;
+        let py = pitch(h.dIDUResolutionY, h.dImageResolutionY).ok_or_else(|| invalid("pixel spacing"))?;
+        let offset = |v: f64| if is_set(v) { v * 10.0 } else { 0.0 };
+        Ok(ConeBeam {
+            sad: h.dSAD * 10.0,
+            sdd: sdd * 10.0,
+            pitch: [px, py],
+            offset: [offset(h.dIDUPosLat), offset(h.dIDUPosLng)],
+            size: [h.SizeX as usize, h.SizeY as usize],
+        })
+    }
+
+    /// Detector coordinates (u along the columns, v up the rows, in mm) where
+    /// the ray through `point` hits at projection angle `angle` (degree).
+    pub fn project_point(&self, point: [f64; 3], angle: f64) -> [f64; 2] {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let d = [point[0] - self.sad * sin, point[1] + self.sad * cos, point[2]];
+        let depth = -d[0] * sin + d[1] * cos;
+        let scale = self.sdd / depth;
+        [(d[0] * cos + d[1] * sin) * scale, d[2] * scale]
+    }
+
+    /// Column and row of the detector coordinates `uv` (mm).
+    pub fn to_pixel(&self, uv: [f64; 2]) -> [f64; 2] {
+        [
+            (uv[0] - self.offset[0]) / self.pitch[0] + (self.size[0] as f64 - 1.0) / 2.0,
+            (self.size[1] as f64 - 1.0) / 2.0 - (uv[1] - self.offset[1]) / self.pitch[1],
+        ]
+    }
+
+    /// Line integrals of the attenuation through `solids` for every detector
+    /// pixel at projection angle `angle` (degree).
+    pub fn project(&self, solids: &[Solid], angle: f64) -> Vec<f64> {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let source = [self.sad * sin, -self.sad * cos, 0.0];
+        let toward = [-sin, cos, 0.0];
+        let u_axis = [cos, sin, 0.0];
+        let centre = [
+            source[0] + self.sdd * toward[0],
+            source[1] + self.sdd * toward[1],
+            0.0,
+        ];
+        let (w, h) = (self.size[0], self.size[1]);
+        let mut out = Vec::with_capacity(w * h);
+        for row in 0..h {
+            let v = ((h as f64 - 1.0) / 2.0 - row as f64) * self.pitch[1] + self.offset[1];
+            for col in 0..w {
+                let u = (col as f64 - (w as f64 - 1.0) / 2.0) * self.pitch[0] + self.offset[0];
+                let pixel = [centre[0] + u * u_axis[0], centre[1] + u * u_axis[1], v];
+                let dir = [pixel[0] - source[0], pixel[1] - source[1], pixel[2] - source[2]];
+                let len = (dir[0] * dir[0] + dir[1] * dir[1] + dir[2] * dir[2]).sqrt();
+                let dir = [dir[0] / len, dir[1] / len, dir[2] / len];
+                out.push(solids.iter().map(|s| s.mu * s.chord(source, dir)).sum());
+            }
+        }
+        out
+    }
+}
+
+/// A small xorshift generator, good enough for noise and reproducible from a
+/// seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in (0, 1).
+    pub fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+    }
+
+    pub fn normal(&mut self) -> f64 {
+        (-2.0 * self.uniform().ln()).sqrt() * (2.0 * std::f64::consts::PI * self.uniform()).cos()
+    }
+
+    /// Poisson distributed with mean `lambda`, normal approximation for
+    /// large means.
+    pub fn poisson(&mut self, lambda: f64) -> f64 {
+        if lambda > 30.0 {
+            return (lambda + lambda.sqrt() * self.normal()).round().max(0.0);
+        }
+        let limit = (-lambda).exp();
+        let (mut k, mut p) = (0.0, self.uniform());
+        while p > limit {
+            k += 1.0;
+            p *= self.uniform();
+        }
+        k
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulateOptions {
+    pub phantom: PhantomKind,
+    /// Phantom radius in mm.
+    pub radius: f64,
+    pub n_images: usize,
+    pub start_angle: f64,
+    /// Gantry rotation covered by the scan, in degree.
+    pub arc: f64,
+    /// Unattenuated counts per pixel.
+    pub flux: f64,
+    pub noise: bool,
+    pub seed: u64,
+}
+
+impl Default for SimulateOptions {
+    fn default() -> Self {
+        SimulateOptions {
+            phantom: PhantomKind::SheppLogan,
+            radius: 100.0,
+            n_images: 360,
+            start_angle: 0.0,
+            arc: 360.0,
+            flux: 50000.0,
+            noise: false,
+            seed: 1,
+        }
+    }
+}
+
+/// Header of a 1024x768 panel at 100 cm SAD and 150 cm SDD, the geometry of
+/// a standard Varian CBCT.
+pub fn default_scan_header() -> hnd_header_t {
+    let mut h = hnd_header_t::new();
+    h.sFileType = "VARIAN_VA_INTERNAL_HND_1.0".to_string();
+    h.SizeX = 1024;
+    h.SizeY = 768;
+    h.dSAD = 100.0;
+    h.dSFD = 150.0;
+    h.dIDUPosLng = 0.0;
+    h.dIDUPosLat = 0.0;
+    h.dIDUPosVrt = -50.0;
+    h.dIDUResolutionX = 0.388;
+    h.dIDUResolutionY = 0.388;
+    h.dImageResolutionX = 0.388 * 100.0 / 150.0;
+    h.dImageResolutionY = 0.388 * 100.0 / 150.0;
+    h.dXRayKV = 100.0;
+    h.dCollX1 = UNSET;
+    h.dCollX2 = UNSET;
+    h.dCollY1 = UNSET;
+    h.dCollY2 = UNSET;
+    h.dCollRtn = UNSET;
+    h.dPatientSupportAngle = UNSET;
+    h
+}
+
+/// Simulate a scan of the phantom with the geometry of `template` and write
+/// it to `dir` as `Proj_00000.hnd`, ... Returns the number of files written.
+pub fn simulate_scan(dir: &Path, template: &hnd_header_t, opts: &SimulateOptions) -> Result<usize, io::Error> {
+    let geometry = ConeBeam::from_header(template)?;
+    let solids = phantom(opts.phantom, opts.radius);
+    let mut rng = Rng::new(opts.seed);
+    std::fs::create_dir_all(dir)?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64());
+    let secs = now as i64;
+    let date = crate::shift_date("19700101", secs.div_euclid(86400)).unwrap_or_default();
+    let time = secs.rem_euclid(86400);
+    // one minute for a full rotation
+    let interval = 60.0 / opts.n_images.max(1) as f64 * opts.arc.abs() / 360.0;
+
+    for i in 0..opts.n_images {
+        let angle = opts.start_angle + opts.arc * i as f64 / opts.n_images as f64;
+        let counts: Vec<u32> = geometry
+            .project(&solids, angle)
+            .iter()
+            .map(|p| {
+                let expected = opts.flux * (-p).exp();
+                let value = if opts.noise { rng.poisson(expected) } else { expected.round() };
+                value.min(u32::MAX as f64) as u32
+            })
+            .collect();
+        let img = RawImage::new(geometry.size[0], geometry.size[1], counts)?;
+
+        let mut h = template.clone();
+        h.dCTProjectionAngle = angle;
+        // the kV source is mounted 90 degree ahead of the MV gantry
+        h.dGantryRtn = (angle + 90.0).rem_euclid(360.0);
+        h.dCTNormChamber = opts.flux;
+        h.dGatingTimeTag = now + interval * i as f64;
+        h.sCreationDate = date.clone();
+        h.sCreationTime = format!("{:02}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60);
+        let hnd_image = HndImage::new(h, &img)?;
+        crate::write_file(&mut std::fs::File::create(dir.join(format!("Proj_{:05}.hnd", i)))?, &hnd_image)?;
+    }
+    Ok(opts.n_images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chord() {
+        let sphere = Solid {
+            shape: Shape::Ellipsoid,
+            center: [0.0, 0.0, 0.0],
+            axes: [10.0, 10.0, 10.0],
+            phi: 0.0,
+            mu: 1.0,
+        };
+        assert!((sphere.chord([-50.0, 0.0, 0.0], [1.0, 0.0, 0.0]) - 20.0).abs() < 1e-9);
+        assert!((sphere.chord([-50.0, 6.0, 0.0], [1.0, 0.0, 0.0]) - 16.0).abs() < 1e-9);
+        assert_eq!(sphere.chord([-50.0, 11.0, 0.0], [1.0, 0.0, 0.0]), 0.0);
+
+        let cylinder = Solid {
+            shape: Shape::Cylinder,
+            axes: [10.0, 10.0, 5.0],
+            ..sphere
+        };
+        // along the axis only the height counts
+        assert!((cylinder.chord([0.0, 0.0, -50.0], [0.0, 0.0, 1.0]) - 10.0).abs() < 1e-9);
+        assert!((cylinder.chord([-50.0, 0.0, 4.0], [1.0, 0.0, 0.0]) - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_project() {
+        let mut h = default_scan_header();
+        h.SizeX = 3;
+        h.SizeY = 1;
+        let geometry = ConeBeam::from_header(&h).unwrap();
+        let p = geometry.project(&water_cylinder(100.0), 0.0);
+        // the central ray crosses 200 mm of water and 40 mm each of the air
+        // and the lung insert
+        assert!((p[1] - (200.0 - 40.0 - 0.7 * 40.0) * MU_WATER).abs() < 1e-9);
+
+        let mut rng = Rng::new(7);
+        let mean = (0..2000).map(|_| rng.poisson(4.0)).sum::<f64>() / 2000.0;
+        assert!((mean - 4.0).abs() < 0.2);
+    }
+}
@@ -0,0 +1,247 @@
+// Windowed grayscale previews of decoded images, written as PNG or PGM.
+
+use std::io;
+use std::io::Write;
+
+use crate::deflate::{crc32_update, zlib_stored};
+use crate::modal::hnd_header_t;
+use crate::{RawImage, Size2D};
+
+/// Display window: pixel values from `level - width / 2` to
+/// `level + width / 2` are mapped to black to white.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub width: f64,
+    pub level: f64,
+}
+
+impl Window {
+    /// The window stored in the header (`nWindow`/`nLevel`). Many files carry
+    /// garbage in these fields, so the window is only used if it overlaps the
+    /// value range of `img`.
+    pub fn from_header(h: &hnd_header_t, img: &RawImage<u32>) -> Option<Window> {
+        let window = Window {
+            width: h.nWindow as f64,
+            level: h.nLevel as f64,
+        };
+        let min = *img.data().iter().min()? as f64;
+        let max = *img.data().iter().max()? as f64;
+        let (lo, hi) = window.range();
+        if window.width > 0.0 && lo < max && hi > min {
+            Some(window)
+        } else {
+            None
+        }
+    }
+
+    /// A window spanning the `percent` to `100 - percent` percentiles of `img`.
+    pub fn percentile(img: &RawImage<u32>, percent: f64) -> Window {
+        let mut values = img.data().to_vec();
+        if values.is_empty() {
+            return Window { width: 1.0, level: 0.0 };
+        }
+        values.sort_unstable();
+        let rank = |p: f64| {
+            let i = (p / 100.0 * (values.len() - 1) as f64).round() as usize;
+            values[i.min(values.len() - 1)] as f64
+        };
+        let percent = percent.clamp(0.0, 50.0);
+        let (lo, hi) = (rank(percent), rank(100.0 - percent));
+        Window {
+            width: (hi - lo).max(1.0),
+            level: (lo + hi) / 2.0,
+        }
+    }
+
+    fn range(&self) -> (f64, f64) {
+        (self.level - self.width / 2.0, self.level + self.width / 2.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+impl BitDepth {
+    fn max_value(self) -> f64 {
+        match self {
+            BitDepth::Eight => 255.0,
+            BitDepth::Sixteen => 65535.0,
+        }
+    }
+}
+
+/// Map `img` through `window` to 8 or 16 bit gray values.
+pub fn render(img: &RawImage<u32>, window: &Window, depth: BitDepth, invert: bool) -> RawImage<u16> {
+    let (lo, hi) = window.range();
+    let max = depth.max_value();
+    let data = img
+        .data()
+        .iter()
+        .map(|v| {
+            let t = ((*v as f64 - lo) / (hi - lo)).clamp(0.0, 1.0);
+            let t = if invert { 1.0 - t } else { t };
+            (t * max).round() as u16
+        })
+        .collect();
+    RawImage::new(img.width(), img.height(), data).unwrap()
+}
+
+/// Shrink `img` by averaging `factor` x `factor` blocks, dropping incomplete
+/// blocks at the right and bottom edge.
+pub fn shrink(img: &RawImage<u32>, factor: usize) -> RawImage<u32> {
+    // a side shorter than the factor shrinks to a single pixel
+    let (fx, fy) = (factor.clamp(1, img.width().max(1)), factor.clamp(1, img.height().max(1)));
+    let (w, h) = (img.width() / fx, img.height() / fy);
+    let mut data = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let mut sum = 0u64;
+            for dy in 0..fy {
+                let row = (y * fy + dy) * img.width();
+                for dx in 0..fx {
+                    sum += img.data()[row + x * fx + dx] as u64;
+                }
+            }
+            data.push((sum / (fx * fy) as u64) as u32);
+        }
+    }
+    RawImage::new(w, h, data).unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub struct PreviewOptions {
+    /// Fixed window, takes precedence over everything else.
+    pub window: Option<Window>,
+    /// Window from percentiles of the image instead of the header.
+    pub percentile: Option<f64>,
+    pub depth: BitDepth,
+    pub invert: bool,
+    /// Shrink the image until neither side is larger than this.
+    pub max_size: Option<usize>,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        PreviewOptions {
+            window: None,
+            percentile: None,
+            depth: BitDepth::Eight,
+            invert: false,
+            max_size: None,
+        }
+    }
+}
+
+// Percentile used when neither a window nor a usable header window is given.
+const DEFAULT_PERCENTILE: f64 = 1.0;
+
+/// Render a preview of `img`. Without an explicit window or percentile the
+/// header window is used, falling back to the 1-99% percentile window.
+pub fn make_preview(h: &hnd_header_t, img: &RawImage<u32>, opts: &PreviewOptions) -> RawImage<u16> {
+    let window = match (opts.window, opts.percentile) {
+        (Some(w), _) => w,
+        (None, Some(p)) => Window::percentile(img, p),
+        (None, None) => Window::from_header(h, img)
+            .unwrap_or_else(|| Window::percentile(img, DEFAULT_PERCENTILE)),
+    };
+    match opts.max_size {
+        Some(size) if size > 0 && img.width().max(img.height()) > size => {
+            let factor = img.width().max(img.height()).div_ceil(size);
+            render(&shrink(img, factor), &window, opts.depth, opts.invert)
+        }
+        _ => render(img, &window, opts.depth, opts.invert),
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32_update(crc32_update(0, kind), data);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Write a rendered image as a grayscale PNG.
+pub fn write_png<W: Write>(w: &mut W, img: &RawImage<u16>, depth: BitDepth) -> Result<(), io::Error> {
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(img.width() as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(img.height() as u32).to_be_bytes());
+    ihdr.push(if depth == BitDepth::Eight { 8 } else { 16 });
+    ihdr.extend_from_slice(&[0, 0, 0, 0]); // gray, deflate, no filter, no interlace
+
+    let mut scanlines = Vec::new();
+    for row in img.data().chunks(img.width().max(1)) {
+        scanlines.push(0); // filter type none
+        for v in row {
+            match depth {
+                BitDepth::Eight => scanlines.push(*v as u8),
+                BitDepth::Sixteen => scanlines.extend_from_slice(&v.to_be_bytes()),
+            }
+        }
+    }
+
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    png_chunk(&mut out, b"IHDR", &ihdr);
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&scanlines));
+    png_chunk(&mut out, b"IEND", &[]);
+    w.write_all(&out)
+}
+
+/// Write a rendered image as a binary PGM (P5).
+pub fn write_pgm<W: Write>(w: &mut W, img: &RawImage<u16>, depth: BitDepth) -> Result<(), io::Error> {
+    write!(w, "P5\n{} {}\n{}\n", img.width(), img.height(), depth.max_value())?;
+    let mut buf = Vec::new();
+    for v in img.data() {
+        match depth {
+            BitDepth::Eight => buf.push(*v as u8),
+            BitDepth::Sixteen => buf.extend_from_slice(&v.to_be_bytes()),
+        }
+    }
+    w.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let img = RawImage::new(4, 1, vec![0, 100, 150, 300]).unwrap();
+        let window = Window { width: 100.0, level: 150.0 };
+        assert_eq!(render(&img, &window, BitDepth::Eight, false).data(), [0, 0, 128, 255]);
+        assert_eq!(render(&img, &window, BitDepth::Eight, true).data(), [255, 255, 128, 0]);
+
+        let window = Window::percentile(&img, 0.0);
+        assert_eq!(window, Window { width: 300.0, level: 150.0 });
+    }
+
+    #[test]
+    fn test_shrink() {
+        let img = RawImage::new(5, 2, vec![1, 3, 5, 7, 9, 1, 3, 5, 7, 9]).unwrap();
+        let small = shrink(&img, 2);
+        assert_eq!((small.width(), small.height()), (2, 1));
+        assert_eq!(small.data(), [2, 6]);
+        // a long thin strip keeps one row
+        let small = shrink(&img, 4);
+        assert_eq!((small.width(), small.height()), (1, 1));
+        assert_eq!(small.data(), [4]);
+    }
+
+    #[test]
+    fn test_write_png() {
+        let img = RawImage::new(2, 2, vec![0, 255, 255, 0]).unwrap();
+        let mut out = Vec::new();
+        write_png(&mut out, &img, BitDepth::Eight).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[out.len() - 8..out.len() - 4], b"IEND");
+
+        let mut out = Vec::new();
+        write_pgm(&mut out, &img, BitDepth::Sixteen).unwrap();
+        assert_eq!(&out[..14], b"P5\n2 2\n65535\n\x00");
+        assert_eq!(out.len(), 13 + 8);
+    }
+}
@@ -0,0 +1,366 @@
+// Acquisition geometry of a scan at a glance, and whether it can be
+// reconstructed.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::check::{angle_step, Issue, Severity};
+use crate::modal::{hnd_header_t, is_set};
+use crate::phantom::ConeBeam;
+
+/// Smallest and largest value of a header field over the scan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Range {
+    fn of(values: impl Iterator<Item = f64>) -> Option<Range> {
+        values.filter(|v| is_set(*v)).fold(None, |r, v| match r {
+            None => Some(Range { min: v, max: v }),
+            Some(r) => Some(Range {
+                min: r.min.min(v),
+                max: r.max.max(v),
+            }),
+        })
+    }
+
+    pub fn spread(&self) -> f64 {
+        self.max - self.min
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    /// Steps larger than this (degree) are gaps, `None` for five times the
+    /// median step.
+    pub gap_threshold: Option<f64>,
+    /// Allowed spread of `dSAD`, `dSFD` and `dIDUPosLat` in cm.
+    pub geometry_tolerance: f64,
+    /// Allowed deviation of the gantry speed between two frames from the
+    /// median, as a fraction.
+    pub speed_tolerance: f64,
+}
+
+impl Default for ReportOptions {
+    fn default() -> ReportOptions {
+        ReportOptions {
+            gap_threshold: None,
+            geometry_tolerance: 0.1,
+            speed_tolerance: 0.5,
+        }
+    }
+}
+
+/// Summary of the acquisition geometry of a scan, frames in acquisition
+/// order.
+#[derive(Debug, Clone)]
+pub struct ScanReport {
+    pub frames: usize,
+    pub start_angle: f64,
+    pub end_angle: f64,
+    /// Rotation covered in degree.
+    pub arc: f64,
+    /// +1 for increasing `dCTProjectionAngle`, -1 for decreasing, 0 if the
+    /// gantry does not move.
+    pub direction: f64,
+    pub mean_step: f64,
+    pub max_step: f64,
+    /// Frames with the same angle as the frame before.
+    pub duplicates: Vec<PathBuf>,
+    /// Frames that step against the rotation direction.
+    pub out_of_order: Vec<PathBuf>,
+    /// Frames after a step above the gap threshold, with the step.
+    pub gaps: Vec<(PathBuf, f64)>,
+    /// Seconds from the first to the last frame, from `dGatingTimeTag`.
+    pub duration: Option<f64>,
+    /// Mean gantry speed in degree per second.
+    pub gantry_speed: Option<f64>,
+    /// Slowest and fastest speed between two frames.
+    pub speed_range: Option<Range>,
+    /// Full fan angle of the detector in degree.
+    pub fan_angle: Option<f64>,
+    pub sad: Option<Range>,
+    pub sfd: Option<Range>,
+    pub idu_pos_lat: Option<Range>,
+    pub issues: Vec<Issue>,
+}
+
+impl ScanReport {
+    pub fn new(dir: &Path, opts: &ReportOptions) -> Result<ScanReport, io::Error> {
+        let scan = crate::read_scan_headers(dir)?;
+        let headers: Vec<hnd_header_t> = scan.iter().map(|(_, h)| h.clone()).collect();
+        let order = crate::time_order(&headers);
+        let paths: Vec<&Path> = order.iter().map(|i| scan[*i].0.as_path()).collect();
+        let headers: Vec<&hnd_header_t> = order.iter().map(|i| &headers[*i]).collect();
+        Ok(ScanReport::from_headers(dir, &paths, &headers, opts))
+    }
+
+    /// Report on the frames `headers` of the scan in `dir`, in acquisition
+    /// order.
+    pub fn from_headers(dir: &Path, paths: &[&Path], headers: &[&hnd_header_t], opts: &ReportOptions) -> ScanReport {
+        let mut issues = Vec::new();
+        let mut report = |severity, path: &Path, message: String| {
+            issues.push(Issue {
+                severity,
+                path: path.to_path_buf(),
+                message,
+            })
+        };
+        let angles: Vec<f64> = headers.iter().map(|h| h.dCTProjectionAngle).collect();
+        let steps: Vec<f64> = angles.windows(2).map(|w| angle_step(w[0], w[1])).collect();
+        let direction = steps.iter().sum::<f64>().signum();
+        let direction = if steps.iter().all(|s| *s == 0.0) { 0.0 } else { direction };
+        // an empty sum is -0.0
+        let arc = steps.iter().filter(|s| s.signum() == direction).fold(0.0, |arc, s| arc + s.abs());
+
+        let mut sizes: Vec<f64> = steps.iter().map(|s| s.abs()).collect();
+        sizes.sort_by(f64::total_cmp);
+        let median = sizes.get(sizes.len() / 2).cloned().unwrap_or(0.0);
+        let threshold = opts.gap_threshold.unwrap_or(5.0 * median);
+        let mut duplicates = Vec::new();
+        let mut out_of_order = Vec::new();
+        let mut gaps = Vec::new();
+        for (i, step) in steps.iter().enumerate() {
+            let path = paths[i + 1];
+            if *step == 0.0 {
+                duplicates.push(path.to_path_buf());
+            } else if step.signum() != direction {
+                out_of_order.push(path.to_path_buf());
+            } else if threshold > 0.0 && step.abs() > threshold {
+                gaps.push((path.to_path_buf(), step.abs()));
+            }
+        }
+
+        let times: Vec<f64> = headers.iter().map(|h| h.dGatingTimeTag).collect();
+        let timed = times.len() > 1 && times.iter().all(|t| is_set(*t) && *t > 0.0);
+        let (duration, gantry_speed, speed_range) = if timed {
+            let duration = times[times.len() - 1] - times[0];
+            let speeds: Vec<f64> = steps
+                .iter()
+                .zip(times.windows(2))
+                .filter(|(_, t)| t[1] > t[0])
+                .map(|(s, t)| s.abs() / (t[1] - t[0]))
+                .collect();
+            let speed = if duration > 0.0 { Some(arc / duration) } else { None };
+            (Some(duration), speed, Range::of(speeds.into_iter()))
+        } else {
+            (None, None, None)
+        };
+
+        let fan_angle = headers.first().and_then(|h| ConeBeam::from_header(h).ok()).map(|g| {
+            let half = g.size[0] as f64 * g.pitch[0] / 2.0;
+            let (left, right) = (g.offset[0] - half, g.offset[0] + half);
+            (right.atan2(g.sdd) - left.atan2(g.sdd)).to_degrees()
+        });
+        let sad = Range::of(headers.iter().map(|h| h.dSAD));
+        let sfd = Range::of(headers.iter().map(|h| h.dSFD));
+        let idu_pos_lat = Range::of(headers.iter().map(|h| h.dIDUPosLat));
+
+        // findings, the worst first
+        if headers.is_empty() {
+            report(Severity::Error, dir, "0 frames".to_string());
+        } else if headers.len() < 2 {
+            report(Severity::Error, dir, format!("{} frame(s) are too few to reconstruct", headers.len()));
+        }
+        if !out_of_order.is_empty() {
+            report(
+                Severity::Error,
+                dir,
+                format!("{} frame(s) step against the rotation direction", out_of_order.len()),
+            );
+        }
+        for (name, range) in &[("dSAD", sad), ("dSFD", sfd)] {
+            if let Some(r) = range {
+                if r.spread() > opts.geometry_tolerance {
+                    report(Severity::Error, dir, format!("{} varies from {} to {} cm", name, r.min, r.max));
+                }
+            }
+        }
+        if let (Some(fan), Some(first)) = (fan_angle, headers.first()) {
+            let offset = ConeBeam::from_header(first).map_or(0.0, |g| g.offset[0].abs());
+            let half_width = first.SizeX as f64 * first.dIDUResolutionX / 2.0;
+            if arc < 180.0 + fan {
+                report(
+                    Severity::Error,
+                    dir,
+                    format!("arc of {:.1} degree is short of 180 plus the fan angle ({:.1})", arc, 180.0 + fan),
+                );
+            } else if offset > half_width / 2.0 && arc < 360.0 - 2.0 * median {
+                report(
+                    Severity::Warning,
+                    dir,
+                    format!("arc of {:.1} degree with an offset detector, which needs a full rotation", arc),
+                );
+            }
+        }
+        if let Some(r) = idu_pos_lat {
+            if r.spread() > opts.geometry_tolerance {
+                report(Severity::Warning, dir, format!("dIDUPosLat varies from {} to {} cm", r.min, r.max));
+            }
+        }
+        for (path, step) in &gaps {
+            report(Severity::Warning, path, format!("gap of {:.2} degree", step));
+        }
+        if !duplicates.is_empty() {
+            report(
+                Severity::Warning,
+                dir,
+                format!("{} frame(s) repeat the angle of the frame before", duplicates.len()),
+            );
+        }
+        if let (Some(speed), Some(range)) = (gantry_speed, speed_range) {
+            if range.min < (1.0 - opts.speed_tolerance) * speed || range.max > (1.0 + opts.speed_tolerance) * speed {
+                report(
+                    Severity::Warning,
+                    dir,
+                    format!("gantry speed varies from {:.2} to {:.2} degree/s", range.min, range.max),
+                );
+            }
+        }
+        if !timed {
+            report(Severity::Info, dir, "no time tags, gantry speed unknown".to_string());
+        }
+
+        ScanReport {
+            frames: headers.len(),
+            start_angle: angles.first().cloned().unwrap_or(0.0),
+            end_angle: angles.last().cloned().unwrap_or(0.0),
+            arc,
+            direction,
+            mean_step: if steps.is_empty() { 0.0 } else { arc / steps.len() as f64 },
+            max_step: sizes.last().cloned().unwrap_or(0.0),
+            duplicates,
+            out_of_order,
+            gaps,
+            duration,
+            gantry_speed,
+            speed_range,
+            fan_angle,
+            sad,
+            sfd,
+            idu_pos_lat,
+            issues,
+        }
+    }
+
+    /// Whether nothing rules out a reconstruction.
+    pub fn suitable(&self) -> bool {
+        crate::worst_severity(&self.issues) != Some(Severity::Error)
+    }
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let range = |r: Option<Range>| match r {
+            Some(r) if r.spread() == 0.0 => format!("{}", r.min),
+            Some(r) => format!("{} to {}", r.min, r.max),
+            None => "unset".to_string(),
+        };
+        let direction = match self.direction as i64 {
+            1 => "increasing angle",
+            -1 => "decreasing angle",
+            _ => "none",
+        };
+        writeln!(f, "frames:\t{}", self.frames)?;
+        writeln!(f, "start angle:\t{}", self.start_angle)?;
+        writeln!(f, "end angle:\t{}", self.end_angle)?;
+        writeln!(f, "arc:\t{:.3}", self.arc)?;
+        writeln!(f, "rotation:\t{}", direction)?;
+        writeln!(f, "mean step:\t{:.4}", self.mean_step)?;
+        writeln!(f, "max step:\t{:.4}", self.max_step)?;
+        writeln!(f, "duplicates:\t{}", self.duplicates.len())?;
+        writeln!(f, "out of order:\t{}", self.out_of_order.len())?;
+        writeln!(f, "gaps:\t{}", self.gaps.len())?;
+        match (self.duration, self.gantry_speed) {
+            (Some(d), Some(s)) => {
+                writeln!(f, "duration (s):\t{:.2}", d)?;
+                writeln!(f, "gantry speed (degree/s):\t{:.3}", s)?;
+            }
+            _ => writeln!(f, "gantry speed (degree/s):\tunknown")?,
+        }
+        if let Some(r) = self.speed_range {
+            writeln!(f, "speed range (degree/s):\t{:.3} to {:.3}", r.min, r.max)?;
+        }
+        if let Some(fan) = self.fan_angle {
+            writeln!(f, "fan angle:\t{:.2}", fan)?;
+        }
+        writeln!(f, "dSAD:\t{}", range(self.sad))?;
+        writeln!(f, "dSFD:\t{}", range(self.sfd))?;
+        writeln!(f, "dIDUPosLat:\t{}", range(self.idu_pos_lat))?;
+        writeln!(f, "suitable for reconstruction:\t{}", if self.suitable() { "yes" } else { "no" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(angles: &[f64]) -> (Vec<PathBuf>, Vec<hnd_header_t>) {
+        let paths = (0..angles.len()).map(|i| PathBuf::from(format!("Proj_{:05}.hnd", i))).collect();
+        let headers = angles
+            .iter()
+            .enumerate()
+            .map(|(i, a)| {
+                let mut h = crate::default_scan_header();
+                h.dCTProjectionAngle = *a;
+                h.dGatingTimeTag = 1.5e9 + i as f64 * 0.1;
+                h
+            })
+            .collect();
+        (paths, headers)
+    }
+
+    fn report(paths: &[PathBuf], headers: &[hnd_header_t]) -> ScanReport {
+        let paths: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        let headers: Vec<&hnd_header_t> = headers.iter().collect();
+        ScanReport::from_headers(Path::new("scan"), &paths, &headers, &ReportOptions::default())
+    }
+
+    #[test]
+    fn test_full_rotation() {
+        // wraps from 179.5 to -180
+        let angles: Vec<f64> = (0..720).map(|i| (i as f64 * 0.5 + 180.0).rem_euclid(360.0) - 180.0).collect();
+        let (paths, headers) = scan(&angles);
+        let r = report(&paths, &headers);
+        assert!((r.arc - 359.5).abs() < 1e-9);
+        assert_eq!(r.direction, 1.0);
+        assert!((r.gantry_speed.unwrap() - 5.0).abs() < 1e-3);
+        assert!(r.gaps.is_empty() && r.duplicates.is_empty() && r.out_of_order.is_empty());
+        assert!(r.suitable(), "{:?}", r.issues);
+    }
+
+    #[test]
+    fn test_defects() {
+        let mut angles: Vec<f64> = (0..100).map(|i| -i as f64).collect();
+        angles[10] = angles[9];
+        angles[50] = -48.0;
+        angles.drain(70..80);
+        let (paths, mut headers) = scan(&angles);
+        headers[5].dSAD = 101.0;
+        let r = report(&paths, &headers);
+        assert_eq!(r.direction, -1.0);
+        assert_eq!(r.duplicates, vec![PathBuf::from("Proj_00010.hnd")]);
+        assert_eq!(r.out_of_order, vec![PathBuf::from("Proj_00050.hnd")]);
+        assert_eq!(r.gaps.len(), 1);
+        assert!(!r.suitable());
+        assert!(r.issues.iter().any(|i| i.message.contains("dSAD")));
+        assert!(r.issues.iter().any(|i| i.message.contains("short of 180")));
+    }
+
+    #[test]
+    fn test_too_few_frames() {
+        let (paths, headers) = scan(&[12.0]);
+        let r = report(&paths, &headers);
+        assert_eq!(r.arc.to_bits(), 0.0f64.to_bits());
+        assert!(r.issues.iter().any(|i| i.message.contains("arc of 0.0 degree")), "{:?}", r.issues);
+        assert!(!r.to_string().contains("-0"));
+
+        let r = report(&[], &[]);
+        assert_eq!(r.frames, 0);
+        assert!(r.issues.iter().any(|i| i.severity == Severity::Error && i.message == "0 frames"));
+        assert!(!r.suitable());
+    }
+}
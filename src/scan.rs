@@ -0,0 +1,195 @@
+// A scan is a directory of HND projections, one file per frame.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::modal::hnd_header_t;
+use crate::Size2D;
+
+/// The `.hnd` files of a scan directory in frame order. Files are sorted by
+/// the number in their name (`Proj_00012.hnd`), then by name.
+pub fn list_scan_files(dir: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.retain(|p| p.is_file() && crate::is_hnd_file(p));
+    files.sort_by(|a, b| (frame_index(a), a).cmp(&(frame_index(b), b)));
+    Ok(files)
+}
+
+/// The frame number in a file name, i.e. the last run of digits in the file
+/// stem: `Proj_00012.hnd` is frame 12.
+pub fn frame_index(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let end = stem.rfind(|c: char| c.is_ascii_digit())? + 1;
+    let start = stem[..end]
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |i| i + 1);
+    stem[start..end].parse().ok()
+}
+
+/// Read the headers of all frames of a scan, in frame order.
+pub fn read_scan_headers(dir: &Path) -> Result<Vec<(PathBuf, hnd_header_t)>, io::Error> {
+    let mut headers = Vec::new();
+    for path in list_scan_files(dir)? {
+        let header = crate::read_header(&mut File::open(&path)?)?;
+        headers.push((path, header));
+    }
+    Ok(headers)
+}
+
+/// Acquisition order of a scan given its headers in frame order: by
+/// `dGatingTimeTag` when every frame has a distinct, finite time tag,
+/// otherwise the frame order itself.
+pub fn time_order(headers: &[hnd_header_t]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..headers.len()).collect();
+    let mut tags: Vec<f64> = headers.iter().map(|h| h.dGatingTimeTag).collect();
+    if tags.iter().all(|t| t.is_finite()) {
+        tags.sort_by(f64::total_cmp);
+        if tags.windows(2).all(|w| w[0] < w[1]) {
+            order.sort_by(|a, b| headers[*a].dGatingTimeTag.total_cmp(&headers[*b].dGatingTimeTag));
+        }
+    }
+    order
+}
+
+/// Parse a text table with one line per frame, columns separated by
+/// whitespace or commas. Blank lines and lines starting with `#` are
+/// skipped.
+pub fn parse_frame_table(text: &str) -> Result<Vec<Vec<f64>>, String> {
+    let mut rows = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let row = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f64>().map_err(|_| format!("line {}: {:?} is not a number", i + 1, v)))
+            .collect::<Result<Vec<f64>, String>>()?;
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Set a frame's angle from the first column of its frame table row and,
+/// with `norm_column` (1-based), its chamber reading from that column.
+pub fn apply_frame_row(header: &mut hnd_header_t, row: &[f64], norm_column: Option<usize>) -> Result<(), String> {
+    let column = |col: usize| row.get(col).cloned().ok_or_else(|| format!("column {} missing in frame table", col + 1));
+    header.dCTProjectionAngle = column(0)?;
+    match norm_column {
+        Some(0) => return Err("the norm chamber column counts from 1".to_string()),
+        Some(col) => header.dCTNormChamber = column(col - 1)?,
+        None => {}
+    }
+    Ok(())
+}
+
+/// Split `n_images` raw frames read from `r` into HND files, each with a
+/// copy of `header` that `frame_header` adjusts for the frame. A single
+/// frame goes to `output`, several to `<output>_00000.hnd`, ... with a
+/// `.hnd` extension of `output` left out. Missing directories are created.
+/// Returns the files written.
+pub fn split_raw_frames<R, F>(
+    r: &mut R,
+    header: &hnd_header_t,
+    n_images: usize,
+    bytes_per_pixel: usize,
+    output: &Path,
+    mut frame_header: F,
+) -> Result<Vec<PathBuf>, io::Error>
+where
+    R: Read,
+    F: FnMut(usize, &mut hnd_header_t) -> Result<(), io::Error>,
+{
+    if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let output = output.to_string_lossy();
+    let prefix = output.trim_end_matches(".hnd");
+    let mut files = Vec::new();
+    for i in 0..n_images {
+        let img = crate::read_raw_frame(r, header.width(), header.height(), bytes_per_pixel)?;
+        let mut h = header.clone();
+        frame_header(i, &mut h)?;
+        let path = if n_images == 1 { PathBuf::from(&*output) } else { PathBuf::from(format!("{}_{:05}.hnd", prefix, i)) };
+        let hnd_image = crate::HndImage::new(h, &img)?;
+        crate::write_file(&mut File::create(&path)?, &hnd_image)?;
+        files.push(path);
+    }
+    Ok(files)
+}
+
+/// Read the text file at `path` and parse it, naming the file in parse
+/// errors.
+pub(crate) fn parse_file<T, F>(path: &Path, parse: F) -> Result<T, io::Error>
+where
+    F: FnOnce(&str) -> Result<T, String>,
+{
+    parse(&std::fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_index() {
+        assert_eq!(frame_index(Path::new("scan/Proj_00012.hnd")), Some(12));
+        assert_eq!(frame_index(Path::new("a1_b0003.hnd")), Some(3));
+        assert_eq!(frame_index(Path::new("image.hnd")), None);
+    }
+
+    #[test]
+    fn test_time_order() {
+        let mut headers = vec![hnd_header_t::new(), hnd_header_t::new(), hnd_header_t::new()];
+        assert_eq!(time_order(&headers), [0, 1, 2]);
+        for (h, t) in headers.iter_mut().zip(&[3.0, 1.0, 2.0]) {
+            h.dGatingTimeTag = *t;
+        }
+        assert_eq!(time_order(&headers), [1, 2, 0]);
+    }
+
+    #[test]
+    fn test_parse_frame_table() {
+        let rows = parse_frame_table("# angle norm\n0.5, 1164\n\n 1.0\t1170 \n").unwrap();
+        assert_eq!(rows, vec![vec![0.5, 1164.0], vec![1.0, 1170.0]]);
+        assert!(parse_frame_table("1.0 x").unwrap_err().contains("line 1"));
+    }
+
+    #[test]
+    fn test_split_raw_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut header = hnd_header_t::new();
+        header.SizeX = 4;
+        header.SizeY = 2;
+        header.dCTNormChamber = 1.0;
+        let raw: Vec<u8> = (0u16..24).flat_map(|v| v.to_ne_bytes()).collect();
+        let table = parse_frame_table("0.5 1164\n1.5 1170\n2.5 1180\n").unwrap();
+
+        let output = dir.path().join("new").join("proj.hnd");
+        let files = split_raw_frames(&mut &raw[..], &header, 3, 2, &output, |i, h| {
+            apply_frame_row(h, &table[i], Some(2)).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        })
+        .unwrap();
+        assert_eq!(files, (0..3).map(|i| dir.path().join(format!("new/proj_{:05}.hnd", i))).collect::<Vec<_>>());
+        for (i, path) in files.iter().enumerate() {
+            let mut f = File::open(path).unwrap();
+            let h = crate::read_header(&mut f).unwrap();
+            assert_eq!((h.dCTProjectionAngle, h.dCTNormChamber), (table[i][0], table[i][1]));
+            let img = crate::read_image(&mut File::open(path).unwrap()).unwrap();
+            assert_eq!(img.data(), (8 * i as u32..8 * i as u32 + 8).collect::<Vec<_>>().as_slice());
+        }
+
+        let mut h = header.clone();
+        assert!(apply_frame_row(&mut h, &[3.0], Some(2)).unwrap_err().contains("column 2"));
+        assert!(apply_frame_row(&mut h, &[3.0, 1.0], Some(0)).unwrap_err().contains("counts from 1"));
+        // a single frame keeps the output name
+        let output = dir.path().join("one.hnd");
+        assert_eq!(split_raw_frames(&mut &raw[..16], &header, 1, 2, &output, |_, _| Ok(())).unwrap(), [output]);
+    }
+}
@@ -0,0 +1,350 @@
+// Scatter estimation by kernel superposition (Ohnesorge et al. 1999, Sun and
+// Star-Lack 2010).
+//
+// Every primary pencil beam P at a pixel spreads scatter over the detector
+// with the amplitude
+//
+//     w(P) = A (P / I0)^alpha (ln(I0 / P))^beta
+//
+// and the shape of a sum of Gaussians h. The symmetric model is the
+// convolution (w P) * h. The asymmetric model accounts for the water
+// equivalent thickness tau (in cm) between the emission and the detection
+// point, h (1 + gamma (tau' - tau)), which is again a pair of convolutions.
+// The primary is found by fixed point iteration P = I - S(P).
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::modal::{hnd_header_t, ImageConvError, is_set};
+use crate::phantom::MU_WATER;
+use crate::{HndImage, RawImage};
+
+/// A Gaussian term of the scatter kernel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KernelTerm {
+    pub weight: f64,
+    /// Standard deviation on the detector in mm.
+    pub sigma: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScatterModel {
+    pub amplitude: f64,
+    pub alpha: f64,
+    pub beta: f64,
+    /// Change of the kernel amplitude per cm of water thickness difference,
+    /// 0 for the symmetric model.
+    pub gamma: f64,
+    pub kernel: Vec<KernelTerm>,
+}
+
+impl ScatterModel {
+    /// Parse the text form, one `name value...` entry per line and `#`
+    /// comments:
+    ///
+    /// ```text
+    /// amplitude 0.8
+    /// alpha -0.2
+    /// beta 0.6
+    /// gamma 0.01
+    /// kernel 0.7 15    # weight, sigma in mm
+    /// kernel 0.3 60
+    /// ```
+    pub fn parse(text: &str) -> Result<ScatterModel, String> {
+        let mut model = ScatterModel {
+            amplitude: 0.0,
+            alpha: 0.0,
+            beta: 0.0,
+            gamma: 0.0,
+            kernel: Vec::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let name = words.next().unwrap();
+            let values = words
+                .map(|v| v.parse::<f64>().map_err(|_| format!("line {}: {:?} is not a number", i + 1, v)))
+                .collect::<Result<Vec<f64>, String>>()?;
+            let expect = if name == "kernel" { 2 } else { 1 };
+            if values.len() != expect {
+                return Err(format!("line {}: {} takes {} value(s)", i + 1, name, expect));
+            }
+            match name {
+                "amplitude" => model.amplitude = values[0],
+                "alpha" => model.alpha = values[0],
+                "beta" if values[0] >= 0.0 => model.beta = values[0],
+                "beta" => return Err(format!("line {}: beta must not be negative", i + 1)),
+                "gamma" => model.gamma = values[0],
+                "kernel" if values[1] > 0.0 => model.kernel.push(KernelTerm {
+                    weight: values[0],
+                    sigma: values[1],
+                }),
+                "kernel" => return Err(format!("line {}: sigma must be positive", i + 1)),
+                _ => return Err(format!("line {}: unknown entry {:?}", i + 1, name)),
+            }
+        }
+        if model.kernel.is_empty() {
+            return Err("the model has no kernel".to_string());
+        }
+        Ok(model)
+    }
+
+    pub fn load(path: &Path) -> Result<ScatterModel, io::Error> {
+        crate::scan::parse_file(path, ScatterModel::parse)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScatterOptions {
+    pub iterations: usize,
+    /// Binning of the grid the scatter is computed on.
+    pub bin: usize,
+    /// Upper limit of the scatter as a fraction of the measured signal.
+    pub max_fraction: f64,
+}
+
+impl Default for ScatterOptions {
+    fn default() -> ScatterOptions {
+        ScatterOptions {
+            iterations: 3,
+            bin: 4,
+            max_fraction: 0.9,
+        }
+    }
+}
+
+// Normalized Gaussian taps out to 3 sigma, sigma in pixels.
+fn gaussian_taps(sigma: f64) -> Vec<f64> {
+    let r = (3.0 * sigma).ceil().max(1.0) as i64;
+    let taps: Vec<f64> = (-r..=r).map(|i| (-(i * i) as f64 / (2.0 * sigma * sigma)).exp()).collect();
+    let sum: f64 = taps.iter().sum();
+    taps.iter().map(|t| t / sum).collect()
+}
+
+// Separable convolution with zero padding: no scatter comes from outside the
+// detector.
+fn blur(data: &[f64], width: usize, height: usize, sigma: [f64; 2]) -> Vec<f64> {
+    let convolve = |input: &[f64], taps: &[f64], n: usize, stride: usize, count: usize, step: usize| {
+        let r = (taps.len() / 2) as i64;
+        let mut out = vec![0.0; input.len()];
+        for line in 0..count {
+            for i in 0..n as i64 {
+                let mut sum = 0.0;
+                for (k, t) in taps.iter().enumerate() {
+                    let j = i + k as i64 - r;
+                    if j >= 0 && j < n as i64 {
+                        sum += t * input[line * step + j as usize * stride];
+                    }
+                }
+                out[line * step + i as usize * stride] = sum;
+            }
+        }
+        out
+    };
+    let rows = convolve(data, &gaussian_taps(sigma[0]), width, 1, height, width);
+    convolve(&rows, &gaussian_taps(sigma[1]), height, width, width, 1)
+}
+
+/// The scatter for the primary signal `primary` on a detector with `pitch`
+/// (column, row spacing in mm).
+pub fn scatter_estimate(model: &ScatterModel, primary: &RawImage<f64>, pitch: [f64; 2], i0: f64) -> Vec<f64> {
+    let tau: Vec<f64> = primary
+        .data
+        .iter()
+        .map(|p| (i0 / p.max(1e-6 * i0)).ln().max(0.0))
+        .collect();
+    let source: Vec<f64> = primary
+        .data
+        .iter()
+        .zip(&tau)
+        .map(|(p, t)| model.amplitude * (p / i0).max(1e-6).powf(model.alpha) * t.powf(model.beta) * p)
+        .collect();
+    // Thickness in cm of water
+    let tau: Vec<f64> = tau.iter().map(|t| t / (MU_WATER * 10.0)).collect();
+    let weighted: Vec<f64> = source.iter().zip(&tau).map(|(s, t)| s * t).collect();
+
+    let n = primary.data.len();
+    let mut symmetric = vec![0.0; n];
+    let mut asymmetric = vec![0.0; n];
+    for term in &model.kernel {
+        let sigma = [term.sigma / pitch[0], term.sigma / pitch[1]];
+        for (s, v) in symmetric.iter_mut().zip(blur(&source, primary.width, primary.height, sigma)) {
+            *s += term.weight * v;
+        }
+        if model.gamma != 0.0 {
+            for (s, v) in asymmetric.iter_mut().zip(blur(&weighted, primary.width, primary.height, sigma)) {
+                *s += term.weight * v;
+            }
+        }
+    }
+    (0..n)
+        .map(|i| ((1.0 - model.gamma * tau[i]) * symmetric[i] + model.gamma * asymmetric[i]).max(0.0))
+        .collect()
+}
+
+// Bilinear interpolation of the `bin` times binned `coarse` image back to
+// `width` x `height`, each value at the centre of its block. Rows and columns
+// left over by the binning take the value of the nearest block.
+fn unbin(coarse: &RawImage<f64>, bin: usize, width: usize, height: usize) -> RawImage<f64> {
+    let pos = |i: usize, n: usize| {
+        let f = ((i as f64 + 0.5) / bin as f64 - 0.5).max(0.0).min((n - 1) as f64);
+        let i0 = f.floor() as usize;
+        (i0, (i0 + 1).min(n - 1), f - i0 as f64)
+    };
+    let at = |x: usize, y: usize| coarse.data[y * coarse.width + x];
+    let mut data = Vec::with_capacity(width * height);
+    for row in 0..height {
+        let (y0, y1, ty) = pos(row, coarse.height);
+        for col in 0..width {
+            let (x0, x1, tx) = pos(col, coarse.width);
+            let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+            let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+            data.push(top * (1.0 - ty) + bottom * ty);
+        }
+    }
+    RawImage { width, height, data }
+}
+
+/// Estimate the primary of the measured image `img` and return it with the
+/// scatter removed.
+pub fn scatter_correct(
+    model: &ScatterModel,
+    img: &RawImage<f64>,
+    pitch: [f64; 2],
+    i0: f64,
+    opts: &ScatterOptions,
+) -> Result<RawImage<f64>, ImageConvError> {
+    let bin = opts.bin.max(1).min(img.width).min(img.height);
+    let coarse = img.bin(bin)?;
+    let coarse_pitch = [pitch[0] * bin as f64, pitch[1] * bin as f64];
+    let mut primary = coarse.convert::<f64>();
+    let mut scatter = vec![0.0; coarse.data.len()];
+    for _ in 0..opts.iterations {
+        scatter = scatter_estimate(model, &primary, coarse_pitch, i0);
+        for ((p, s), m) in primary.data.iter_mut().zip(&scatter).zip(&coarse.data) {
+            *p = m - s.min(opts.max_fraction * m);
+        }
+    }
+    let scatter = unbin(&RawImage::new(coarse.width, coarse.height, scatter)?, bin, img.width, img.height);
+    let data = img
+        .data
+        .iter()
+        .zip(&scatter.data)
+        .map(|(m, s)| m - s.min(opts.max_fraction * m))
+        .collect();
+    RawImage::new(img.width, img.height, data)
+}
+
+/// Detector pixel spacing in mm: `dIDUResolution`, else the spacing at the
+/// isocentre scaled to the detector.
+pub fn detector_pitch(h: &hnd_header_t) -> Result<[f64; 2], io::Error> {
+    let sdd = if is_set(h.dSFD) && h.dSFD > 0.0 {
+        h.dSFD
+    } else {
+        h.dSAD - h.dIDUPosVrt
+    };
+    let pitch = |idu: f64, image: f64| {
+        if is_set(idu) && idu > 0.0 {
+            Ok(idu)
+        } else if is_set(image) && image > 0.0 && is_set(h.dSAD) && h.dSAD > 0.0 && sdd.is_finite() {
+            Ok(image * sdd / h.dSAD)
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "header has no pixel spacing"))
+        }
+    };
+    Ok([
+        pitch(h.dIDUResolutionX, h.dImageResolutionX)?,
+        pitch(h.dIDUResolutionY, h.dImageResolutionY)?,
+    ])
+}
+
+/// Scatter correct one HND file. `i0` is the unattenuated signal, the
+/// reading of the detector in an open field; the norm chamber is on another
+/// scale and cannot stand in for it.
+pub fn scatter_correct_file(
+    input: &Path,
+    output: &Path,
+    model: &ScatterModel,
+    i0: f64,
+    opts: &ScatterOptions,
+) -> Result<(), io::Error> {
+    if !i0.is_finite() || i0 <= 0.0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unattenuated signal {} is not positive", i0)));
+    }
+    let mut fin = File::open(input)?;
+    let header = crate::read_header(&mut fin)?;
+    let img: RawImage<f64> = crate::read_image(&mut fin)?.convert();
+    let corrected = scatter_correct(model, &img, detector_pitch(&header)?, i0, opts)?;
+    let hnd_image = HndImage::new(header, &corrected.convert())?;
+    crate::write_file(&mut File::create(output)?, &hnd_image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(gamma: f64) -> ScatterModel {
+        ScatterModel::parse(&format!(
+            "# test kernel\namplitude 0.1\nalpha 0\nbeta 1\ngamma {}\nkernel 0.6 8\nkernel 0.4 30\n",
+            gamma
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_blur() {
+        let mut data = vec![0.0; 41 * 41];
+        data[20 * 41 + 20] = 1.0;
+        let out = blur(&data, 41, 41, [2.0, 3.0]);
+        assert!((out.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(out[20 * 41 + 26] < out[26 * 41 + 20]);
+        assert!(ScatterModel::parse("amplitude 1\n").is_err());
+        assert!(ScatterModel::parse("beta -0.5\nkernel 1 10\n").is_err());
+    }
+
+    #[test]
+    fn test_unbin() {
+        // a ramp along the columns, 10 wide so that the binning leaves two
+        // columns over
+        let ramp = RawImage::new(10, 6, (0..60).map(|i| (i % 10) as f64).collect()).unwrap();
+        let coarse = ramp.bin(4).unwrap();
+        assert_eq!(coarse.data, vec![1.5, 5.5]);
+        let back = unbin(&coarse, 4, 10, 6);
+        for x in 2..6 {
+            assert!((back.data[3 * 10 + x] - x as f64).abs() < 1e-12, "{}", x);
+        }
+        assert_eq!(back.data[9], 5.5);
+        assert_eq!(back.data[0], 1.5);
+    }
+
+    #[test]
+    fn test_scatter_correct() {
+        let (w, h, i0) = (96, 64, 10000.0);
+        let primary: Vec<f64> = (0..w * h)
+            .map(|i| {
+                let (x, y) = ((i % w) as f64 - 48.0, (i / w) as f64 - 32.0);
+                if x * x + y * y < 25.0 * 25.0 { i0 * (-3.0f64).exp() } else { i0 }
+            })
+            .collect();
+        let primary = RawImage::new(w, h, primary).unwrap();
+        for gamma in &[0.0, 0.02] {
+            let model = model(*gamma);
+            let scatter = scatter_estimate(&model, &primary, [2.0, 2.0], i0);
+            let measured: Vec<f64> = primary.data.iter().zip(&scatter).map(|(p, s)| p + s).collect();
+            let measured = RawImage::new(w, h, measured).unwrap();
+            let opts = ScatterOptions {
+                iterations: 10,
+                bin: 1,
+                ..Default::default()
+            };
+            let corrected = scatter_correct(&model, &measured, [2.0, 2.0], i0, &opts).unwrap();
+            let centre = 32 * w + 48;
+            assert!(scatter[centre] > 0.1 * primary.data[centre]);
+            assert!((corrected.data[centre] / primary.data[centre] - 1.0).abs() < 0.01);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// Breathing signal from the projections themselves, the Amsterdam shroud
+// (Zijp, Sonke and van Herk 2004), for scans without gating data.
+//
+// Every projection is reduced to one column: the derivative of the log
+// signal in the cranio-caudal direction (down the rows), summed over the
+// detector columns. Side by side the columns show the diaphragm as a wavy
+// edge. Its vertical shift from frame to frame, summed up, is the breathing
+// trace; a low order polynomial over the scan takes out the drift from the
+// gantry rotation.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::gating::{gating_times, respiratory_phase};
+use crate::RawImage;
+
+/// One shroud column of a projection.
+pub fn shroud_column(img: &RawImage<u32>) -> Vec<f64> {
+    let log: Vec<f64> = img.data.iter().map(|v| (*v as f64).max(1.0).ln()).collect();
+    (0..img.height.saturating_sub(1))
+        .map(|y| {
+            let (row, next) = (y * img.width, (y + 1) * img.width);
+            // the log signal falls where attenuation rises
+            (0..img.width).map(|x| log[row + x] - log[next + x]).sum()
+        })
+        .collect()
+}
+
+/// The shroud image, the frame of each of its columns and their times.
+pub type Shroud = (RawImage<f64>, Vec<usize>, Vec<f64>);
+
+/// The Amsterdam shroud of a scan: one column per frame in acquisition
+/// order, `(frames, rows - 1)` as width and height.
+pub fn amsterdam_shroud(dir: &Path) -> Result<Shroud, io::Error> {
+    let scan = crate::read_scan_headers(dir)?;
+    let headers: Vec<_> = scan.iter().map(|(_, h)| h.clone()).collect();
+    let times = gating_times(&headers);
+    let mut order: Vec<usize> = (0..scan.len()).collect();
+    order.sort_by(|a, b| times[*a].total_cmp(&times[*b]));
+    let mut columns = Vec::with_capacity(scan.len());
+    for i in &order {
+        let column = shroud_column(&crate::read_image(&mut File::open(&scan[*i].0)?)?);
+        if columns.first().is_some_and(|c: &Vec<f64>| c.len() != column.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} differs in size from the first frame", scan[*i].0.display()),
+            ));
+        }
+        columns.push(column);
+    }
+    let height = columns.first().map_or(0, |c| c.len());
+    let mut data = vec![0.0; height * columns.len()];
+    for (x, column) in columns.iter().enumerate() {
+        for (y, v) in column.iter().enumerate() {
+            data[y * columns.len() + x] = *v;
+        }
+    }
+    let shroud = RawImage::new(columns.len(), height, data)?;
+    let times = order.iter().map(|i| times[*i]).collect();
+    Ok((shroud, order, times))
+}
+
+fn standardized(column: &[f64]) -> Vec<f64> {
+    let n = column.len().max(1) as f64;
+    let mean = column.iter().sum::<f64>() / n;
+    let sd = (column.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n).sqrt();
+    column.iter().map(|v| if sd > 0.0 { (v - mean) / sd } else { 0.0 }).collect()
+}
+
+// Shift in rows that best moves column `a` onto column `b`, searched up to
+// `max_shift` with a parabola through the minimum for the sub-row part.
+fn column_shift(a: &[f64], b: &[f64], max_shift: usize) -> f64 {
+    let n = a.len() as i64;
+    let m = max_shift as i64;
+    let cost = |s: i64| {
+        let (mut sum, mut count) = (0.0, 0);
+        for y in 0.max(-s)..n.min(n - s) {
+            let d = a[y as usize] - b[(y + s) as usize];
+            sum += d * d;
+            count += 1;
+        }
+        if count > 0 { sum / count as f64 } else { f64::INFINITY }
+    };
+    let costs: Vec<f64> = (-m..=m).map(cost).collect();
+    let best = (0..costs.len()).min_by(|i, j| costs[*i].total_cmp(&costs[*j])).unwrap();
+    let mut shift = best as f64 - m as f64;
+    if best > 0 && best + 1 < costs.len() {
+        let (l, c, r) = (costs[best - 1], costs[best], costs[best + 1]);
+        let curvature = l - 2.0 * c + r;
+        if curvature > 0.0 {
+            shift += 0.5 * (l - r) / curvature;
+        }
+    }
+    shift
+}
+
+// Remove the least squares polynomial of `degree` over the sequence.
+fn detrend(values: &mut [f64], degree: usize) {
+    let n = values.len();
+    if n <= degree + 1 {
+        return;
+    }
+    let t = |x: usize| 2.0 * x as f64 / (n - 1) as f64 - 1.0;
+    let rows: Vec<Vec<f64>> = (0..n).map(|x| (0..=degree as i32).map(|k| t(x).powi(k)).collect()).collect();
+    if let Some(c) = crate::fit::least_squares(&rows, values) {
+        for (x, v) in values.iter_mut().enumerate() {
+            *v -= rows[x].iter().zip(&c).map(|(a, b)| a * b).sum::<f64>();
+        }
+    }
+}
+
+/// The breathing trace of a shroud in rows, positive towards the feet (the
+/// diaphragm moving down on inhale). `max_shift` limits the motion between
+/// two frames, `degree` is the order of the drift polynomial removed.
+pub fn breathing_trace(shroud: &RawImage<f64>, max_shift: usize, degree: usize) -> Vec<f64> {
+    let (n, h) = (shroud.width, shroud.height);
+    let columns: Vec<Vec<f64>> = (0..n)
+        .map(|x| standardized(&(0..h).map(|y| shroud.data[y * n + x]).collect::<Vec<f64>>()))
+        .collect();
+    let mut trace = vec![0.0; n];
+    for x in 1..n {
+        trace[x] = trace[x - 1] + column_shift(&columns[x - 1], &columns[x], max_shift);
+    }
+    detrend(&mut trace, degree);
+    trace
+}
+
+/// A breathing trace per frame, as written to and read from CSV.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreathingTrace {
+    /// Frame index in the scan's file order.
+    pub frame: Vec<usize>,
+    pub time: Vec<f64>,
+    pub amplitude: Vec<f64>,
+    pub phase: Vec<Option<f64>>,
+}
+
+impl BreathingTrace {
+    /// Trace and phase from the shroud of the scan in `dir`.
+    pub fn from_scan(dir: &Path, max_shift: usize, degree: usize) -> Result<BreathingTrace, io::Error> {
+        let (shroud, frame, time) = amsterdam_shroud(dir)?;
+        let amplitude = breathing_trace(&shroud, max_shift, degree);
+        let phase = respiratory_phase(&time, &amplitude.iter().map(|a| Some(*a)).collect::<Vec<_>>());
+        Ok(BreathingTrace {
+            frame,
+            time,
+            amplitude,
+            phase,
+        })
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("frame,time,amplitude,phase\n");
+        for i in 0..self.frame.len() {
+            let phase = self.phase[i].map_or(String::new(), |p| p.to_string());
+            out.push_str(&format!("{},{},{},{}\n", self.frame[i], self.time[i], self.amplitude[i], phase));
+        }
+        out
+    }
+
+    /// Parse the CSV form; the header line is optional, an empty phase is
+    /// missing.
+    pub fn parse_csv(text: &str) -> Result<BreathingTrace, String> {
+        let mut trace = BreathingTrace {
+            frame: Vec::new(),
+            time: Vec::new(),
+            amplitude: Vec::new(),
+            phase: Vec::new(),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || (i == 0 && line.starts_with("frame")) {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if fields.len() != 4 {
+                return Err(format!("line {}: expected frame, time, amplitude and phase", i + 1));
+            }
+            let number = |v: &str| v.parse::<f64>().map_err(|_| format!("line {}: {:?} is not a number", i + 1, v));
+            trace.frame.push(fields[0].parse().map_err(|_| format!("line {}: bad frame index", i + 1))?);
+            trace.time.push(number(fields[1])?);
+            trace.amplitude.push(number(fields[2])?);
+            trace.phase.push(if fields[3].is_empty() { None } else { Some(number(fields[3])?) });
+        }
+        Ok(trace)
+    }
+
+    pub fn load(path: &Path) -> Result<BreathingTrace, io::Error> {
+        crate::scan::parse_file(path, BreathingTrace::parse_csv)
+    }
+
+    /// Amplitude (or phase) of each of `n` frames in file order, missing for
+    /// frames not in the trace.
+    pub fn values(&self, n: usize, phase: bool) -> Vec<Option<f64>> {
+        let mut out = vec![None; n];
+        for (k, frame) in self.frame.iter().enumerate() {
+            if *frame < n {
+                out[*frame] = if phase { self.phase[k] } else { Some(self.amplitude[k]) };
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A dark lung over a bright abdomen, the diaphragm at `edge`.
+    fn projection(edge: f64) -> RawImage<u32> {
+        let (w, h) = (32, 80);
+        let data = (0..w * h)
+            .map(|i| {
+                let y = (i / w) as f64;
+                let s = 1.0 / (1.0 + (-(y - edge)).exp());
+                (20000.0 * (-0.5 - 2.0 * s).exp()) as u32
+            })
+            .collect();
+        RawImage::new(w, h, data).unwrap()
+    }
+
+    #[test]
+    fn test_breathing_trace() {
+        let mut motion: Vec<f64> = (0..160).map(|k| 4.0 * (k as f64 * 2.0 * std::f64::consts::PI / 40.0).sin()).collect();
+        let n = motion.len();
+        let columns: Vec<Vec<f64>> = motion.iter().map(|m| shroud_column(&projection(40.0 + m))).collect();
+        let h = columns[0].len();
+        let mut data = vec![0.0; n * h];
+        for (x, c) in columns.iter().enumerate() {
+            for (y, v) in c.iter().enumerate() {
+                data[y * n + x] = *v;
+            }
+        }
+        let trace = breathing_trace(&RawImage::new(n, h, data).unwrap(), 5, 2);
+        detrend(&mut motion, 2);
+        for (t, m) in trace.iter().zip(&motion) {
+            assert!((t - m).abs() < 0.3, "{} {}", t, m);
+        }
+
+        let times: Vec<f64> = (0..n).map(|k| k as f64).collect();
+        let phase = respiratory_phase(&times, &trace.iter().map(|a| Some(*a)).collect::<Vec<_>>());
+        let trace = BreathingTrace {
+            frame: (0..n).collect(),
+            time: times,
+            amplitude: trace,
+            phase,
+        };
+        // peak of the sine at frame 10
+        assert!(trace.phase[10].unwrap() < 0.05 || trace.phase[10].unwrap() > 0.95);
+        assert!((trace.phase[0].unwrap() - 0.75).abs() < 0.05);
+        let back = BreathingTrace::parse_csv(&trace.to_csv()).unwrap();
+        assert_eq!(back.values(n, true), trace.values(n, true));
+    }
+}
@@ -0,0 +1,329 @@
+// Uncompressed grayscale TIFF export and import. The HND header travels
+// along as JSON in the ImageDescription tag, so a file written here can be
+// turned back into an HND without losing geometry.
+
+use std::io;
+use std::io::Write;
+
+use crate::format::{header_from_json, header_to_json};
+use crate::modal::hnd_header_t;
+use crate::{RawImage, Size2D};
+
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const IMAGE_DESCRIPTION: u16 = 270;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const RESOLUTION_UNIT: u16 = 296;
+const SAMPLE_FORMAT: u16 = 339;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("TIFF: {}", msg))
+}
+
+// Pixels per centimetre as a TIFF rational, from a pixel spacing in mm.
+fn resolution(spacing_mm: f64) -> (u32, u32) {
+    if spacing_mm > 0.0 && spacing_mm.is_finite() {
+        ((10.0 / spacing_mm * 10000.0).round() as u32, 10000)
+    } else {
+        (1, 1)
+    }
+}
+
+/// Write `img` as a little-endian, single strip TIFF with 16 or 32 bits per
+/// pixel. 16-bit output fails if a pixel does not fit.
+pub fn write_tiff<W: Write>(w: &mut W, h: &hnd_header_t, img: &RawImage<u32>, bits: u16) -> Result<(), io::Error> {
+    let pixels = crate::pixel_bytes(img, bits)?;
+    let mut description = header_to_json(h, &[]).unwrap().into_bytes();
+    description.push(0);
+    let description_len = description.len() as u32;
+    // keep the following offsets on word boundaries
+    if description.len() % 2 == 1 {
+        description.push(0);
+    }
+
+    // layout: header, description, resolutions, pixels, IFD
+    let description_offset = 8u32;
+    let x_res_offset = description_offset + description.len() as u32;
+    let y_res_offset = x_res_offset + 8;
+    let pixel_offset = y_res_offset + 8;
+    let ifd_offset = pixel_offset + pixels.len() as u32;
+    let (xn, xd) = resolution(h.dImageResolutionX);
+    let (yn, yd) = resolution(h.dImageResolutionY);
+    let unit = if (xn, xd) == (1, 1) { 1 } else { 3 };
+
+    let mut out = Vec::with_capacity(ifd_offset as usize + 200);
+    out.extend_from_slice(b"II");
+    out.extend_from_slice(&42u16.to_le_bytes());
+    out.extend_from_slice(&ifd_offset.to_le_bytes());
+    out.extend_from_slice(&description);
+    for v in &[xn, xd, yn, yd] {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out.extend_from_slice(&pixels);
+
+    let entries: [(u16, u16, u32, u32); 14] = [
+        (IMAGE_WIDTH, TYPE_LONG, 1, img.width() as u32),
+        (IMAGE_LENGTH, TYPE_LONG, 1, img.height() as u32),
+        (BITS_PER_SAMPLE, TYPE_SHORT, 1, bits as u32),
+        (COMPRESSION, TYPE_SHORT, 1, 1),
+        (PHOTOMETRIC, TYPE_SHORT, 1, 1),
+        (IMAGE_DESCRIPTION, TYPE_ASCII, description_len, description_offset),
+        (STRIP_OFFSETS, TYPE_LONG, 1, pixel_offset),
+        (SAMPLES_PER_PIXEL, TYPE_SHORT, 1, 1),
+        (ROWS_PER_STRIP, TYPE_LONG, 1, img.height() as u32),
+        (STRIP_BYTE_COUNTS, TYPE_LONG, 1, pixels.len() as u32),
+        (X_RESOLUTION, TYPE_RATIONAL, 1, x_res_offset),
+        (Y_RESOLUTION, TYPE_RATIONAL, 1, y_res_offset),
+        (RESOLUTION_UNIT, TYPE_SHORT, 1, unit),
+        (SAMPLE_FORMAT, TYPE_SHORT, 1, 1),
+    ];
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (tag, kind, count, value) in entries.iter() {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        if *kind == TYPE_SHORT {
+            out.extend_from_slice(&(*value as u16).to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+        } else {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    out.extend_from_slice(&0u32.to_le_bytes());
+    w.write_all(&out)
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    little: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    fn bytes(&self, pos: usize, len: usize) -> Result<&'a [u8], io::Error> {
+        self.data.get(pos..pos + len).ok_or_else(|| invalid("unexpected end of file"))
+    }
+
+    fn u16(&self, pos: usize) -> Result<u16, io::Error> {
+        let b = self.bytes(pos, 2)?;
+        Ok(if self.little { u16::from_le_bytes([b[0], b[1]]) } else { u16::from_be_bytes([b[0], b[1]]) })
+    }
+
+    fn u32(&self, pos: usize) -> Result<u32, io::Error> {
+        let b = self.bytes(pos, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(if self.little { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+    }
+
+    // All values of an IFD entry of integer type.
+    fn values(&self, entry: usize) -> Result<Vec<u32>, io::Error> {
+        let kind = self.u16(entry + 2)?;
+        let count = self.u32(entry + 4)? as usize;
+        let size = match kind {
+            TYPE_SHORT => 2,
+            TYPE_LONG => 4,
+            _ => return Err(invalid("unsupported field type")),
+        };
+        let pos = if size * count <= 4 { entry + 8 } else { self.u32(entry + 8)? as usize };
+        (0..count)
+            .map(|i| {
+                if size == 2 {
+                    self.u16(pos + i * 2).map(|v| v as u32)
+                } else {
+                    self.u32(pos + i * 4)
+                }
+            })
+            .collect()
+    }
+
+    // The first value of an IFD entry of integer type.
+    fn value(&self, entry: usize) -> Result<u32, io::Error> {
+        self.values(entry)?.first().copied().ok_or_else(|| invalid("field without a value"))
+    }
+
+    fn rational(&self, entry: usize) -> Result<f64, io::Error> {
+        let pos = self.u32(entry + 8)? as usize;
+        let (n, d) = (self.u32(pos)?, self.u32(pos + 4)?);
+        Ok(if d == 0 { 0.0 } else { n as f64 / d as f64 })
+    }
+
+    fn ascii(&self, entry: usize) -> Result<String, io::Error> {
+        let count = self.u32(entry + 4)? as usize;
+        let pos = if count <= 4 { entry + 8 } else { self.u32(entry + 8)? as usize };
+        let raw = self.bytes(pos, count)?;
+        Ok(String::from_utf8_lossy(raw).trim_end_matches('\0').to_string())
+    }
+}
+
+/// Read the first image of an uncompressed grayscale TIFF with 8, 16 or 32
+/// unsigned bits per pixel. The header is restored from the ImageDescription
+/// if it holds one written by `write_tiff`, otherwise a new header is made
+/// from the image size and resolution.
+pub fn read_tiff(data: &[u8]) -> Result<(hnd_header_t, RawImage<u32>), io::Error> {
+    let little = match data.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return Err(invalid("not a TIFF file")),
+    };
+    let r = TiffReader { data, little };
+    if r.u16(2)? != 42 {
+        return Err(invalid("not a TIFF file"));
+    }
+    let ifd = r.u32(4)? as usize;
+    let n_entries = r.u16(ifd)? as usize;
+
+    let (mut width, mut height, mut bits) = (0, 0, 1);
+    let (mut offsets, mut counts) = (Vec::new(), Vec::new());
+    let (mut x_res, mut y_res, mut unit) = (0.0, 0.0, 2);
+    let mut description = String::new();
+    for i in 0..n_entries {
+        let entry = ifd + 2 + i * 12;
+        match r.u16(entry)? {
+            IMAGE_WIDTH => width = r.value(entry)? as usize,
+            IMAGE_LENGTH => height = r.value(entry)? as usize,
+            BITS_PER_SAMPLE => bits = r.value(entry)?,
+            COMPRESSION if r.value(entry)? != 1 => return Err(invalid("compressed images are not supported")),
+            SAMPLES_PER_PIXEL if r.value(entry)? != 1 => return Err(invalid("only grayscale images are supported")),
+            SAMPLE_FORMAT if r.value(entry)? != 1 => return Err(invalid("only unsigned integer pixels are supported")),
+            STRIP_OFFSETS => offsets = r.values(entry)?,
+            STRIP_BYTE_COUNTS => counts = r.values(entry)?,
+            X_RESOLUTION => x_res = r.rational(entry)?,
+            Y_RESOLUTION => y_res = r.rational(entry)?,
+            RESOLUTION_UNIT => unit = r.value(entry)?,
+            IMAGE_DESCRIPTION => description = r.ascii(entry)?,
+            _ => {}
+        }
+    }
+    if offsets.len() != counts.len() {
+        return Err(invalid("strip offsets and byte counts do not match"));
+    }
+
+    let size = bits as usize / 8;
+    if ![1, 2, 4].contains(&size) || bits % 8 != 0 {
+        return Err(invalid("only 8, 16 and 32 bits per pixel are supported"));
+    }
+    // sizes come from the file, check them before allocating
+    let len = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(size))
+        .ok_or_else(|| invalid("image size is too large"))?;
+    let available = counts.iter().map(|c| *c as u64).sum::<u64>();
+    if len as u64 > available || len > data.len() {
+        return Err(invalid("image data is too short"));
+    }
+    let mut pixels = Vec::with_capacity(len);
+    for (offset, count) in offsets.iter().zip(&counts) {
+        pixels.extend_from_slice(r.bytes(*offset as usize, *count as usize)?);
+    }
+    let values: Vec<u32> = pixels[..len]
+        .chunks_exact(size)
+        .map(|c| match size {
+            1 => c[0] as u32,
+            2 if little => u16::from_le_bytes([c[0], c[1]]) as u32,
+            2 => u16::from_be_bytes([c[0], c[1]]) as u32,
+            _ if little => u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
+            _ => u32::from_be_bytes([c[0], c[1], c[2], c[3]]),
+        })
+        .collect();
+    let img = RawImage::new(width, height, values)?;
+
+    let mut header = match header_from_json(&description, &hnd_header_t::new()) {
+        Ok(h) => h,
+        Err(_) => {
+            let mut h = hnd_header_t::new();
+            // pixel spacing in mm from pixels per inch or centimetre
+            let mm_per_unit = match unit {
+                2 => 25.4,
+                3 => 10.0,
+                _ => 0.0,
+            };
+            if x_res > 0.0 && y_res > 0.0 && mm_per_unit > 0.0 {
+                h.dImageResolutionX = mm_per_unit / x_res;
+                h.dImageResolutionY = mm_per_unit / y_res;
+            }
+            h
+        }
+    };
+    header.SizeX = width as u32;
+    header.SizeY = height as u32;
+    Ok((header, img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiff_roundtrip() {
+        let mut f = std::fs::File::open("test/test_data_1.hnd").unwrap();
+        let header = crate::read_header(&mut f).unwrap();
+        let img = crate::read_image(&mut f).unwrap();
+
+        let mut buf = Vec::new();
+        write_tiff(&mut buf, &header, &img, 32).unwrap();
+        let (h, img2) = read_tiff(&buf).unwrap();
+        assert_eq!(img2.data(), img.data());
+        assert_eq!(h.dCTProjectionAngle, header.dCTProjectionAngle);
+        assert_eq!(h.sCreationDate, header.sCreationDate);
+
+        // this image has values above 65535
+        assert!(write_tiff(&mut Vec::new(), &header, &img, 16).is_err());
+        let small = RawImage::new(3, 1, vec![0, 1000, 65535]).unwrap();
+        let mut buf = Vec::new();
+        write_tiff(&mut buf, &header, &small, 16).unwrap();
+        let (h, img2) = read_tiff(&buf).unwrap();
+        assert_eq!(img2.data(), small.data());
+        assert_eq!((h.SizeX, h.SizeY), (3, 1));
+    }
+
+    #[test]
+    fn test_tiff_resolution_without_header() {
+        let img = RawImage::new(2, 1, vec![1, 2]).unwrap();
+        let mut h = hnd_header_t::new();
+        h.dImageResolutionX = 0.4;
+        h.dImageResolutionY = 0.5;
+        let mut buf = Vec::new();
+        write_tiff(&mut buf, &h, &img, 16).unwrap();
+        // drop the description so the resolution tags are used
+        let pos = buf.windows(9).position(|w| w == b"{\"sFileTy").unwrap();
+        buf[pos] = b'x';
+        let (h2, _) = read_tiff(&buf).unwrap();
+        assert!((h2.dImageResolutionX - 0.4).abs() < 1e-6);
+        assert!((h2.dImageResolutionY - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_tiff_bad_sizes() {
+        let img = RawImage::new(2, 1, vec![1, 2]).unwrap();
+        let mut buf = Vec::new();
+        write_tiff(&mut buf, &hnd_header_t::new(), &img, 16).unwrap();
+        let ifd = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        // the first two entries are the image width and length
+        let (width, length) = (ifd + 2, ifd + 14);
+
+        let mut bad = buf.clone();
+        bad[width + 4..width + 8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(read_tiff(&bad).is_err());
+
+        let mut bad = buf.clone();
+        bad[width + 8..width + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        bad[length + 8..length + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_tiff(&bad).is_err());
+
+        let mut bad = buf;
+        bad[length + 8..length + 12].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(read_tiff(&bad).is_err());
+    }
+}
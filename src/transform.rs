@@ -0,0 +1,366 @@
+// Geometric operations on images that keep the header in step: size, pixel
+// spacing and detector offset.
+//
+// Offsets follow the detector frame of the phantom module: `dIDUPosLat`
+// along the columns, `dIDUPosLng` up the rows, both in cm.
+
+use crate::modal::{hnd_header_t, ImageConvError, is_set};
+use crate::{RawImage, Size2D};
+
+/// Pixel types that can be averaged and converted into each other.
+pub trait Pixel: Copy {
+    fn to_f64(self) -> f64;
+    fn from_f64(v: f64) -> Self;
+}
+
+impl Pixel for u16 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    fn from_f64(v: f64) -> Self {
+        v.round().max(0.0).min(u16::MAX as f64) as u16
+    }
+}
+
+impl Pixel for u32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+    fn from_f64(v: f64) -> Self {
+        v.round().max(0.0).min(u32::MAX as f64) as u32
+    }
+}
+
+impl Pixel for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+    fn from_f64(v: f64) -> Self {
+        v
+    }
+}
+
+impl<T: Pixel> RawImage<T> {
+    /// The image with another pixel type, rounded and clamped to its range.
+    pub fn convert<U: Pixel>(&self) -> RawImage<U> {
+        RawImage {
+            width: self.width,
+            height: self.height,
+            data: self.data.iter().map(|v| U::from_f64(v.to_f64())).collect(),
+        }
+    }
+}
+
+impl<T: Copy> RawImage<T> {
+    /// The `width` x `height` region with its upper left corner at `x`, `y`.
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Result<RawImage<T>, ImageConvError> {
+        if x.checked_add(width).is_none_or(|end| end > self.width)
+            || y.checked_add(height).is_none_or(|end| end > self.height)
+        {
+            return Err(ImageConvError::OutOfBounds);
+        }
+        let mut data = Vec::with_capacity(width * height);
+        for row in y..y + height {
+            data.extend_from_slice(&self.data[row * self.width + x..row * self.width + x + width]);
+        }
+        RawImage::new(width, height, data)
+    }
+
+    /// Mirror left to right.
+    pub fn flip_horizontal(&self) -> RawImage<T> {
+        let mut data = self.data.clone();
+        for row in data.chunks_mut(self.width.max(1)) {
+            row.reverse();
+        }
+        RawImage {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+
+    /// Mirror top to bottom.
+    pub fn flip_vertical(&self) -> RawImage<T> {
+        let mut data = Vec::with_capacity(self.data.len());
+        for row in self.data.chunks(self.width.max(1)).rev() {
+            data.extend_from_slice(row);
+        }
+        RawImage {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+
+    /// Rotate clockwise by `turns` quarter turns.
+    pub fn rotate90(&self, turns: u32) -> RawImage<T> {
+        match turns % 4 {
+            0 => RawImage {
+                width: self.width,
+                height: self.height,
+                data: self.data.clone(),
+            },
+            2 => {
+                let mut data = self.data.clone();
+                data.reverse();
+                RawImage {
+                    width: self.width,
+                    height: self.height,
+                    data,
+                }
+            }
+            n => {
+                let (w, h) = (self.width, self.height);
+                let mut data = Vec::with_capacity(self.data.len());
+                for row in 0..w {
+                    for col in 0..h {
+                        // clockwise: new (row, col) comes from (h - 1 - col, row)
+                        let (y, x) = if n == 1 { (h - 1 - col, row) } else { (col, w - 1 - row) };
+                        data.push(self.data[y * w + x]);
+                    }
+                }
+                RawImage {
+                    width: h,
+                    height: w,
+                    data,
+                }
+            }
+        }
+    }
+}
+
+impl<T: Pixel> RawImage<T> {
+    /// Average `factor` x `factor` blocks. Rows and columns that do not fill
+    /// a block are dropped.
+    pub fn bin(&self, factor: usize) -> Result<RawImage<T>, ImageConvError> {
+        if factor == 0 || factor > self.width || factor > self.height {
+            return Err(ImageConvError::OutOfBounds);
+        }
+        let (w, h) = (self.width / factor, self.height / factor);
+        let mut data = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = 0.0;
+                for dy in 0..factor {
+                    let row = (y * factor + dy) * self.width + x * factor;
+                    sum += self.data[row..row + factor].iter().map(|v| v.to_f64()).sum::<f64>();
+                }
+                data.push(T::from_f64(sum / (factor * factor) as f64));
+            }
+        }
+        RawImage::new(w, h, data)
+    }
+
+    /// Bilinear resampling to `width` x `height`, with the corners of the
+    /// images aligned.
+    pub fn resample(&self, width: usize, height: usize) -> Result<RawImage<T>, ImageConvError> {
+        if width == 0 || height == 0 || self.data.is_empty() {
+            return Err(ImageConvError::OutOfBounds);
+        }
+        let scale = |n: usize, m: usize| if m > 1 { (n - 1) as f64 / (m - 1) as f64 } else { 0.0 };
+        let (sx, sy) = (scale(self.width, width), scale(self.height, height));
+        let at = |x: usize, y: usize| self.data[y * self.width + x].to_f64();
+        let mut data = Vec::with_capacity(width * height);
+        for row in 0..height {
+            let fy = row as f64 * sy;
+            let y0 = (fy.floor() as usize).min(self.height - 1);
+            let y1 = (y0 + 1).min(self.height - 1);
+            let ty = fy - y0 as f64;
+            for col in 0..width {
+                let fx = col as f64 * sx;
+                let x0 = (fx.floor() as usize).min(self.width - 1);
+                let x1 = (x0 + 1).min(self.width - 1);
+                let tx = fx - x0 as f64;
+                let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+                let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+                data.push(T::from_f64(top * (1.0 - ty) + bottom * ty));
+            }
+        }
+        RawImage::new(width, height, data)
+    }
+}
+
+/// A geometric operation on a projection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    Crop {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    FlipHorizontal,
+    FlipVertical,
+    /// Clockwise quarter turns.
+    Rotate90(u32),
+    Bin(usize),
+    Resample {
+        width: usize,
+        height: usize,
+    },
+}
+
+fn scale(v: &mut f64, factor: f64) {
+    if is_set(*v) {
+        *v *= factor;
+    }
+}
+
+fn shift(v: &mut f64, cm: f64) {
+    if is_set(*v) {
+        *v += cm;
+    } else if cm != 0.0 {
+        *v = cm;
+    }
+}
+
+fn negate(v: &mut f64) {
+    if is_set(*v) {
+        *v = -*v;
+    }
+}
+
+fn scale_spacing(h: &mut hnd_header_t, fx: f64, fy: f64) {
+    scale(&mut h.dImageResolutionX, fx);
+    scale(&mut h.dIDUResolutionX, fx);
+    scale(&mut h.dImageResolutionY, fy);
+    scale(&mut h.dIDUResolutionY, fy);
+}
+
+/// Apply `t` to `img` and update `header` to match. Fails with
+/// `ImageConvError::TooSmall`, leaving `header` as it is, if the result has
+/// no columns or fewer than two rows, which HND cannot hold.
+pub fn apply_transform<T: Pixel>(
+    header: &mut hnd_header_t,
+    img: &RawImage<T>,
+    t: &Transform,
+) -> Result<RawImage<T>, ImageConvError> {
+    let mut updated = header.clone();
+    let h = &mut updated;
+    let out = match *t {
+        Transform::Crop { x, y, width, height } => {
+            let out = img.crop(x, y, width, height)?;
+            // move the offset to the centre of the region, in detector pixels
+            let pitch = |idu: f64, image: f64| if is_set(idu) { idu } else if is_set(image) { image } else { 0.0 };
+            let du = (x as f64 + width as f64 / 2.0 - img.width() as f64 / 2.0)
+                * pitch(h.dIDUResolutionX, h.dImageResolutionX);
+            let dv = (img.height() as f64 / 2.0 - y as f64 - height as f64 / 2.0)
+                * pitch(h.dIDUResolutionY, h.dImageResolutionY);
+            shift(&mut h.dIDUPosLat, du / 10.0);
+            shift(&mut h.dIDUPosLng, dv / 10.0);
+            out
+        }
+        Transform::FlipHorizontal => {
+            negate(&mut h.dIDUPosLat);
+            img.flip_horizontal()
+        }
+        Transform::FlipVertical => {
+            negate(&mut h.dIDUPosLng);
+            img.flip_vertical()
+        }
+        Transform::Rotate90(turns) => {
+            for _ in 0..turns % 4 {
+                // a point at (lat, lng) ends up at (lng, -lat)
+                std::mem::swap(&mut h.dIDUPosLat, &mut h.dIDUPosLng);
+                negate(&mut h.dIDUPosLng);
+                std::mem::swap(&mut h.dImageResolutionX, &mut h.dImageResolutionY);
+                std::mem::swap(&mut h.dIDUResolutionX, &mut h.dIDUResolutionY);
+            }
+            img.rotate90(turns)
+        }
+        Transform::Bin(factor) => {
+            // drop the incomplete blocks first so the offset follows them
+            let (w, hgt) = (img.width() / factor.max(1) * factor, img.height() / factor.max(1) * factor);
+            let out = if (w, hgt) != (img.width(), img.height()) {
+                apply_transform(h, img, &Transform::Crop { x: 0, y: 0, width: w, height: hgt })?.bin(factor)?
+            } else {
+                img.bin(factor)?
+            };
+            scale_spacing(h, factor as f64, factor as f64);
+            out
+        }
+        Transform::Resample { width, height } => {
+            let out = img.resample(width, height)?;
+            scale_spacing(
+                h,
+                img.width() as f64 / width as f64,
+                img.height() as f64 / height as f64,
+            );
+            out
+        }
+    };
+    if out.width() < 1 || out.height() < 2 {
+        return Err(ImageConvError::TooSmall);
+    }
+    h.SizeX = out.width() as u32;
+    h.SizeY = out.height() as u32;
+    *header = updated;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modal::UNSET;
+
+    fn image() -> RawImage<u32> {
+        // 1 2 3
+        // 4 5 6
+        RawImage::new(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap()
+    }
+
+    #[test]
+    fn test_image_ops() {
+        let img = image();
+        assert_eq!(img.crop(1, 0, 2, 2).unwrap().data(), [2, 3, 5, 6]);
+        assert!(img.crop(2, 0, 2, 1).is_err());
+        assert!(img.crop(1, 0, usize::MAX, 1).is_err());
+        assert_eq!(img.flip_horizontal().data(), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(img.flip_vertical().data(), [4, 5, 6, 1, 2, 3]);
+        let r = img.rotate90(1);
+        assert_eq!((r.width(), r.height()), (2, 3));
+        assert_eq!(r.data(), [4, 1, 5, 2, 6, 3]);
+        assert_eq!(img.rotate90(3).data(), [3, 6, 2, 5, 1, 4]);
+        assert_eq!(img.rotate90(2).data(), [6, 5, 4, 3, 2, 1]);
+        assert_eq!(img.bin(2).unwrap().data(), [3]);
+        assert_eq!(img.resample(2, 2).unwrap().data(), [1, 3, 4, 6]);
+        let float = RawImage::new(2, 1, vec![-1.0, 2.6]).unwrap();
+        assert_eq!(float.convert::<u16>().data(), [0, 3]);
+    }
+
+    #[test]
+    fn test_header_updates() {
+        let mut h = hnd_header_t::new();
+        h.dIDUResolutionX = 0.5;
+        h.dIDUResolutionY = 0.5;
+        h.dImageResolutionX = 0.25;
+        h.dImageResolutionY = 0.25;
+        let img = RawImage::new(4, 4, vec![0u16; 16]).unwrap();
+
+        let crop = Transform::Crop { x: 2, y: 0, width: 2, height: 2 };
+        let out = apply_transform(&mut h, &img, &crop).unwrap();
+        assert_eq!((h.SizeX, h.SizeY), (2, 2));
+        assert_eq!((out.width(), out.height()), (2, 2));
+        // one pixel right and one pixel up
+        assert!((h.dIDUPosLat - 0.05).abs() < 1e-12);
+        assert!((h.dIDUPosLng - 0.05).abs() < 1e-12);
+
+        apply_transform(&mut h, &img, &Transform::Bin(2)).unwrap();
+        assert_eq!((h.SizeX, h.SizeY), (2, 2));
+        assert_eq!((h.dIDUResolutionX, h.dImageResolutionY), (1.0, 0.5));
+
+        // a single row cannot be written as HND, the header stays as it is
+        let row = Transform::Crop { x: 0, y: 0, width: 4, height: 1 };
+        assert!(matches!(apply_transform(&mut h, &img, &row), Err(ImageConvError::TooSmall)));
+        let resample = Transform::Resample { width: 4, height: 1 };
+        assert!(matches!(apply_transform(&mut h, &img, &resample), Err(ImageConvError::TooSmall)));
+        assert!(apply_transform(&mut h, &img, &Transform::Bin(4)).is_err());
+        assert_eq!((h.SizeX, h.SizeY, h.dIDUResolutionX), (2, 2, 1.0));
+
+        h.dIDUResolutionX = UNSET;
+        apply_transform(&mut h, &out, &Transform::Rotate90(1)).unwrap();
+        assert_eq!(h.dIDUResolutionY, UNSET);
+        assert!((h.dIDUPosLat - 0.05).abs() < 1e-12);
+        assert!((h.dIDUPosLng + 0.05).abs() < 1e-12);
+    }
+}